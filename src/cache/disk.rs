@@ -0,0 +1,815 @@
+use crate::parser::StreamItem;
+use crate::upstream::chunk::{self, Body, Source};
+use crate::upstream::limit::Limits;
+use crate::util;
+use actix_web::http::header::HeaderMap;
+use actix_web::rt::time;
+use actix_web::web::{self, Bytes};
+use futures_util::stream::{self, StreamExt};
+use memmap2::{Advice, Mmap};
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::Notify;
+
+// 以块为单位记录已缓存的部分, 上游请求也按块对齐, 保证写入的都是完整的块
+const BLOCK: u64 = 1 << 20;
+
+// 从缓存文件每次输出的大小
+const READ: u64 = 256 << 10;
+
+// 位图文件最多每隔FLUSH写入一次, 请求结束时再写入一次; 位图只会少记录已写入的块, 中途退出只是少缓存一些
+const FLUSH: Duration = Duration::from_secs(5);
+
+// 其他请求正在获取同一块时等待的最长时间, 超时后自行获取
+const PENDING_WAIT: Duration = Duration::from_secs(10);
+
+// 环境变量DISK_CACHE为缓存目录, 未配置时不开启; DISK_CACHE_SIZE为总大小, 默认10G
+pub static DISK: LazyLock<Option<Disk>> = LazyLock::new(|| {
+    let dir = PathBuf::from(env::var("DISK_CACHE").ok().filter(|v| !v.is_empty())?);
+    let budget = env::var("DISK_CACHE_SIZE")
+        .map(|v| util::parse_size(&v))
+        .ok()
+        .filter(|v| *v > 0)
+        .unwrap_or(10 << 30);
+    if let Err(e) = fs::create_dir_all(&dir) {
+        println!("disk cache {}: {}", dir.display(), e);
+        return None;
+    }
+    let disk = Disk {
+        dir,
+        budget,
+        used: AtomicU64::new(0),
+        entries: Mutex::new(HashMap::new()),
+    };
+    disk.scan();
+    Some(disk)
+});
+
+pub struct Disk {
+    dir: PathBuf,
+    budget: u64,
+    // 所有缓存文件中已缓存的字节数
+    used: AtomicU64,
+    entries: Mutex<HashMap<String, Arc<Entry>>>,
+}
+
+// 一个资源对应一个稀疏文件及记录已缓存块的位图文件(.map), 位图文件为8字节的总大小加上位图
+pub struct Entry {
+    key: String,
+    path: PathBuf,
+    total: u64,
+    state: Mutex<State>,
+    // 有块写入完成或放弃获取时通知等待的请求
+    done: Notify,
+}
+
+struct State {
+    bitmap: Vec<u8>,
+    cached: u64,
+    used: SystemTime,
+    // 已被淘汰, 正在进行的请求不再写入位图
+    evicted: bool,
+    // 正在从上游获取的块, 相同的块只获取一次
+    pending: HashSet<u64>,
+    // 位图有未写入文件的修改
+    dirty: bool,
+    flushed: Instant,
+}
+
+impl Entry {
+    fn blocks(&self) -> u64 {
+        self.total.div_ceil(BLOCK)
+    }
+
+    fn block_len(&self, n: u64) -> u64 {
+        BLOCK.min(self.total - n * BLOCK)
+    }
+
+    fn has(&self, n: u64) -> bool {
+        let s = self.state.lock().unwrap();
+        s.bitmap[(n / 8) as usize] & (1 << (n % 8)) != 0
+    }
+
+    fn map_path(&self) -> PathBuf {
+        self.path.with_extension("map")
+    }
+
+    // 标记一个块已写入, 返回新增的字节数
+    // 只有标记了正在获取该块的请求才清除标记, 超时后自行获取的请求不影响其他请求的标记
+    fn mark(&self, n: u64, claimed: bool) -> io::Result<u64> {
+        let mut s = self.state.lock().unwrap();
+        if claimed {
+            s.pending.remove(&n);
+        }
+        self.done.notify_waiters();
+        let (i, bit) = ((n / 8) as usize, 1 << (n % 8));
+        if s.evicted || s.bitmap[i] & bit != 0 {
+            return Ok(0);
+        }
+        s.bitmap[i] |= bit;
+        let len = self.block_len(n);
+        s.cached += len;
+        s.dirty = true;
+        if s.flushed.elapsed() >= FLUSH {
+            self.write_map(&mut s)?;
+        }
+        Ok(len)
+    }
+
+    fn write_map(&self, s: &mut State) -> io::Result<()> {
+        s.dirty = false;
+        s.flushed = Instant::now();
+        let mut data = self.total.to_be_bytes().to_vec();
+        data.extend_from_slice(&s.bitmap);
+        fs::write(self.map_path(), data)
+    }
+
+    fn flush(&self) {
+        let mut s = self.state.lock().unwrap();
+        if s.dirty
+            && !s.evicted
+            && let Err(e) = self.write_map(&mut s)
+        {
+            println!("disk cache map {}: {}", self.key, e);
+        }
+    }
+
+    // 从first开始连续缺失且没有其他请求正在获取的块, 标记为正在获取, 返回最后一块
+    // 最多标记一个CHUNK_SIZE, 其余的块留给其他请求; first正在被获取时返回None
+    fn claim(&self, first: u64, last: u64) -> Option<u64> {
+        let last = window(first, last);
+        let mut s = self.state.lock().unwrap();
+        let free = |s: &State, n: u64| {
+            s.bitmap[(n / 8) as usize] & (1 << (n % 8)) == 0 && !s.pending.contains(&n)
+        };
+        if !free(&s, first) {
+            return None;
+        }
+        let mut n = first;
+        while n < last && free(&s, n + 1) {
+            n += 1;
+        }
+        s.pending.extend(first..=n);
+        Some(n)
+    }
+
+    // 放弃获取的块
+    fn release(&self, first: u64, last: u64) {
+        let mut s = self.state.lock().unwrap();
+        for n in first..=last {
+            s.pending.remove(&n);
+        }
+        self.done.notify_waiters();
+    }
+}
+
+// 一次从上游获取的块不超过一个CHUNK_SIZE
+fn window(first: u64, last: u64) -> u64 {
+    last.min(first + chunk::CHUNK_SIZE.div_ceil(BLOCK) - 1)
+}
+
+impl Disk {
+    // 启动时加载已有的缓存
+    fn scan(&self) {
+        let Ok(dir) = fs::read_dir(&self.dir) else {
+            return;
+        };
+        let mut entries = self.entries.lock().unwrap();
+        for file in dir.flatten() {
+            let path = file.path();
+            if path.extension().is_none_or(|e| e != "map") {
+                continue;
+            }
+            let Some(key) = path.file_stem().and_then(|v| v.to_str()) else {
+                continue;
+            };
+            let Ok(data) = fs::read(&path) else {
+                continue;
+            };
+            let used = file
+                .metadata()
+                .and_then(|m| m.modified())
+                .unwrap_or(SystemTime::UNIX_EPOCH);
+            let Some(entry) = self.load(key, &data, used) else {
+                let _ = fs::remove_file(&path);
+                let _ = fs::remove_file(path.with_extension("bin"));
+                continue;
+            };
+            let cached = entry.state.lock().unwrap().cached;
+            self.used.fetch_add(cached, Ordering::Relaxed);
+            entries.insert(key.to_owned(), Arc::new(entry));
+        }
+    }
+
+    fn load(&self, key: &str, data: &[u8], used: SystemTime) -> Option<Entry> {
+        let total = u64::from_be_bytes(data.get(..8)?.try_into().ok()?);
+        let entry = self.create(key, total);
+        if fs::metadata(&entry.path).ok()?.len() != total {
+            return None;
+        }
+        let bitmap = &data[8..];
+        if bitmap.len() != entry.state.lock().unwrap().bitmap.len() {
+            return None;
+        }
+        let cached = (0..entry.blocks())
+            .filter(|n| bitmap[(n / 8) as usize] & (1 << (n % 8)) != 0)
+            .map(|n| entry.block_len(n))
+            .sum();
+        {
+            let mut s = entry.state.lock().unwrap();
+            s.bitmap = bitmap.to_vec();
+            s.cached = cached;
+            s.used = used;
+        }
+        Some(entry)
+    }
+
+    fn create(&self, key: &str, total: u64) -> Entry {
+        Entry {
+            key: key.to_owned(),
+            path: self.dir.join(format!("{}.bin", key)),
+            total,
+            state: Mutex::new(State {
+                bitmap: vec![0; total.div_ceil(BLOCK).div_ceil(8) as usize],
+                cached: 0,
+                used: SystemTime::now(),
+                evicted: false,
+                pending: HashSet::new(),
+                dirty: false,
+                flushed: Instant::now(),
+            }),
+            done: Notify::new(),
+        }
+    }
+
+    // 超过预算时按最近使用时间淘汰到预算的90%, 之后写入预算的10%才会再次淘汰
+    fn evict(&self, keep: &str) {
+        if self.used.load(Ordering::Relaxed) <= self.budget {
+            return;
+        }
+        let target = self.budget / 10 * 9;
+        let mut entries = self.entries.lock().unwrap();
+        let mut list: Vec<(SystemTime, Arc<Entry>)> = entries
+            .values()
+            .filter(|e| e.key != keep)
+            .map(|e| (e.state.lock().unwrap().used, e.clone()))
+            .collect();
+        list.sort_by_key(|(used, _)| *used);
+        let mut removed = Vec::new();
+        for (_, entry) in list {
+            if self.used.load(Ordering::Relaxed) <= target {
+                break;
+            }
+            entries.remove(&entry.key);
+            let cached = {
+                let mut s = entry.state.lock().unwrap();
+                s.evicted = true;
+                s.cached
+            };
+            self.used.fetch_sub(cached, Ordering::Relaxed);
+            println!("disk cache evict {} {}", entry.key, cached);
+            removed.push(entry);
+        }
+        drop(entries);
+        for entry in removed {
+            // 正在读取的请求持有打开的文件及映射, 删除后仍可继续读取
+            let _ = fs::remove_file(entry.map_path());
+            let _ = fs::remove_file(&entry.path);
+        }
+    }
+}
+
+// 地址中的lmt区分资源版本, 没有lmt的资源不缓存
+pub fn entry(vid: &str, item: &StreamItem, total: u64) -> Option<Arc<Entry>> {
+    let disk = DISK.as_ref()?;
+    let lmt = util::query_param(&item.url, "lmt")?;
+    let key = match item.lang() {
+        "" => format!("{}-{}-{}", vid, item.itag, lmt),
+        lang => format!("{}-{}-{}-{}", vid, item.itag, lang, lmt),
+    };
+    let mut entries = disk.entries.lock().unwrap();
+    let entry = entries
+        .entry(key.clone())
+        .or_insert_with(|| Arc::new(disk.create(&key, total)))
+        .clone();
+    if entry.total != total {
+        return None;
+    }
+    entry.state.lock().unwrap().used = SystemTime::now();
+    Some(entry)
+}
+
+enum Cur {
+    Idle,
+    // 从缓存文件读取, 直到end
+    File(u64),
+    // 从上游获取按块对齐的[start, end], buf为从buf_start开始还未写入的数据
+    // claimed为是否标记了这些块正在获取, 结束前需要放弃未写入的块
+    Upstream {
+        body: Body,
+        up_pos: u64,
+        end: u64,
+        buf: Vec<u8>,
+        buf_start: u64,
+        claimed: bool,
+    },
+}
+
+struct Fill {
+    src: Source,
+    limits: Limits,
+    entry: Arc<Entry>,
+    file: Arc<File>,
+    // 已缓存的部分直接以映射的内存输出, 不复制
+    map: Arc<Mmap>,
+    pos: u64,
+    end: u64,
+    cur: Cur,
+    // 正在等待其他请求获取的块及等待的截止时间
+    waited: Option<(u64, Instant)>,
+}
+
+// 映射中的一段, 作为Bytes的owner
+struct Mapped {
+    map: Arc<Mmap>,
+    start: usize,
+    end: usize,
+}
+
+impl AsRef<[u8]> for Mapped {
+    fn as_ref(&self) -> &[u8] {
+        &self.map[self.start..self.end]
+    }
+}
+
+// 已缓存的块从文件读取, 缺失的块从上游获取并写入文件, 一边写入一边输出
+pub async fn open(
+    entry: Arc<Entry>,
+    src: Source,
+    start: u64,
+    end: u64,
+    limits: Limits,
+) -> Result<(HeaderMap, Body), io::Error> {
+    let (path, total) = (entry.path.clone(), entry.total);
+    let (file, map) = web::block(move || {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        if file.metadata()?.len() != total {
+            file.set_len(total)?;
+        }
+        // SAFETY: 缓存文件创建后长度不变, 只会写入未标记的块或被删除, 映射中已标记的块不会改变
+        let map = unsafe { Mmap::map(&file)? };
+        Ok::<_, io::Error>((file, map))
+    })
+    .await
+    .map_err(io::Error::other)??;
+    let fill = Fill {
+        src,
+        limits,
+        entry,
+        file: Arc::new(file),
+        map: Arc::new(map),
+        pos: start,
+        end,
+        cur: Cur::Idle,
+        waited: None,
+    };
+    let mut body = stream::unfold(fill, next).boxed_local();
+    // 与chunk::open一样, 首块数据出错时直接返回错误
+    let first = match body.next().await {
+        Some(Ok(b)) => b,
+        Some(Err(e)) => return Err(e),
+        None => Bytes::new(),
+    };
+    let body = stream::once(async move { Ok(first) })
+        .chain(body)
+        .boxed_local();
+    Ok((HeaderMap::new(), body))
+}
+
+// 已缓存的部分, 预读下一段避免输出时缺页阻塞
+// actix-web的响应体只能是内存中的数据, 无法使用sendfile, 以映射的内存输出, 只在写入socket时复制一次
+fn mapped(map: &Arc<Mmap>, pos: u64, len: u64) -> Bytes {
+    let (start, end) = (pos as usize, (pos + len) as usize);
+    let _ = map.advise_range(
+        Advice::WillNeed,
+        start,
+        (len + READ).min(map.len() as u64 - pos) as usize,
+    );
+    Bytes::from_owner(Mapped {
+        map: map.clone(),
+        start,
+        end,
+    })
+}
+
+async fn write_block(
+    file: Arc<File>,
+    entry: Arc<Entry>,
+    n: u64,
+    data: Vec<u8>,
+    claimed: bool,
+) -> io::Result<()> {
+    web::block(move || {
+        file.write_all_at(&data, n * BLOCK)?;
+        let added = entry.mark(n, claimed)?;
+        if let Some(disk) = DISK.as_ref()
+            && added > 0
+        {
+            disk.used.fetch_add(added, Ordering::Relaxed);
+            disk.evict(&entry.key);
+        }
+        Ok::<_, io::Error>(())
+    })
+    .await
+    .map_err(io::Error::other)?
+}
+
+impl Drop for Fill {
+    fn drop(&mut self) {
+        self.release();
+        self.entry.flush();
+    }
+}
+
+impl Fill {
+    fn stop(mut self) -> Self {
+        self.release();
+        self.pos = self.end + 1;
+        self.cur = Cur::Idle;
+        self
+    }
+
+    // 放弃当前从上游获取中还未写入的块
+    fn release(&mut self) {
+        if let Cur::Upstream {
+            end,
+            buf_start,
+            claimed,
+            ..
+        } = &mut self.cur
+            && *claimed
+        {
+            *claimed = false;
+            if *buf_start <= *end {
+                self.entry.release(*buf_start / BLOCK, *end / BLOCK);
+            }
+        }
+    }
+
+    // 从pos开始连续已缓存或连续缺失的块, 不超过end
+    fn run(&self, cached: bool) -> (u64, u64) {
+        let first = self.pos / BLOCK;
+        let last = self.end / BLOCK;
+        let mut n = first;
+        while n < last && self.entry.has(n + 1) == cached {
+            n += 1;
+        }
+        (first, n)
+    }
+}
+
+async fn next(mut s: Fill) -> Option<(Result<Bytes, io::Error>, Fill)> {
+    loop {
+        match &mut s.cur {
+            Cur::Idle => {
+                if s.pos > s.end {
+                    return None;
+                }
+                let total = s.entry.total;
+                if s.entry.has(s.pos / BLOCK) {
+                    let (_, last) = s.run(true);
+                    s.cur = Cur::File(s.end.min((last + 1) * BLOCK - 1));
+                    continue;
+                }
+                let (first, last) = s.run(false);
+                let entry = s.entry.clone();
+                let notified = entry.done.notified();
+                let (last, claimed) = match s.entry.claim(first, last) {
+                    Some(last) => (last, true),
+                    // 其他请求正在获取, 等待其写入后从文件读取
+                    // 超时后自行获取从first开始连续缺失的块, 不标记
+                    None => {
+                        let deadline = match s.waited {
+                            Some((n, deadline)) if n == first => deadline,
+                            _ => {
+                                let deadline = Instant::now() + PENDING_WAIT;
+                                s.waited = Some((first, deadline));
+                                deadline
+                            }
+                        };
+                        let now = Instant::now();
+                        if now < deadline {
+                            let _ = time::timeout(deadline - now, notified).await;
+                            continue;
+                        }
+                        (window(first, last), false)
+                    }
+                };
+                let (start, end) = (first * BLOCK, total.min((last + 1) * BLOCK) - 1);
+                s.cur = Cur::Upstream {
+                    body: stream::empty().boxed_local(),
+                    up_pos: start,
+                    end,
+                    buf: Vec::with_capacity(BLOCK as usize),
+                    buf_start: start,
+                    claimed,
+                };
+                match chunk::open(s.src.clone(), start, end, s.limits, 1).await {
+                    Ok((_, upstream)) => {
+                        if let Cur::Upstream { body, .. } = &mut s.cur {
+                            *body = upstream;
+                        }
+                    }
+                    Err(e) => return Some((Err(e), s.stop())),
+                }
+            }
+            Cur::File(end) => {
+                let end = *end;
+                if s.pos > end {
+                    s.cur = Cur::Idle;
+                    continue;
+                }
+                let len = READ.min(end - s.pos + 1);
+                let b = mapped(&s.map, s.pos, len);
+                s.pos += len;
+                return Some((Ok(b), s));
+            }
+            Cur::Upstream {
+                body,
+                up_pos,
+                end,
+                buf,
+                buf_start,
+                claimed,
+            } => {
+                if *up_pos > *end {
+                    s.cur = Cur::Idle;
+                    continue;
+                }
+                let b = match body.next().await {
+                    Some(Ok(b)) => b,
+                    Some(Err(e)) => return Some((Err(e), s.stop())),
+                    None => {
+                        let e = io::Error::new(io::ErrorKind::UnexpectedEof, "upstream closed");
+                        return Some((Err(e), s.stop()));
+                    }
+                };
+                let from = *up_pos;
+                *up_pos += b.len() as u64;
+                buf.extend_from_slice(&b);
+                // 凑满的块写入文件
+                while !buf.is_empty() {
+                    let n = *buf_start / BLOCK;
+                    let len = s.entry.block_len(n) as usize;
+                    if buf.len() < len {
+                        break;
+                    }
+                    let data: Vec<u8> = buf.drain(..len).collect();
+                    *buf_start += len as u64;
+                    let (file, entry) = (s.file.clone(), s.entry.clone());
+                    if let Err(e) = write_block(file, entry, n, data, *claimed).await {
+                        println!("disk cache write {}: {}", s.entry.key, e);
+                    }
+                }
+                // 只输出请求范围内的部分, 块对齐多获取的部分只写入缓存
+                let a = s.pos.max(from);
+                let z = (s.end + 1).min(from + b.len() as u64);
+                if a >= z {
+                    continue;
+                }
+                s.pos = z;
+                return Some((Ok(b.slice((a - from) as usize..(z - from) as usize)), s));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::upstream::limit;
+    use actix_web::{App, HttpResponse, HttpServer};
+
+    type Ranges = Arc<Mutex<Vec<(u64, u64)>>>;
+
+    // 每个测试使用独立的临时目录
+    fn disk(name: &str, budget: u64) -> Disk {
+        let dir = env::temp_dir().join(format!("disk-cache-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        Disk {
+            dir,
+            budget,
+            used: AtomicU64::new(0),
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn content(start: u64, end: u64) -> Vec<u8> {
+        (start..=end).map(|i| (i % 251) as u8).collect()
+    }
+
+    // 本地上游, 按query参数range返回区间, 记录收到的range
+    fn serve() -> (String, Ranges) {
+        let ranges = Arc::new(Mutex::new(Vec::new()));
+        let log = ranges.clone();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = format!("http://{}/f?id=1", listener.local_addr().unwrap());
+        let server = HttpServer::new(move || {
+            let log = log.clone();
+            App::new().default_service(web::to(move |q: web::Query<HashMap<String, String>>| {
+                let log = log.clone();
+                async move {
+                    let (a, b) = q["range"].split_once('-').unwrap();
+                    let (a, b): (u64, u64) = (a.parse().unwrap(), b.parse().unwrap());
+                    log.lock().unwrap().push((a, b));
+                    HttpResponse::Ok().body(content(a, b))
+                }
+            }))
+        })
+        .workers(1)
+        .listen(listener)
+        .unwrap()
+        .run();
+        actix_web::rt::spawn(server);
+        (addr, ranges)
+    }
+
+    async fn collect(mut body: Body) -> Vec<u8> {
+        let mut out = Vec::new();
+        while let Some(b) = body.next().await {
+            out.extend_from_slice(&b.unwrap());
+        }
+        out
+    }
+
+    #[test]
+    fn claim_and_mark() {
+        let d = disk("claim", 1 << 30);
+        let e = d.create("a", 4 * BLOCK + 10);
+        assert_eq!(e.blocks(), 5);
+        assert_eq!(e.block_len(4), 10);
+        assert_eq!(e.mark(2, false).unwrap(), BLOCK);
+        // 已缓存的块截断连续缺失的部分
+        assert_eq!(e.claim(0, 4), Some(1));
+        assert_eq!(e.claim(1, 4), None);
+        assert_eq!(e.claim(3, 4), Some(4));
+        e.release(0, 1);
+        assert_eq!(e.claim(1, 1), Some(1));
+        assert_eq!(e.mark(4, true).unwrap(), 10);
+        // 重复标记不再计入
+        assert_eq!(e.mark(4, true).unwrap(), 0);
+        assert!(e.has(2) && e.has(4) && !e.has(3));
+        assert_eq!(e.state.lock().unwrap().cached, BLOCK + 10);
+        assert!(e.state.lock().unwrap().pending == HashSet::from([1, 3]));
+        // 没有标记的请求写入块时不清除其他请求的标记
+        assert_eq!(e.mark(1, false).unwrap(), BLOCK);
+        assert!(e.state.lock().unwrap().pending == HashSet::from([1, 3]));
+        e.mark(1, true).unwrap();
+        assert!(e.state.lock().unwrap().pending == HashSet::from([3]));
+        let _ = fs::remove_dir_all(&d.dir);
+    }
+
+    #[test]
+    fn claim_window() {
+        let d = disk("window", 1 << 30);
+        let w = chunk::CHUNK_SIZE.div_ceil(BLOCK);
+        let e = d.create("w", (3 * w + 1) * BLOCK);
+        // 每次最多标记一个CHUNK_SIZE, 剩余的块可由其他请求标记
+        assert_eq!(e.claim(0, 3 * w), Some(w - 1));
+        assert_eq!(e.claim(w, 3 * w), Some(2 * w - 1));
+        assert_eq!(e.claim(3 * w, 3 * w), Some(3 * w));
+        assert_eq!(window(5, 3 * w), w + 4);
+        let _ = fs::remove_dir_all(&d.dir);
+    }
+
+    #[test]
+    fn reload() {
+        let d = disk("reload", 1 << 30);
+        let total = 9 * BLOCK + 5;
+        let e = d.create("b", total);
+        File::create(&e.path).unwrap().set_len(total).unwrap();
+        e.mark(0, false).unwrap();
+        e.mark(9, false).unwrap();
+        e.flush();
+        let d2 = Disk {
+            dir: d.dir.clone(),
+            budget: d.budget,
+            used: AtomicU64::new(0),
+            entries: Mutex::new(HashMap::new()),
+        };
+        d2.scan();
+        assert_eq!(d2.used.load(Ordering::Relaxed), BLOCK + 5);
+        let e2 = d2.entries.lock().unwrap()["b"].clone();
+        assert!(e2.has(0) && e2.has(9) && !e2.has(1));
+        // 长度与位图不符的缓存被删除
+        File::create(&e.path).unwrap().set_len(total - 1).unwrap();
+        d2.entries.lock().unwrap().clear();
+        d2.scan();
+        assert!(d2.entries.lock().unwrap().is_empty());
+        assert!(!e.map_path().exists());
+        let _ = fs::remove_dir_all(&d.dir);
+    }
+
+    #[test]
+    fn evict_oldest() {
+        let d = disk("evict", 10 * BLOCK);
+        let now = SystemTime::now();
+        for (i, key) in ["old", "mid", "new"].iter().enumerate() {
+            let e = Arc::new(d.create(key, 4 * BLOCK));
+            for n in 0..4 {
+                e.mark(n, false).unwrap();
+            }
+            e.state.lock().unwrap().used = now + Duration::from_secs(i as u64);
+            d.used.fetch_add(4 * BLOCK, Ordering::Relaxed);
+            d.entries.lock().unwrap().insert(key.to_string(), e);
+        }
+        // 正在写入的资源不被淘汰, 即使最久未使用
+        d.evict("old");
+        {
+            let entries = d.entries.lock().unwrap();
+            assert!(entries.contains_key("old") && entries.contains_key("new"));
+            assert!(!entries.contains_key("mid"));
+        }
+        assert_eq!(d.used.load(Ordering::Relaxed), 8 * BLOCK);
+        let _ = fs::remove_dir_all(&d.dir);
+    }
+
+    #[actix_web::test]
+    async fn fill_and_reuse() {
+        let d = disk("fill", 1 << 30);
+        let (addr, ranges) = serve();
+        let total = 2 * BLOCK + 1000;
+        let entry = Arc::new(d.create("c", total));
+        let src = Source {
+            url: addr,
+            refresh: None,
+        };
+        // 上游请求按块对齐, 只输出请求的部分
+        let (a, z) = (BLOCK + 10, BLOCK + 99);
+        let (_, body) = open(entry.clone(), src.clone(), a, z, limit::FILE)
+            .await
+            .unwrap();
+        assert!(collect(body).await == content(a, z));
+        assert_eq!(ranges.lock().unwrap().clone(), vec![(BLOCK, 2 * BLOCK - 1)]);
+        assert!(entry.has(1) && !entry.has(0));
+        // 已缓存的块从文件读取
+        let (_, body) = open(entry.clone(), src.clone(), a, z, limit::FILE)
+            .await
+            .unwrap();
+        assert!(collect(body).await == content(a, z));
+        assert_eq!(ranges.lock().unwrap().len(), 1);
+        let (_, body) = open(entry.clone(), src, 0, total - 1, limit::FILE)
+            .await
+            .unwrap();
+        assert!(collect(body).await == content(0, total - 1));
+        let expected = vec![
+            (BLOCK, 2 * BLOCK - 1),
+            (0, BLOCK - 1),
+            (2 * BLOCK, total - 1),
+        ];
+        assert_eq!(ranges.lock().unwrap().clone(), expected);
+        assert!((0..3).all(|n| entry.has(n)));
+        let _ = fs::remove_dir_all(&d.dir);
+    }
+
+    #[actix_web::test]
+    async fn overlapping_readers() {
+        let d = disk("overlap", 1 << 30);
+        let (addr, ranges) = serve();
+        let total = 4 * BLOCK;
+        let entry = Arc::new(d.create("o", total));
+        let src = Source {
+            url: addr,
+            refresh: None,
+        };
+        let read = |a: u64, z: u64| {
+            let (entry, src) = (entry.clone(), src.clone());
+            async move {
+                let (_, body) = open(entry, src, a, z, limit::FILE).await.unwrap();
+                collect(body).await
+            }
+        };
+        // 第二个请求等待第一个请求获取重叠的块, 只获取之后缺失的块
+        let (a, b) = futures_util::join!(read(0, 3 * BLOCK - 1), read(BLOCK, total - 1));
+        assert!(a == content(0, 3 * BLOCK - 1));
+        assert!(b == content(BLOCK, total - 1));
+        let expected = vec![(0, 3 * BLOCK - 1), (3 * BLOCK, total - 1)];
+        assert_eq!(ranges.lock().unwrap().clone(), expected);
+        assert!((0..4).all(|n| entry.has(n)));
+        assert!(entry.state.lock().unwrap().pending.is_empty());
+        let _ = fs::remove_dir_all(&d.dir);
+    }
+}
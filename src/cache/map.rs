@@ -44,6 +44,10 @@ impl<V> CacheMap<V> {
         pending.retain(|_, v| v.t.elapsed() < v.ttl);
     }
 
+    pub async fn remove(&self, key: &String) {
+        self.data.write().await.remove(key);
+    }
+
     pub async fn len(&self) -> usize {
         self.data.read().await.len()
     }
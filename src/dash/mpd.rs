@@ -0,0 +1,206 @@
+use actix_web::web;
+use awc::Client;
+use std::collections::BTreeMap;
+use std::error;
+use std::fmt::Write;
+use std::io;
+
+use crate::parser::{self, StreamItem, VideoInfo};
+
+const PROFILES: &str =
+    "urn:mpeg:dash:profile:isoff-on-demand:2011,urn:webm:dash:profile:webm-on-demand:2012";
+
+// 按 (类型, 容器, 编码, 语言) 分组, 同组内不同清晰度可自适应切换
+type Group<'a> = (&'a str, &'a str, &'a str, &'a str);
+
+pub async fn mpd(
+    client: &web::Data<Client>,
+    vid: &String,
+) -> Result<String, Box<dyn error::Error>> {
+    let info = parser::parse(client, vid).await?;
+    render(vid, &info)
+}
+
+fn render(vid: &str, info: &VideoInfo) -> Result<String, Box<dyn error::Error>> {
+    let mut groups: BTreeMap<Group, Vec<&StreamItem>> = BTreeMap::new();
+    for item in info.streams.values() {
+        // 只有adaptive格式才有initRange和indexRange
+        if item.init().is_none() || item.index().is_none() {
+            continue;
+        }
+        let (mime, codecs) = item.mime();
+        let Some((kind, _)) = mime.split_once('/') else {
+            continue;
+        };
+        let codec = codecs.split('.').next().unwrap_or(codecs);
+        let lang = match &item.audio_track {
+            Some(t) => t.lang.as_str(),
+            None => "",
+        };
+        groups
+            .entry((kind, mime, codec, lang))
+            .or_default()
+            .push(item);
+    }
+    if groups.is_empty() {
+        return Err(Box::new(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} adaptiveFormats", vid),
+        )));
+    }
+    let duration = info
+        .streams
+        .values()
+        .filter_map(|s| s.duration_ms)
+        .max()
+        .map(|ms| ms as f64 / 1000.0)
+        .unwrap_or_else(|| info.duration.parse().unwrap_or_default());
+
+    let mut out = String::new();
+    out.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    writeln!(
+        out,
+        "<MPD xmlns=\"urn:mpeg:dash:schema:mpd:2011\" profiles=\"{}\" type=\"static\" mediaPresentationDuration=\"PT{:.3}S\" minBufferTime=\"PT1.5S\">",
+        PROFILES, duration
+    )?;
+    writeln!(out, "<Period id=\"0\" start=\"PT0S\">")?;
+    for (id, ((kind, mime, _, lang), mut items)) in groups.into_iter().enumerate() {
+        items.sort_by_key(|s| s.bitrate.unwrap_or_default());
+        write!(
+            out,
+            "<AdaptationSet id=\"{}\" contentType=\"{}\" mimeType=\"{}\" subsegmentAlignment=\"true\" subsegmentStartsWithSAP=\"1\"",
+            id, kind, mime
+        )?;
+        if !lang.is_empty() {
+            write!(out, " lang=\"{}\"", escape(lang))?;
+        }
+        writeln!(out, ">")?;
+        if let Some(t) = items.first().and_then(|s| s.audio_track.as_ref()) {
+            let role = if t.default { "main" } else { "dub" };
+            writeln!(
+                out,
+                "<Role schemeIdUri=\"urn:mpeg:dash:role:2011\" value=\"{}\"/>",
+                role
+            )?;
+            if !t.name.is_empty() {
+                writeln!(out, "<Label>{}</Label>", escape(&t.name))?;
+            }
+        }
+        for item in items {
+            representation(&mut out, vid, item)?;
+        }
+        writeln!(out, "</AdaptationSet>")?;
+    }
+    writeln!(out, "</Period>")?;
+    writeln!(out, "</MPD>")?;
+    Ok(out)
+}
+
+fn representation(out: &mut String, vid: &str, item: &StreamItem) -> std::fmt::Result {
+    let (_, codecs) = item.mime();
+    let (init_start, init_end) = item.init().unwrap_or_default();
+    let (index_start, index_end) = item.index().unwrap_or_default();
+    write!(
+        out,
+        "<Representation id=\"{}\" bandwidth=\"{}\" codecs=\"{}\"",
+        // 多音轨时相同的itag出现在多个AdaptationSet中, id需要在Period内唯一
+        escape(&item.key()),
+        item.bitrate.unwrap_or_default(),
+        escape(codecs)
+    )?;
+    if let (Some(w), Some(h)) = (item.width, item.height) {
+        write!(out, " width=\"{}\" height=\"{}\"", w, h)?;
+    }
+    if let Some(fps) = item.fps {
+        write!(out, " frameRate=\"{}\"", fps)?;
+    }
+    if let Some(rate) = item.sample_rate {
+        write!(out, " audioSamplingRate=\"{}\"", rate)?;
+    }
+    writeln!(out, ">")?;
+    if let Some(ch) = item.channels {
+        writeln!(
+            out,
+            "<AudioChannelConfiguration schemeIdUri=\"urn:mpeg:dash:23003:3:audio_channel_configuration:2011\" value=\"{}\"/>",
+            ch
+        )?;
+    }
+    writeln!(out, "<BaseURL>{}</BaseURL>", escape(&item.proxy_path(vid)))?;
+    writeln!(
+        out,
+        "<SegmentBase indexRange=\"{}-{}\"><Initialization range=\"{}-{}\"/></SegmentBase>",
+        index_start, index_end, init_start, init_end
+    )?;
+    writeln!(out, "</Representation>")
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::tests::{adaptive, info};
+    use serde_json::json;
+
+    // 形如name="value"的所有值
+    fn values<'a>(xml: &'a str, name: &str) -> Vec<&'a str> {
+        let key = format!("{}=\"", name);
+        xml.match_indices(&key)
+            .filter_map(|(i, _)| xml[i + key.len()..].split('"').next())
+            .collect()
+    }
+
+    #[test]
+    fn adaptation_sets() {
+        let info = info(&[
+            adaptive(137, "video/mp4; codecs=\"avc1.640028\"", 4_000_000, None),
+            adaptive(136, "video/mp4; codecs=\"avc1.4d401f\"", 2_000_000, None),
+            adaptive(248, "video/webm; codecs=\"vp9\"", 3_000_000, None),
+            adaptive(
+                140,
+                "audio/mp4; codecs=\"mp4a.40.2\"",
+                128_000,
+                Some(("en.4", true)),
+            ),
+            adaptive(
+                140,
+                "audio/mp4; codecs=\"mp4a.40.2\"",
+                128_000,
+                Some(("es-US.3", false)),
+            ),
+            json!({"itag": 18, "mimeType": "video/mp4; codecs=\"avc1.42001E, mp4a.40.2\""}),
+        ]);
+        let xml = render("x", &info).unwrap();
+        // 按(类型, 容器, 编码, 语言)分组, 同组内按码率排序, progressive格式不输出
+        let sets: Vec<&str> = xml.split("<AdaptationSet").skip(1).collect();
+        assert_eq!(sets.len(), 4);
+        assert_eq!(values(sets[0], "lang"), vec!["en"]);
+        assert!(sets[0].contains("value=\"main\""));
+        assert_eq!(values(sets[1], "lang"), vec!["es-US"]);
+        assert!(sets[1].contains("value=\"dub\""));
+        assert!(sets[1].contains("<BaseURL>/video/x/140.mp4?lang=es-US</BaseURL>"));
+        assert_eq!(values(sets[2], "mimeType"), vec!["video/mp4"]);
+        assert_eq!(values(sets[2], "bandwidth"), vec!["2000000", "4000000"]);
+        assert_eq!(values(sets[3], "mimeType"), vec!["video/webm"]);
+        // 配音的Representation id在Period内唯一
+        let mut ids = values(&xml, "Representation id");
+        assert_eq!(ids, vec!["140", "140-es-US", "136", "137", "248"]);
+        ids.sort();
+        ids.dedup();
+        assert_eq!(ids.len(), 5);
+        assert!(sets[2].contains(
+            "<SegmentBase indexRange=\"137-2000\"><Initialization range=\"0-136\"/></SegmentBase>"
+        ));
+    }
+
+    #[test]
+    fn no_adaptive() {
+        let info = info(&[json!({"itag": 18, "mimeType": "video/mp4"})]);
+        assert!(render("x", &info).is_err());
+    }
+}
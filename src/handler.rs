@@ -1,12 +1,35 @@
+use crate::cache::disk;
+use crate::media::tags::{self, Tags};
+use crate::media::{audio, clip, fragment, mux, seek};
 use crate::parser;
-use actix_web::http::StatusCode;
-use actix_web::http::header::CACHE_CONTROL;
+use crate::upstream::ahead;
+use crate::upstream::chunk;
+use crate::upstream::fanout;
+use crate::upstream::hedge;
+use crate::upstream::limit::{self, Limits};
+use crate::upstream::range::{self, Ranges};
+use crate::upstream::shape::{self, Shaper};
+use crate::util;
+use actix_web::HttpResponseBuilder;
+use actix_web::http::header::{
+    ACCEPT_RANGES, CACHE_CONTROL, CONTENT_DISPOSITION, CONTENT_RANGE, CONTENT_TYPE, ETAG,
+    HeaderName, HttpDate, IF_MODIFIED_SINCE, IF_NONE_MATCH, IF_RANGE, LAST_MODIFIED, LOCATION,
+    RANGE,
+};
+use actix_web::http::{Method, StatusCode};
+use actix_web::web::Bytes;
 use actix_web::{HttpRequest, HttpResponse, Responder, web};
 use awc::Client;
 use awc::ClientRequest;
 use core::time::Duration;
+use futures_util::stream::{self, StreamExt};
+use serde::Serialize;
+use std::env;
 use std::error;
 use std::io::{Error, ErrorKind};
+use std::net::IpAddr;
+use std::sync::LazyLock;
+use std::time::UNIX_EPOCH;
 
 // 暴露的headers, 此处需要是小写
 const EXPOSE: &[&str] = &[
@@ -27,6 +50,7 @@ const FWD: &[&str] = &[
     "accept-language",
     "if-modified-since",
     "if-none-match",
+    "if-range",
     "range",
     "content-length",
     "content-type",
@@ -51,6 +75,15 @@ const FWD_SIMPLE: &[&str] = &[
     "content-type",
 ];
 
+static REDIRECT: LazyLock<Vec<String>> = LazyLock::new(|| {
+    env::var("REDIRECT")
+        .unwrap_or_default()
+        .split(',')
+        .map(|v| v.trim().to_owned())
+        .filter(|v| !v.is_empty())
+        .collect()
+});
+
 const PREFER_LIST: &str =
     "18,59,22,37,243,134,396,244,135,397,247,136,302,398,248,137,242,133,395,278,598,160,597";
 
@@ -61,17 +94,9 @@ pub async fn get_info(
     parser::parse(client, vid).await
 }
 
-pub async fn proxy_image(
-    client: web::Data<Client>,
-    req: HttpRequest,
-    vid: String,
-    ext: String,
-) -> impl Responder {
-    let url = match ext.as_str() {
-        "jpg" => format!("https://i.ytimg.com/vi/{}/mqdefault.{}", vid, ext),
-        _ => format!("https://i.ytimg.com/vi_webp/{}/mqdefault.{}", vid, ext),
-    };
-    proxy(client, req, url, 10, None).await
+pub async fn proxy_image(req: HttpRequest, vid: String, ext: String) -> impl Responder {
+    let url = util::thumbnail(&vid, &ext);
+    proxy(req, url, "image", limit::IMAGE, None).await
 }
 
 pub async fn proxy_ts(
@@ -88,20 +113,20 @@ pub async fn proxy_ts(
                 url.push_str(&item.url);
                 url.push_str("&range=");
                 url.push_str(&part);
-                simple_proxy(client, req, url, 30, None).await
+                simple_proxy(req, url, "ts", limit::TS, None).await
             }
             None => {
                 simple_proxy(
-                    client,
                     req,
                     "".to_owned(),
-                    10,
+                    "",
+                    limit::TS,
                     Some(Box::new(Error::new(ErrorKind::NotFound, "itag not found"))),
                 )
                 .await
             }
         },
-        Err(err) => simple_proxy(client, req, "".to_owned(), 10, Some(err)).await,
+        Err(err) => simple_proxy(req, "".to_owned(), "", limit::TS, Some(err)).await,
     }
 }
 
@@ -110,90 +135,741 @@ pub async fn proxy_file(
     req: HttpRequest,
     vid: String,
     itag: String,
-) -> impl Responder {
+    lang: &str,
+) -> impl Responder + use<> {
     match get_info(&client, &vid).await {
-        Ok(res) => match res.streams.get(&itag) {
-            Some(item) => proxy(client, req, item.url.clone(), 3600, None).await,
+        Ok(res) => match res.stream(&itag, lang) {
+            Some(item) => match redirect(&req, item, "stream") {
+                Some(resp) => resp,
+                None => media_proxy(client, req, &res, item, "stream", limit::FILE).await,
+            },
             None => {
                 proxy(
-                    client,
                     req,
                     "".to_owned(),
-                    10,
+                    "",
+                    limit::FILE,
                     Some(Box::new(Error::new(ErrorKind::NotFound, "itag not found"))),
                 )
                 .await
             }
         },
-        Err(err) => proxy(client, req, "".to_owned(), 10, Some(err)).await,
+        Err(err) => proxy(req, "".to_owned(), "", limit::FILE, Some(err)).await,
     }
 }
 
+// lang指定了非默认音轨时, progressive格式只有默认音轨, 改为合并adaptive的视频与该音轨
 pub async fn proxy_auto(
     client: web::Data<Client>,
     req: HttpRequest,
     vid: String,
+    ext: String,
     prefer: &str,
+    lang: &str,
+) -> HttpResponse {
+    let res = match get_info(&client, &vid).await {
+        Ok(res) => res,
+        Err(err) => return proxy(req, "".to_owned(), "", limit::FILE, Some(err)).await,
+    };
+    if let Some((vitag, aitag)) = find_dubbed(&res, prefer, lang, &ext) {
+        return proxy_mux(client, req, vid, vitag, aitag, ext, lang).await;
+    }
+    match find_item(&res, prefer, lang) {
+        Some(item) => match redirect(&req, item, "auto") {
+            Some(resp) => resp,
+            None => media_proxy(client, req, &res, item, "auto", limit::FILE).await,
+        },
+        None => {
+            proxy(
+                req,
+                "".to_owned(),
+                "",
+                limit::FILE,
+                Some(Box::new(Error::new(ErrorKind::NotFound, "itag not found"))),
+            )
+            .await
+        }
+    }
+}
+
+pub async fn proxy_mux(
+    client: web::Data<Client>,
+    req: HttpRequest,
+    vid: String,
+    vitag: String,
+    aitag: String,
+    ext: String,
+    lang: &str,
+) -> HttpResponse {
+    let tags = tags(&client, &req, &vid).await;
+    match mux::mux(&client, &vid, &vitag, &aitag, &ext, lang, tags.as_ref()).await {
+        Ok(res) => {
+            let mut client_resp = HttpResponse::Ok();
+            client_resp
+                .content_type(res.content_type)
+                .insert_header((ACCEPT_RANGES, "none"))
+                .insert_header((CACHE_CONTROL, "public,max-age=3600"))
+                .no_chunking(res.len);
+            // 写入元数据时内容不同, 不使用ETag
+            if let Some(etag) = res.etag
+                && tags.is_none()
+            {
+                client_resp.insert_header((ETAG, etag));
+            }
+            if download(&req) {
+                let name = filename(&res.title, &res.label, &ext);
+                client_resp.insert_header((CONTENT_DISPOSITION, name));
+            }
+            if req.method() == Method::HEAD {
+                return head_only(client_resp);
+            }
+            let shaper = Shaper::new("mux", util::client_ip(&req), None);
+            client_resp.streaming(shaper.wrap(res.body))
+        }
+        Err(err) => HttpResponse::InternalServerError().body(format!("{:?}", err)),
+    }
+}
+
+pub async fn proxy_audio(
+    client: web::Data<Client>,
+    req: HttpRequest,
+    vid: String,
+    ext: String,
+    lang: &str,
 ) -> impl Responder + use<> {
-    match get_info(&client, &vid).await {
-        Ok(res) => match find_item(res, prefer) {
-            Some(item) => proxy(client, req, item, 3600, None).await,
-            None => {
-                proxy(
-                    client,
-                    req,
-                    "".to_owned(),
-                    10,
-                    Some(Box::new(Error::new(ErrorKind::NotFound, "itag not found"))),
-                )
-                .await
+    let tags = tags(&client, &req, &vid).await;
+    match audio::extract(&client, &vid, &ext, lang, tags.as_ref()).await {
+        Ok(res) => {
+            let mut client_resp = HttpResponse::Ok();
+            client_resp
+                .content_type(res.content_type)
+                .insert_header((ACCEPT_RANGES, "none"))
+                .insert_header((CACHE_CONTROL, "public,max-age=3600"));
+            if let Some(len) = res.len {
+                client_resp.no_chunking(len);
             }
-        },
-        Err(err) => proxy(client, req, "".to_owned(), 10, Some(err)).await,
+            // 写入元数据时内容不同, 不使用ETag
+            if let Some(etag) = res.etag
+                && tags.is_none()
+            {
+                client_resp.insert_header((ETAG, etag));
+            }
+            if download(&req) {
+                let name = filename(&res.title, &res.label, &ext);
+                client_resp.insert_header((CONTENT_DISPOSITION, name));
+            }
+            if req.method() == Method::HEAD {
+                return head_only(client_resp);
+            }
+            let shaper = Shaper::new("audio", util::client_ip(&req), None);
+            client_resp.streaming(shaper.wrap(res.body))
+        }
+        Err(err) => HttpResponse::InternalServerError().body(format!("{:?}", err)),
     }
 }
 
-async fn proxy(
+pub async fn proxy_clip(
+    client: web::Data<Client>,
+    req: HttpRequest,
+    vid: String,
+    itag: String,
+    lang: &str,
+) -> impl Responder + use<> {
+    let info = match get_info(&client, &vid).await {
+        Ok(res) => res,
+        Err(err) => return HttpResponse::InternalServerError().body(format!("{:?}", err)),
+    };
+    let Some(item) = info.stream(&itag, lang) else {
+        let err = Error::new(ErrorKind::NotFound, "itag not found");
+        return HttpResponse::InternalServerError().body(format!("{:?}", err));
+    };
+    let t = seek_time(&client, &req, &info).await.unwrap_or_default();
+    let tags = tags(&client, &req, &vid).await;
+    clip_proxy(client, req, &info, item, "clip", limit::FILE, tags, t).await
+}
+
+// progressive格式的fMP4分片, n为None时输出init
+pub async fn proxy_fragment(
+    client: web::Data<Client>,
+    req: HttpRequest,
+    vid: String,
+    itag: String,
+    n: Option<usize>,
+) -> impl Responder + use<> {
+    let info = match get_info(&client, &vid).await {
+        Ok(res) => res,
+        Err(err) => return HttpResponse::InternalServerError().body(format!("{:?}", err)),
+    };
+    let Some(item) = info.stream(&itag, "") else {
+        let err = Error::new(ErrorKind::NotFound, "itag not found");
+        return HttpResponse::InternalServerError().body(format!("{:?}", err));
+    };
+    let res = match n {
+        Some(n) => fragment::fragment(&client, &vid, item, n).await,
+        None => fragment::init(&client, item).await,
+    };
+    let data = match res {
+        Ok(data) => data,
+        Err(err) => return HttpResponse::InternalServerError().body(format!("{:?}", err)),
+    };
+    let content_type = match n {
+        Some(_) => "video/iso.segment",
+        None => "video/mp4",
+    };
+    let mut client_resp = HttpResponse::Ok();
+    client_resp
+        .content_type(content_type)
+        .insert_header((CACHE_CONTROL, "public,max-age=86400"))
+        .no_chunking(data.len() as u64);
+    if req.method() == Method::HEAD {
+        return head_only(client_resp);
+    }
+    let shaper = Shaper::new("fmp4", util::client_ip(&req), None);
+    client_resp.streaming(shaper.wrap(stream::once(async move { Ok::<_, Error>(data) })))
+}
+
+// 已知文件大小的媒体文件, 自行处理Range并分段请求上游
+async fn media_proxy(
+    client: web::Data<Client>,
+    req: HttpRequest,
+    info: &parser::VideoInfo,
+    item: &parser::StreamItem,
+    route: &str,
+    limits: Limits,
+) -> HttpResponse {
+    let total: u64 = item.len.parse().unwrap_or_default();
+    if total == 0 {
+        return proxy(req, item.url.clone(), route, limits, None).await;
+    }
+    if let Some(t) = seek_time(&client, &req, info).await {
+        if item.index().is_none() {
+            return clip_proxy(client, req, info, item, route, limits, None, t).await;
+        }
+        return seek_proxy(client, req, info, item, route, limits, t).await;
+    }
+    let (etag, modified) = validators(item);
+    if not_modified(&req, etag.as_deref(), modified) {
+        let mut client_resp = HttpResponse::NotModified();
+        set_validators(&mut client_resp, &etag, modified);
+        return client_resp.finish();
+    }
+    // If-Range不匹配时忽略Range, 返回整个文件
+    let ranges = match if_range(&req, etag.as_deref(), modified) {
+        true => range::parse(req.headers().get(RANGE), total),
+        false => Ranges::Full,
+    };
+    let ranges = match ranges {
+        Ranges::Full => vec![(0, total - 1)],
+        Ranges::Partial(r) => r,
+        Ranges::Unsatisfiable => {
+            return HttpResponse::RangeNotSatisfiable()
+                .insert_header((CONTENT_RANGE, format!("bytes */{}", total)))
+                .finish();
+        }
+    };
+    let partial = ranges.len() > 1 || ranges[0] != (0, total - 1);
+    let mut client_resp = if partial {
+        HttpResponse::PartialContent()
+    } else {
+        HttpResponse::Ok()
+    };
+    client_resp.insert_header((ACCEPT_RANGES, "bytes"));
+    set_validators(&mut client_resp, &etag, modified);
+    if !partial {
+        client_resp.insert_header((CACHE_CONTROL, "public,max-age=86400"));
+    }
+    if download(&req) {
+        let name = filename(&info.title, &item.label(), item.ext());
+        client_resp.insert_header((CONTENT_DISPOSITION, name));
+    }
+    let mime = item.mime().0;
+    // 多个range以multipart/byteranges返回
+    let boundary = util::hash(&item.url);
+    let (start, end) = ranges[0];
+    let len = if ranges.len() == 1 {
+        if partial {
+            client_resp
+                .insert_header((CONTENT_RANGE, format!("bytes {}-{}/{}", start, end, total)));
+        }
+        client_resp.insert_header((CONTENT_TYPE, mime));
+        end - start + 1
+    } else {
+        client_resp.insert_header((
+            CONTENT_TYPE,
+            format!("multipart/byteranges; boundary={}", boundary),
+        ));
+        ranges
+            .iter()
+            .map(|(a, b)| {
+                range::part_header(&boundary, mime, *a, *b, total).len() as u64 + b - a + 1
+            })
+            .sum::<u64>()
+            + range::tail(&boundary).len() as u64
+    };
+    client_resp.no_chunking(len);
+    // HEAD请求只需要响应头, 不请求上游
+    if req.method() == Method::HEAD {
+        return head_only(client_resp);
+    }
+    let n = parallel(&req);
+    let shaper = Shaper::new(route, util::client_ip(&req), pace(&req, route, item));
+    let src = chunk::source(&client, &info.id, item);
+    // 开启磁盘缓存时优先使用缓存, 否则单个range时与其他相同请求共享上游连接
+    let cache = disk::entry(&info.id, item, total);
+    let stream_key = format!("{}/{}/{}", info.id, item.itag, item.lang());
+    // 单个range时先使用上次预读的数据, 并在后台预读下一段
+    let ahead = match ranges.len() {
+        1 => ahead::take(&stream_key, start, end, limits.ttfb).await,
+        _ => None,
+    };
+    let from = start + ahead.as_ref().map_or(0, |b| b.len() as u64);
+    let opened = match (&cache, ranges.len()) {
+        _ if from > end => Ok((Default::default(), stream::empty().boxed_local())),
+        (Some(entry), _) => disk::open(entry.clone(), src.clone(), from, end, limits).await,
+        (None, 1) => {
+            let key = format!("{}/{}-{}", stream_key, from, end);
+            fanout::open(key, src.clone(), from, end, limits, n).await
+        }
+        (None, _) => chunk::open(src.clone(), start, end, limits, n).await,
+    };
+    let (headers, body) = match opened {
+        Ok(res) => res,
+        Err(e) => return HttpResponse::BadGateway().body(format!("{:?}", e)),
+    };
+    if etag.is_none() && modified.is_none() {
+        for name in [ETAG, LAST_MODIFIED] {
+            if let Some(val) = headers.get(&name) {
+                client_resp.insert_header((name, val.clone()));
+            }
+        }
+    }
+    if ranges.len() == 1 {
+        let body = match ahead {
+            Some(data) => stream::once(async move { Ok(data) })
+                .chain(body)
+                .boxed_local(),
+            None => body,
+        };
+        // 第一块数据输出后才开始预读, 不与当前请求争抢上游连接, 首块出错时也不预读
+        let mut prefetch = Some((stream_key, src, cache));
+        let body = body.inspect(move |chunk| {
+            if chunk.is_ok()
+                && let Some((key, src, cache)) = prefetch.take()
+            {
+                ahead::prefetch(key, src, cache, end + 1, total, limits);
+            }
+        });
+        return client_resp.streaming(shaper.wrap(body));
+    }
+    // 第一个range已经打开, 其余依次请求上游
+    let head = range::part_header(&boundary, mime, start, end, total);
+    let mut parts: Vec<chunk::Body> = vec![
+        stream::once(async move { Ok(Bytes::from(head)) })
+            .chain(body)
+            .boxed_local(),
+    ];
+    for (a, b) in ranges.iter().copied().skip(1) {
+        let head = range::part_header(&boundary, mime, a, b, total);
+        let head = stream::once(async move { Ok(Bytes::from(head)) });
+        let (src, cache) = (src.clone(), cache.clone());
+        let body = stream::once(async move {
+            let opened = match cache {
+                Some(entry) => disk::open(entry, src, a, b, limits).await,
+                None => chunk::open(src, a, b, limits, 1).await,
+            };
+            match opened {
+                Ok((_, body)) => body,
+                Err(e) => stream::once(async move { Err(e) }).boxed_local(),
+            }
+        })
+        .flatten();
+        parts.push(head.chain(body).boxed_local());
+    }
+    let tail = range::tail(&boundary);
+    let body = stream::iter(parts)
+        .flatten()
+        .chain(stream::once(async move { Ok(Bytes::from(tail)) }));
+    client_resp.streaming(shaper.wrap(body))
+}
+
+// 生成moov在前的独立文件, 输出[start, end)秒; 也用于progressive格式按时间输出
+#[allow(clippy::too_many_arguments)]
+async fn clip_proxy(
+    client: web::Data<Client>,
+    req: HttpRequest,
+    info: &parser::VideoInfo,
+    item: &parser::StreamItem,
+    route: &str,
+    limits: Limits,
+    tags: Option<Tags>,
+    (start, end): (f64, Option<f64>),
+) -> HttpResponse {
+    let res = match clip::clip(&client, item, start, end, tags.as_ref()).await {
+        Ok(res) => res,
+        Err(err) => return HttpResponse::InternalServerError().body(format!("{:?}", err)),
+    };
+    let (a, z) = res.range;
+    let mut client_resp = HttpResponse::Ok();
+    client_resp
+        .content_type("video/mp4")
+        .insert_header((ACCEPT_RANGES, "none"))
+        .insert_header((CACHE_CONTROL, "public,max-age=86400"))
+        .insert_header(("x-start-time", format!("{:.3}", res.start)))
+        .no_chunking(res.head.len() as u64 + z - a + 1);
+    if download(&req) {
+        let label = match end {
+            Some(end) => format!("{} {:.0}-{:.0}", item.label(), res.start, end),
+            None => format!("{} {:.0}-", item.label(), res.start),
+        };
+        client_resp.insert_header((CONTENT_DISPOSITION, filename(&info.title, &label, "mp4")));
+    }
+    if req.method() == Method::HEAD {
+        return head_only(client_resp);
+    }
+    let src = chunk::source(&client, &info.id, item);
+    let body = match chunk::open(src, a, z, limits, 1).await {
+        Ok((_, body)) => body,
+        Err(e) => return HttpResponse::BadGateway().body(format!("{:?}", e)),
+    };
+    let head = res.head;
+    let body = stream::once(async move { Ok(head) }).chain(body);
+    let shaper = Shaper::new(route, util::client_ip(&req), pace(&req, route, item));
+    client_resp.streaming(shaper.wrap(body))
+}
+
+// adaptive格式按时间输出, 对齐到关键帧, 输出init加上对应的分片, 响应头X-Start-Time为实际开始的时间
+async fn seek_proxy(
     client: web::Data<Client>,
+    req: HttpRequest,
+    info: &parser::VideoInfo,
+    item: &parser::StreamItem,
+    route: &str,
+    limits: Limits,
+    (start, end): (f64, Option<f64>),
+) -> HttpResponse {
+    let total: u64 = item.len.parse().unwrap_or_default();
+    let sk = match seek::seek(&client, item, start, end).await {
+        Ok(res) => res,
+        Err(err) => return HttpResponse::InternalServerError().body(format!("{:?}", err)),
+    };
+    let (a, z) = sk.range;
+    let mut client_resp = match sk.init {
+        Some(_) => HttpResponse::Ok(),
+        None => HttpResponse::PartialContent(),
+    };
+    match sk.init {
+        Some(_) => client_resp.insert_header((ACCEPT_RANGES, "none")),
+        None => client_resp
+            .insert_header((ACCEPT_RANGES, "bytes"))
+            .insert_header((CONTENT_RANGE, format!("bytes {}-{}/{}", a, z, total))),
+    };
+    client_resp
+        .content_type(item.mime().0)
+        .insert_header((CACHE_CONTROL, "public,max-age=86400"))
+        .insert_header(("x-start-time", format!("{:.3}", sk.start)));
+    if download(&req) {
+        let name = filename(&info.title, &item.label(), item.ext());
+        client_resp.insert_header((CONTENT_DISPOSITION, name));
+    }
+    let parts: Vec<(u64, u64)> = sk.init.into_iter().chain([(a, z)]).collect();
+    client_resp.no_chunking(parts.iter().map(|(a, z)| z - a + 1).sum());
+    if req.method() == Method::HEAD {
+        return head_only(client_resp);
+    }
+    let src = chunk::source(&client, &info.id, item);
+    let (first, rest) = (parts[0], parts[1..].to_vec());
+    let body = match chunk::open(src.clone(), first.0, first.1, limits, 1).await {
+        Ok((_, body)) => body,
+        Err(e) => return HttpResponse::BadGateway().body(format!("{:?}", e)),
+    };
+    let rest = stream::iter(rest)
+        .then(move |(a, z)| {
+            let src = src.clone();
+            async move {
+                match chunk::open(src, a, z, limits, 1).await {
+                    Ok((_, body)) => body,
+                    Err(e) => stream::once(async move { Err(e) }).boxed_local(),
+                }
+            }
+        })
+        .flatten();
+    let shaper = Shaper::new(route, util::client_ip(&req), pace(&req, route, item));
+    client_resp.streaming(shaper.wrap(body.chain(rest)))
+}
+
+// 保留Content-Length, 不输出body
+fn head_only(mut client_resp: HttpResponseBuilder) -> HttpResponse {
+    client_resp.streaming(stream::empty::<Result<Bytes, Error>>())
+}
+
+// 由地址中的lmt(微秒)生成ETag及Last-Modified, 不需要请求上游
+fn validators(item: &parser::StreamItem) -> (Option<String>, Option<HttpDate>) {
+    let Some(lmt) = util::query_param(&item.url, "lmt") else {
+        return (None, None);
+    };
+    let modified = lmt
+        .parse::<u64>()
+        .ok()
+        .map(|v| HttpDate::from(UNIX_EPOCH + Duration::from_micros(v)));
+    (Some(format!("\"{}-{}\"", item.itag, lmt)), modified)
+}
+
+fn set_validators(
+    client_resp: &mut HttpResponseBuilder,
+    etag: &Option<String>,
+    modified: Option<HttpDate>,
+) {
+    if let Some(etag) = etag {
+        client_resp.insert_header((ETAG, etag.as_str()));
+    }
+    if let Some(modified) = modified {
+        client_resp.insert_header((LAST_MODIFIED, modified.to_string()));
+    }
+}
+
+fn header(req: &HttpRequest, name: HeaderName) -> Option<&str> {
+    req.headers().get(name).and_then(|v| v.to_str().ok())
+}
+
+fn not_modified(req: &HttpRequest, etag: Option<&str>, modified: Option<HttpDate>) -> bool {
+    if let Some(tags) = header(req, IF_NONE_MATCH) {
+        let Some(etag) = etag else {
+            return false;
+        };
+        return tags
+            .split(',')
+            .map(|t| t.trim().trim_start_matches("W/"))
+            .any(|t| t == "*" || t == etag);
+    }
+    match (header(req, IF_MODIFIED_SINCE), modified) {
+        (Some(since), Some(modified)) => since.parse::<HttpDate>().is_ok_and(|s| modified <= s),
+        _ => false,
+    }
+}
+
+// If-Range使用强比较, 弱ETag以及无法验证的情况都视为不匹配
+fn if_range(req: &HttpRequest, etag: Option<&str>, modified: Option<HttpDate>) -> bool {
+    let Some(v) = header(req, IF_RANGE) else {
+        return true;
+    };
+    if v.starts_with('"') || v.starts_with("W/") {
+        return Some(v) == etag;
+    }
+    match (v.parse::<HttpDate>(), modified) {
+        (Ok(v), Some(modified)) => v == modified,
+        _ => false,
+    }
+}
+
+#[derive(Default)]
+struct Options {
+    parallel: Option<usize>,
+    download: Option<String>,
+    mode: Option<String>,
+    pace: Option<String>,
+    t: Option<f64>,
+    start: Option<f64>,
+    end: Option<f64>,
+    chapter: Option<usize>,
+}
+
+// 各参数分别解析, 一个参数的值无效时只忽略该参数
+fn options(req: &HttpRequest) -> Options {
+    let Ok(query) = web::Query::<Vec<(String, String)>>::from_query(req.query_string()) else {
+        return Options::default();
+    };
+    let get = |k: &str| {
+        query
+            .iter()
+            .rev()
+            .find(|(n, _)| n == k)
+            .map(|(_, v)| v.clone())
+    };
+    let seconds = |k: &str| {
+        get(k)
+            .and_then(|v| v.parse::<f64>().ok())
+            .filter(|v| v.is_finite())
+    };
+    Options {
+        parallel: get("parallel").and_then(|v| v.parse().ok()),
+        download: get("download"),
+        mode: get("mode"),
+        pace: get("pace"),
+        t: seconds("t"),
+        start: seconds("start"),
+        end: seconds("end"),
+        chapter: get("chapter").and_then(|v| v.parse().ok()),
+    }
+}
+
+// 下载时可通过query参数parallel或请求头x-parallel开启多连接并行获取
+fn parallel(req: &HttpRequest) -> usize {
+    let query = options(req).parallel;
+    let header = req
+        .headers()
+        .get("x-parallel")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok());
+    query.or(header).unwrap_or(1)
+}
+
+// query参数download存在且不为0时作为附件下载
+fn download(req: &HttpRequest) -> bool {
+    options(req)
+        .download
+        .is_some_and(|v| v != "0" && v != "false")
+}
+
+// 播放时先突发一段数据, 之后限速为码率的1.5倍, query参数pace可覆盖路由的默认配置
+fn pace(req: &HttpRequest, route: &str, item: &parser::StreamItem) -> Option<u64> {
+    let on = match options(req).pace {
+        Some(v) => v != "0" && v != "false",
+        None => shape::pace_default(route),
+    };
+    if !on {
+        return None;
+    }
+    // 没有码率时由文件大小及时长估算
+    item.bitrate.or_else(|| {
+        let len: u64 = item.len.parse().ok()?;
+        let ms = item.duration_ms.filter(|v| *v > 0)?;
+        Some(len * 8000 / ms)
+    })
+}
+
+// query参数t或start/end(秒)按时间输出, chapter=N(从1开始)从第N个章节开始
+async fn seek_time(
+    client: &web::Data<Client>,
+    req: &HttpRequest,
+    info: &parser::VideoInfo,
+) -> Option<(f64, Option<f64>)> {
+    let q = options(req);
+    let chapter = match q.chapter.and_then(|n| n.checked_sub(1)) {
+        Some(i) => parser::load_chapters(client, info)
+            .await
+            .get(i)
+            .map(|c| c.start),
+        None => None,
+    };
+    let start = chapter.or(q.t).or(q.start);
+    if start.is_none() && q.end.is_none() {
+        return None;
+    }
+    Some((start.unwrap_or_default(), q.end))
+}
+
+#[derive(Serialize)]
+struct Resolved<'a> {
+    url: &'a str,
+    expire: u64,
+}
+
+// query参数mode为redirect时302到上游地址, 为json时返回地址及过期时间
+// 环境变量REDIRECT可配置默认使用redirect的路由, 例如REDIRECT=stream,auto
+fn redirect(req: &HttpRequest, item: &parser::StreamItem, route: &str) -> Option<HttpResponse> {
+    let mode = options(req)
+        .mode
+        .unwrap_or_else(|| match REDIRECT.iter().any(|r| r == route) {
+            true => "redirect".to_owned(),
+            false => "".to_owned(),
+        });
+    if mode != "redirect" && mode != "json" {
+        return None;
+    }
+    // 上游地址绑定了解析时的ip, 客户端ip不同时无法使用
+    let ip = util::query_param(&item.url, "ip")
+        .and_then(util::percent_decode)
+        .and_then(|v| v.parse::<IpAddr>().ok());
+    let client_ip = util::client_ip(req);
+    if ip.is_none() || ip != client_ip {
+        return Some(HttpResponse::Forbidden().body(format!(
+            "ip mismatch {} {}",
+            ip.map(|v| v.to_string()).unwrap_or_default(),
+            client_ip.map(|v| v.to_string()).unwrap_or_default()
+        )));
+    }
+    if mode == "json" {
+        let expire = util::query_param(&item.url, "expire")
+            .and_then(|v| v.parse().ok())
+            .unwrap_or_default();
+        return Some(HttpResponse::Ok().json(Resolved {
+            url: &item.url,
+            expire,
+        }));
+    }
+    Some(
+        HttpResponse::Found()
+            .insert_header((LOCATION, item.url.as_str()))
+            .finish(),
+    )
+}
+
+// 下载时写入容器的元数据, HEAD请求不输出内容, 不获取元数据及封面
+async fn tags(client: &web::Data<Client>, req: &HttpRequest, vid: &String) -> Option<Tags> {
+    if !download(req) || req.method() == Method::HEAD {
+        return None;
+    }
+    let info = get_info(client, vid).await.ok()?;
+    Some(tags::tags(client, &info).await)
+}
+
+fn filename(title: &str, label: &str, ext: &str) -> String {
+    let name = match label {
+        "" => format!("{}.{}", title, ext),
+        _ => format!("{} [{}].{}", title, label, ext),
+    };
+    util::content_disposition(&name)
+}
+
+async fn proxy(
     req: HttpRequest,
     url: String,
-    timeout: u64,
+    route: &str,
+    limits: Limits,
     err: Option<Box<dyn error::Error>>,
-) -> impl Responder {
-    base_proxy(client, req, url, timeout, err, FWD, EXPOSE).await
+) -> HttpResponse {
+    base_proxy(req, url, route, limits, err, FWD, EXPOSE).await
 }
 
 async fn simple_proxy(
-    client: web::Data<Client>,
     req: HttpRequest,
     url: String,
-    timeout: u64,
+    route: &str,
+    limits: Limits,
     err: Option<Box<dyn error::Error>>,
-) -> impl Responder {
-    base_proxy(client, req, url, timeout, err, FWD_SIMPLE, EXPOSE_SIMPLE).await
+) -> HttpResponse {
+    base_proxy(req, url, route, limits, err, FWD_SIMPLE, EXPOSE_SIMPLE).await
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn base_proxy(
-    client: web::Data<Client>,
     req: HttpRequest,
     url: String,
-    timeout: u64,
+    route: &str,
+    limits: Limits,
     err: Option<Box<dyn error::Error>>,
     forward_headers: &'static [&str],
     expose_headers: &'static [&str],
-) -> impl Responder {
+) -> HttpResponse {
     if let Some(err) = err {
         return HttpResponse::InternalServerError().body(format!("{:?}", err));
     }
-    let mut forwarded_req = request(client, url, timeout);
-
+    // HEAD请求同样以HEAD请求上游, 不打开响应体
+    let method = match *req.method() {
+        Method::HEAD => Method::HEAD,
+        _ => Method::GET,
+    };
     let r = req.headers();
-    for item in forward_headers {
-        if let Some(val) = r.get(*item) {
-            forwarded_req = forwarded_req.insert_header((*item, val.clone()));
+    let forwarded_req = |url: &str| {
+        let mut forwarded_req = request(method.clone(), url, limits);
+        for item in forward_headers {
+            if let Some(val) = r.get(*item) {
+                forwarded_req = forwarded_req.insert_header((*item, val.clone()));
+            }
         }
-    }
-    let res = match forwarded_req.send().await {
+        forwarded_req
+    };
+    let res = match hedge::send(&url, forwarded_req).await {
         Ok(response) => response,
         Err(e) => return HttpResponse::InternalServerError().body(format!("{:?}", e)),
     };
@@ -209,24 +885,220 @@ async fn base_proxy(
     if status == StatusCode::OK {
         client_resp.insert_header((CACHE_CONTROL, "public,max-age=86400"));
     }
-    client_resp.streaming(res)
+    let shaper = Shaper::new(route, util::client_ip(&req), None);
+    client_resp.streaming(shaper.wrap(limit::guard(res, limits, url)))
 }
 
 #[inline]
-fn request(client: web::Data<Client>, url: String, timeout: u64) -> ClientRequest {
-    client
-        .get(url)
+fn request(method: Method, url: &str, limits: Limits) -> ClientRequest {
+    limits
+        .client()
+        .request(method, url)
         .no_decompress()
-        .timeout(Duration::from_secs(timeout))
+        .timeout(limits.request())
 }
 
 #[inline]
-fn find_item(info: parser::VideoInfo, prefer: &str) -> Option<String> {
+// 按优先级选择与ext相同容器的adaptive视频, 音频为lang音轨中码率最高的
+fn find_dubbed(
+    info: &parser::VideoInfo,
+    prefer: &str,
+    lang: &str,
+    ext: &str,
+) -> Option<(String, String)> {
+    // 匹配到默认音轨时使用progressive格式
+    if info.audio_lang(lang).is_none_or(|l| l.is_empty()) {
+        return None;
+    }
+    let mime = format!("audio/{}", ext);
+    let audio = audio::best(info, &mime, "", lang)?;
+    let video = prefer
+        .split(',')
+        .chain(PREFER_LIST.split(','))
+        .filter_map(|itag| info.stream(itag, ""))
+        .find(|s| s.index().is_some() && s.ext() == ext && s.mime().0.starts_with("video/"))?;
+    Some((video.itag.clone(), audio.itag.clone()))
+}
+
+fn find_item<'a>(
+    info: &'a parser::VideoInfo,
+    prefer: &str,
+    lang: &str,
+) -> Option<&'a parser::StreamItem> {
     for itag in prefer.split(',').chain(PREFER_LIST.split(',')) {
-        let Some(item) = info.streams.get(itag) else {
+        let Some(item) = info.stream(itag, lang) else {
             continue;
         };
-        return Some(item.url.clone());
+        return Some(item);
     }
     None
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn date(secs: u64) -> HttpDate {
+        HttpDate::from(UNIX_EPOCH + Duration::from_secs(secs))
+    }
+
+    #[test]
+    fn conditional() {
+        let etag = Some("\"18-1700000000000000\"");
+        let modified = Some(date(1_700_000_000));
+        let req = TestRequest::default()
+            .insert_header((IF_NONE_MATCH, "\"x\", W/\"18-1700000000000000\""))
+            .to_http_request();
+        assert!(not_modified(&req, etag, modified));
+        assert!(!not_modified(&req, None, modified));
+        let req = TestRequest::default()
+            .insert_header((IF_MODIFIED_SINCE, date(1_700_000_001).to_string()))
+            .to_http_request();
+        assert!(not_modified(&req, etag, modified));
+        let req = TestRequest::default()
+            .insert_header((IF_MODIFIED_SINCE, date(1_699_999_999).to_string()))
+            .to_http_request();
+        assert!(!not_modified(&req, etag, modified));
+    }
+
+    #[test]
+    fn if_range_strong() {
+        let etag = Some("\"18-1\"");
+        let modified = Some(date(1_700_000_000));
+        let with = |v: &str| {
+            TestRequest::default()
+                .insert_header((IF_RANGE, v.to_owned()))
+                .to_http_request()
+        };
+        assert!(if_range(
+            &TestRequest::default().to_http_request(),
+            etag,
+            modified
+        ));
+        assert!(if_range(&with("\"18-1\""), etag, modified));
+        assert!(!if_range(&with("W/\"18-1\""), etag, modified));
+        assert!(!if_range(&with("\"18-2\""), etag, modified));
+        assert!(if_range(
+            &with(&date(1_700_000_000).to_string()),
+            etag,
+            modified
+        ));
+        assert!(!if_range(
+            &with(&date(1_700_000_001).to_string()),
+            etag,
+            modified
+        ));
+        assert!(!if_range(
+            &with(&date(1_700_000_000).to_string()),
+            etag,
+            None
+        ));
+    }
+
+    #[test]
+    fn download_name() {
+        let req = TestRequest::with_uri("/v?download=1").to_http_request();
+        assert!(download(&req));
+        let req = TestRequest::with_uri("/v?download=0").to_http_request();
+        assert!(!download(&req));
+        assert!(!download(&TestRequest::default().to_http_request()));
+        let name = filename("t", "720p", "mp4");
+        assert!(name.ends_with("filename*=UTF-8''t%20%5B720p%5D.mp4"));
+        assert!(filename("t", "", "mp4").contains("filename=\"t.mp4\""));
+    }
+
+    #[test]
+    fn options_independent() {
+        let req = TestRequest::with_uri(
+            "/v?t=abc&download=1&parallel=4&start=inf&end=12.5&chapter=x&end=20",
+        )
+        .to_http_request();
+        let o = options(&req);
+        // 无效的值只忽略该参数, 重复的参数以最后一个为准
+        assert_eq!(o.t, None);
+        assert_eq!(o.start, None);
+        assert_eq!(o.end, Some(20.0));
+        assert_eq!(o.chapter, None);
+        assert_eq!(o.parallel, Some(4));
+        assert!(download(&req));
+        assert_eq!(parallel(&req), 4);
+        let req = TestRequest::with_uri("/v?parallel=-1")
+            .insert_header(("x-parallel", "3"))
+            .to_http_request();
+        assert_eq!(parallel(&req), 3);
+    }
+
+    #[actix_web::test]
+    async fn head_without_tags() {
+        let client = web::Data::new(Client::default());
+        let vid = "dQw4w9WgXcQ".to_owned();
+        // HEAD请求及非下载时不获取视频信息
+        let req = TestRequest::with_uri("/v?download=1")
+            .method(Method::HEAD)
+            .to_http_request();
+        assert!(tags(&client, &req, &vid).await.is_none());
+        let req = TestRequest::with_uri("/v").to_http_request();
+        assert!(tags(&client, &req, &vid).await.is_none());
+    }
+
+    #[test]
+    fn redirect_ip() {
+        let (_, item) = parser::stream_item(&serde_json::json!({
+            "itag": 18,
+            "mimeType": "video/mp4; codecs=\"avc1.42001E, mp4a.40.2\"",
+            "url": "https://r1.googlevideo.com/videoplayback?expire=1700000000&ip=1.2.3.4&itag=18",
+        }));
+        let req = |peer: &str, mode: &str| {
+            TestRequest::with_uri(&format!("/v?mode={}", mode))
+                .peer_addr(peer.parse().unwrap())
+                .to_http_request()
+        };
+        assert!(redirect(&req("1.2.3.4:80", ""), &item, "stream").is_none());
+        let res = redirect(&req("1.2.3.4:80", "redirect"), &item, "stream").unwrap();
+        assert_eq!(res.status(), StatusCode::FOUND);
+        assert_eq!(res.headers().get(LOCATION).unwrap(), item.url.as_str());
+        let res = redirect(&req("1.2.3.4:80", "json"), &item, "stream").unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = redirect(&req("5.6.7.8:80", "redirect"), &item, "stream").unwrap();
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        // IPv6地址在上游地址中是百分号编码的
+        let (_, item) = parser::stream_item(&serde_json::json!({
+            "itag": 18,
+            "mimeType": "video/mp4; codecs=\"avc1.42001E, mp4a.40.2\"",
+            "url": "https://r1.googlevideo.com/videoplayback?ip=2001%3Adb8%3A%3A1&itag=18",
+        }));
+        let res = redirect(&req("[2001:db8::1]:80", "redirect"), &item, "stream").unwrap();
+        assert_eq!(res.status(), StatusCode::FOUND);
+        let res = redirect(&req("[2001:db8::2]:80", "redirect"), &item, "stream").unwrap();
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[actix_web::test]
+    async fn head_keeps_length() {
+        use actix_web::{App, HttpServer};
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = format!("http://{}/", listener.local_addr().unwrap());
+        let server = HttpServer::new(|| {
+            App::new().default_service(web::to(|| async {
+                let mut client_resp = HttpResponse::Ok();
+                client_resp.no_chunking(123);
+                head_only(client_resp)
+            }))
+        })
+        .workers(1)
+        .listen(listener)
+        .unwrap()
+        .run();
+        actix_web::rt::spawn(server);
+        let mut res = Client::default().head(&addr).send().await.unwrap();
+        let len = res
+            .headers()
+            .get("content-length")
+            .unwrap()
+            .to_str()
+            .unwrap();
+        assert_eq!(len, "123");
+        assert!(res.body().await.unwrap().is_empty());
+    }
+}
@@ -9,27 +9,45 @@ use tokio::task;
 
 use crate::{parser, request, util};
 
-use super::ts;
+use super::{ts, vod};
 
+// 没有hlsManifestUrl时返回None, 其他错误照常返回
 async fn get_hls_master(
     client: &web::Data<Client>,
     vid: &String,
-) -> Result<Arc<Bytes>, Box<dyn error::Error>> {
-    let url = parser::parse_url(client, vid, "hlsManifestUrl", 3600).await?;
+) -> Result<Option<Arc<Bytes>>, Box<dyn error::Error>> {
+    let url = match parser::parse_url(client, vid, "hlsManifestUrl", 3600).await {
+        Ok(url) => url,
+        Err(e)
+            if e.downcast_ref::<io::Error>()
+                .is_some_and(|e| e.kind() == io::ErrorKind::NotFound) =>
+        {
+            return Ok(None);
+        }
+        Err(e) => return Err(e),
+    };
     let data = request::req_get_cache(client, &url, 600, 2 << 20).await?;
-    Ok(data)
+    Ok(Some(data))
 }
 
 pub async fn playlist_master(
     client: &web::Data<Client>,
     vid: &String,
 ) -> Result<String, Box<dyn error::Error>> {
-    let data = get_hls_master(client, vid).await?;
+    // 普通视频经常没有hlsManifestUrl, 此时根据adaptive格式自行生成
+    let Some(data) = get_hls_master(client, vid).await? else {
+        return vod::master(client, vid).await;
+    };
     let content = std::str::from_utf8(&data).unwrap_or_default();
     let mut uid: String = "".to_owned();
     let lines = content.lines().map(move |f| {
         if f.starts_with("#") {
             uid = util::hash(f);
+            // 多音轨的EXT-X-MEDIA也需要代理,以URI计算uid
+            if let Some(u) = media_uri(f) {
+                let proxied = format!("/video/{}/{}.m3u8", vid, util::hash(u));
+                return f.replace(u, &proxied) + "\r\n";
+            }
             return f.to_owned() + "\r\n";
         }
         "/video/".to_owned() + vid + "/" + &uid + ".m3u8\r\n"
@@ -43,18 +61,25 @@ pub async fn playlist_index(
     vid: &String,
     list: &String,
 ) -> Result<String, Box<dyn error::Error>> {
-    let data = get_hls_master(client, vid).await?;
+    let Some(data) = get_hls_master(client, vid).await? else {
+        return vod::index(client, vid, list).await;
+    };
     let content = std::str::from_utf8(&data).unwrap_or_default();
     let mut found = false;
-    let item = content.lines().find(move |f| {
+    let item = content.lines().find_map(move |f| {
+        if let Some(u) = media_uri(f)
+            && &util::hash(u) == list
+        {
+            return Some(u);
+        }
         if f.starts_with("#") {
             let uid = util::hash(f);
             if &uid == list {
                 found = true
             }
-            return false;
+            return None;
         }
-        found
+        found.then_some(f)
     });
     let Some(u) = item else {
         return Err(Box::new(io::Error::new(
@@ -76,6 +101,15 @@ pub async fn playlist_index(
     Ok(text)
 }
 
+fn media_uri(line: &str) -> Option<&str> {
+    if !line.starts_with("#EXT-X-MEDIA:") {
+        return None;
+    }
+    let (_, rest) = line.split_once("URI=\"")?;
+    let (uri, _) = rest.split_once('"')?;
+    Some(uri)
+}
+
 pub async fn playlist_ts(vid: &String, ts: &String) -> Result<Arc<Bytes>, Box<dyn error::Error>> {
     let res = ts::get_task(ts).await;
     res.ok_or_else(|| -> Box<dyn error::Error> {
@@ -0,0 +1,364 @@
+use actix_web::web;
+use awc::Client;
+use std::collections::BTreeMap;
+use std::error;
+use std::fmt::Write;
+use std::io;
+
+use crate::media::index::{self, Segment};
+use crate::media::{chapters, fragment};
+use crate::parser::{self, Chapter, StreamItem, VideoInfo};
+use crate::util;
+
+// 没有hlsManifestUrl时, 根据adaptive格式的sidx生成基于byterange的HLS
+// HLS只支持fMP4, 所以webm格式不在此列
+fn adaptive_mp4(info: &VideoInfo) -> impl Iterator<Item = &StreamItem> {
+    info.streams
+        .values()
+        .filter(|s| s.init().is_some() && s.index().is_some() && s.mime().0.ends_with("/mp4"))
+}
+
+fn not_found(vid: &str, what: &str) -> Box<dyn error::Error> {
+    Box::new(io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("{} {}", vid, what),
+    ))
+}
+
+pub async fn master(
+    client: &web::Data<Client>,
+    vid: &String,
+) -> Result<String, Box<dyn error::Error>> {
+    let info = parser::parse(client, vid).await?;
+    master_playlist(vid, &info)
+}
+
+fn master_playlist(vid: &str, info: &VideoInfo) -> Result<String, Box<dyn error::Error>> {
+    // 每种语言取码率最高的音轨
+    let mut audios: BTreeMap<&str, &StreamItem> = BTreeMap::new();
+    let mut videos: Vec<&StreamItem> = Vec::new();
+    for item in adaptive_mp4(info) {
+        if item.mime().0.starts_with("video/") {
+            videos.push(item);
+            continue;
+        }
+        let lang = item.audio_track.as_ref().map_or("", |t| t.lang.as_str());
+        match audios.get(lang) {
+            Some(cur) if cur.bitrate >= item.bitrate => {}
+            _ => {
+                audios.insert(lang, item);
+            }
+        }
+    }
+    if videos.is_empty() && audios.is_empty() {
+        return Err(not_found(vid, "adaptiveFormats"));
+    }
+    videos.sort_by_key(|s| s.bitrate.unwrap_or_default());
+
+    let mut out = String::new();
+    out.push_str("#EXTM3U\r\n#EXT-X-VERSION:7\r\n#EXT-X-INDEPENDENT-SEGMENTS\r\n");
+    // 各语言的音轨属于同一个group, 码率及编码以其中码率最高的为准
+    let (audio_bitrate, audio_codec) = audios
+        .values()
+        .max_by_key(|s| s.bitrate.unwrap_or_default())
+        .map_or((0, ""), |s| (s.bitrate.unwrap_or_default(), s.mime().1));
+    for (lang, item) in &audios {
+        let default = item.audio_track.as_ref().is_none_or(|t| t.default);
+        let name = match &item.audio_track {
+            Some(t) if !t.name.is_empty() => t.name.as_str(),
+            _ if !lang.is_empty() => lang,
+            _ => "default",
+        };
+        write!(
+            out,
+            "#EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID=\"audio\",NAME=\"{}\",DEFAULT={},AUTOSELECT=YES",
+            name.replace('"', "'"),
+            if default { "YES" } else { "NO" }
+        )?;
+        if !lang.is_empty() {
+            write!(out, ",LANGUAGE=\"{}\"", lang)?;
+        }
+        write!(
+            out,
+            ",URI=\"/video/{}/{}.m3u8\"\r\n",
+            vid,
+            util::hash(&item.proxy_path(vid))
+        )?;
+    }
+    for item in videos {
+        let mut codecs = item.mime().1.to_owned();
+        if !audio_codec.is_empty() {
+            codecs = codecs + "," + audio_codec;
+        }
+        write!(
+            out,
+            "#EXT-X-STREAM-INF:BANDWIDTH={},CODECS=\"{}\"",
+            item.bitrate.unwrap_or_default() + audio_bitrate,
+            codecs
+        )?;
+        if let (Some(w), Some(h)) = (item.width, item.height) {
+            write!(out, ",RESOLUTION={}x{}", w, h)?;
+        }
+        if let Some(fps) = item.fps {
+            write!(out, ",FRAME-RATE={}", fps)?;
+        }
+        if !audios.is_empty() {
+            out.push_str(",AUDIO=\"audio\"");
+        }
+        write!(
+            out,
+            "\r\n/video/{}/{}.m3u8\r\n",
+            vid,
+            util::hash(&item.proxy_path(vid))
+        )?;
+    }
+    Ok(out)
+}
+
+pub async fn index(
+    client: &web::Data<Client>,
+    vid: &String,
+    list: &String,
+) -> Result<String, Box<dyn error::Error>> {
+    let info = parser::parse(client, vid).await?;
+    let item = adaptive_mp4(&info)
+        .find(|s| &util::hash(&s.proxy_path(vid)) == list)
+        .ok_or_else(|| not_found(vid, list))?;
+    let segments = index::segments(client, item).await?;
+    let init = item.init().unwrap_or_default();
+    let chapters = parser::load_chapters(client, &info).await;
+    Ok(byterange_playlist(
+        &item.proxy_path(vid),
+        init,
+        &segments,
+        &chapters,
+    )?)
+}
+
+fn byterange_playlist(
+    path: &str,
+    (init_start, init_end): (u64, u64),
+    segments: &[Segment],
+    chapters: &[Chapter],
+) -> Result<String, std::fmt::Error> {
+    let target = segments
+        .iter()
+        .map(|s| s.duration.ceil() as u64)
+        .max()
+        .unwrap_or_default();
+
+    let mut out = String::new();
+    write!(
+        out,
+        "#EXTM3U\r\n#EXT-X-VERSION:7\r\n#EXT-X-TARGETDURATION:{}\r\n#EXT-X-MEDIA-SEQUENCE:0\r\n#EXT-X-PLAYLIST-TYPE:VOD\r\n#EXT-X-INDEPENDENT-SEGMENTS\r\n",
+        target
+    )?;
+    write!(
+        out,
+        "#EXT-X-MAP:URI=\"{}\",BYTERANGE=\"{}@{}\"\r\n",
+        path,
+        init_end - init_start + 1,
+        init_start
+    )?;
+    dateranges(&mut out, chapters)?;
+    for s in segments {
+        write!(
+            out,
+            "#EXTINF:{:.3},\r\n#EXT-X-BYTERANGE:{}@{}\r\n{}\r\n",
+            s.duration, s.size, s.offset, path
+        )?;
+    }
+    out.push_str("#EXT-X-ENDLIST\r\n");
+    Ok(out)
+}
+
+// progressive格式转为fMP4分片的HLS, 分片及init由fragment实时生成
+pub async fn progressive(
+    client: &web::Data<Client>,
+    vid: &String,
+    itag: &str,
+) -> Result<String, Box<dyn error::Error>> {
+    let info = parser::parse(client, vid).await?;
+    let item = info.stream(itag, "").ok_or_else(|| not_found(vid, itag))?;
+    let durations = fragment::durations(client, item).await?;
+    let chapters = parser::load_chapters(client, &info).await;
+    Ok(fragment_playlist(&durations, &chapters)?)
+}
+
+fn fragment_playlist(durations: &[f64], chapters: &[Chapter]) -> Result<String, std::fmt::Error> {
+    let target = durations
+        .iter()
+        .map(|d| d.ceil() as u64)
+        .max()
+        .unwrap_or_default();
+
+    let mut out = String::new();
+    write!(
+        out,
+        "#EXTM3U\r\n#EXT-X-VERSION:7\r\n#EXT-X-TARGETDURATION:{}\r\n#EXT-X-MEDIA-SEQUENCE:0\r\n#EXT-X-PLAYLIST-TYPE:VOD\r\n#EXT-X-INDEPENDENT-SEGMENTS\r\n",
+        target
+    )?;
+    out.push_str("#EXT-X-MAP:URI=\"init.mp4\"\r\n");
+    dateranges(&mut out, chapters)?;
+    for (i, d) in durations.iter().enumerate() {
+        write!(out, "#EXTINF:{:.3},\r\n{}.m4s\r\n", d, i)?;
+    }
+    out.push_str("#EXT-X-ENDLIST\r\n");
+    Ok(out)
+}
+
+// 章节以EXT-X-DATERANGE表示, 需要EXT-X-PROGRAM-DATE-TIME确定时间, 以1970-01-01为开始
+fn dateranges(out: &mut String, chapters: &[Chapter]) -> std::fmt::Result {
+    if chapters.is_empty() {
+        return Ok(());
+    }
+    write!(out, "#EXT-X-PROGRAM-DATE-TIME:{}\r\n", chapters::date(0.0))?;
+    for (i, c) in chapters.iter().enumerate() {
+        write!(
+            out,
+            "#EXT-X-DATERANGE:ID=\"chapter-{}\",CLASS=\"chapter\",START-DATE=\"{}\",DURATION={:.3},X-TITLE=\"{}\"\r\n",
+            i + 1,
+            chapters::date(c.start),
+            c.end - c.start,
+            // quoted-string中不能有双引号及换行
+            c.title.replace(['"', '\r', '\n'], "'")
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::tests::{adaptive, info};
+
+    fn chapters() -> Vec<Chapter> {
+        vec![
+            Chapter {
+                title: "Intro \"1\"".to_owned(),
+                start: 0.0,
+                end: 90.0,
+            },
+            Chapter {
+                title: "End".to_owned(),
+                start: 90.0,
+                end: 3000000.0,
+            },
+        ]
+    }
+
+    #[test]
+    fn master_codecs() {
+        let mut hd = adaptive(137, "video/mp4; codecs=\"avc1.640028\"", 4_000_000, None);
+        hd["width"] = 1920.into();
+        hd["height"] = 1080.into();
+        hd["fps"] = 30.into();
+        let info = info(&[
+            hd,
+            adaptive(136, "video/mp4; codecs=\"avc1.4d401f\"", 2_000_000, None),
+            adaptive(248, "video/webm; codecs=\"vp9\"", 3_000_000, None),
+            adaptive(
+                140,
+                "audio/mp4; codecs=\"mp4a.40.2\"",
+                128_000,
+                Some(("en.4", true)),
+            ),
+            adaptive(
+                139,
+                "audio/mp4; codecs=\"mp4a.40.5\"",
+                48_000,
+                Some(("es-US.3", false)),
+            ),
+            adaptive(
+                139,
+                "audio/mp4; codecs=\"mp4a.40.5\"",
+                48_000,
+                Some(("en.4", true)),
+            ),
+        ]);
+        let m3u8 = master_playlist("x", &info).unwrap();
+        let lines: Vec<&str> = m3u8.lines().collect();
+        let media: Vec<&&str> = lines
+            .iter()
+            .filter(|l| l.starts_with("#EXT-X-MEDIA:"))
+            .collect();
+        assert_eq!(media.len(), 2);
+        assert!(media[0].contains("NAME=\"en.4\",DEFAULT=YES,AUTOSELECT=YES,LANGUAGE=\"en\""));
+        let uri = util::hash("/video/x/140.mp4");
+        assert!(media[0].ends_with(&format!("URI=\"/video/x/{}.m3u8\"", uri)));
+        assert!(media[1].contains("DEFAULT=NO") && media[1].contains("LANGUAGE=\"es-US\""));
+        // webm不输出, 按码率排序, 编码及码率取码率最高的音轨
+        let inf: Vec<&&str> = lines
+            .iter()
+            .filter(|l| l.starts_with("#EXT-X-STREAM-INF:"))
+            .collect();
+        assert_eq!(
+            inf,
+            vec![
+                &"#EXT-X-STREAM-INF:BANDWIDTH=2128000,CODECS=\"avc1.4d401f,mp4a.40.2\",AUDIO=\"audio\"",
+                &"#EXT-X-STREAM-INF:BANDWIDTH=4128000,CODECS=\"avc1.640028,mp4a.40.2\",RESOLUTION=1920x1080,FRAME-RATE=30,AUDIO=\"audio\"",
+            ]
+        );
+        let empty = crate::parser::tests::info(&[]);
+        assert!(master_playlist("x", &empty).is_err());
+    }
+
+    #[test]
+    fn byterange_segments() {
+        let segments = [
+            Segment {
+                start: 0.0,
+                duration: 5.005,
+                offset: 1000,
+                size: 5000,
+            },
+            Segment {
+                start: 5.005,
+                duration: 4.2,
+                offset: 6000,
+                size: 4000,
+            },
+        ];
+        let m3u8 = byterange_playlist("/video/x/137.mp4", (0, 740), &segments, &[]).unwrap();
+        assert_eq!(
+            m3u8,
+            "#EXTM3U\r\n#EXT-X-VERSION:7\r\n#EXT-X-TARGETDURATION:6\r\n#EXT-X-MEDIA-SEQUENCE:0\r\n\
+             #EXT-X-PLAYLIST-TYPE:VOD\r\n#EXT-X-INDEPENDENT-SEGMENTS\r\n\
+             #EXT-X-MAP:URI=\"/video/x/137.mp4\",BYTERANGE=\"741@0\"\r\n\
+             #EXTINF:5.005,\r\n#EXT-X-BYTERANGE:5000@1000\r\n/video/x/137.mp4\r\n\
+             #EXTINF:4.200,\r\n#EXT-X-BYTERANGE:4000@6000\r\n/video/x/137.mp4\r\n\
+             #EXT-X-ENDLIST\r\n"
+        );
+    }
+
+    #[test]
+    fn fragments_with_chapters() {
+        let m3u8 = fragment_playlist(&[6.006, 2.5], &chapters()).unwrap();
+        let lines: Vec<&str> = m3u8.split("\r\n").collect();
+        assert!(lines.contains(&"#EXT-X-TARGETDURATION:7"));
+        assert!(lines.contains(&"#EXT-X-MAP:URI=\"init.mp4\""));
+        assert!(lines.contains(&"#EXT-X-PROGRAM-DATE-TIME:1970-01-01T00:00:00.000Z"));
+        // 标题中的双引号被替换
+        assert!(lines.contains(
+            &"#EXT-X-DATERANGE:ID=\"chapter-1\",CLASS=\"chapter\",START-DATE=\"1970-01-01T00:00:00.000Z\",DURATION=90.000,X-TITLE=\"Intro '1'\""
+        ));
+        assert!(lines.contains(
+            &"#EXT-X-DATERANGE:ID=\"chapter-2\",CLASS=\"chapter\",START-DATE=\"1970-01-01T00:01:30.000Z\",DURATION=2999910.000,X-TITLE=\"End\""
+        ));
+        let tail: Vec<&str> = lines.iter().rev().take(6).rev().copied().collect();
+        assert_eq!(
+            tail,
+            vec![
+                "#EXTINF:6.006,",
+                "0.m4s",
+                "#EXTINF:2.500,",
+                "1.m4s",
+                "#EXT-X-ENDLIST",
+                ""
+            ]
+        );
+        // 没有章节时不输出PROGRAM-DATE-TIME
+        let m3u8 = fragment_playlist(&[1.0], &[]).unwrap();
+        assert!(!m3u8.contains("PROGRAM-DATE-TIME"));
+    }
+}
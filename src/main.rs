@@ -5,12 +5,43 @@ mod parser;
 mod request;
 mod route;
 mod util;
+mod upstream {
+    pub mod ahead;
+    pub mod chunk;
+    pub mod fanout;
+    pub mod hedge;
+    pub mod limit;
+    pub mod range;
+    pub mod shape;
+}
 mod cache {
+    pub mod disk;
     pub mod map;
 }
+mod dash {
+    pub mod mpd;
+}
 mod hls {
     pub mod playlist;
     pub mod ts;
+    pub mod vod;
+}
+mod media {
+    pub mod audio;
+    pub mod chapters;
+    pub mod clip;
+    pub mod fragment;
+    pub mod index;
+    pub mod m4a;
+    pub mod moof;
+    pub mod moov;
+    pub mod mp4;
+    pub mod mux;
+    pub mod ogg;
+    pub mod seek;
+    pub mod subtitle;
+    pub mod tags;
+    pub mod webm;
 }
 
 use actix_files as fs;
@@ -24,18 +55,35 @@ async fn main() -> std::io::Result<()> {
     let (addr, mount_path, serve_from) = opt();
     HttpServer::new(move || {
         App::new()
-            .app_data(Data::new(awc::Client::new()))
+            .app_data(Data::new(
+                awc::Client::builder()
+                    .connector(awc::Connector::new().timeout(upstream::limit::CONNECT))
+                    .finish(),
+            ))
             .wrap(middleware::DefaultHeaders::new().add((ACCESS_CONTROL_ALLOW_ORIGIN, "*")))
             .service(route::hello)
             .service(route::echo)
+            .service(route::rate)
+            .service(route::rate_update)
+            .service(route::hosts)
             .service(route::vinfo)
+            .service(route::chapters_vtt)
+            .service(route::subs)
             .service(route::image)
             .service(route::stream)
+            .service(route::streammux)
             .service(route::streamts)
+            .service(route::segment_index)
+            .service(route::clip)
+            .service(route::fmp4_list)
+            .service(route::fmp4_init)
+            .service(route::fmp4_segment)
             .service(route::streamauto)
+            .service(route::streamaudio)
             .service(route::hls)
             .service(route::hls_list)
             .service(route::hls_ts)
+            .service(route::dash)
             .service(
                 fs::Files::new(&mount_path, serve_from.clone())
                     .show_files_listing()
@@ -45,6 +93,7 @@ async fn main() -> std::io::Result<()> {
                     .use_etag(true),
             )
             .route("/{filename:.*\\.\\w{1,4}}", web::get().to(route::serve))
+            .route("/{filename:.*\\.\\w{1,4}}", web::head().to(route::serve))
     })
     .bind(addr)?
     .run()
@@ -0,0 +1,75 @@
+use actix_web::web::{self, Bytes};
+use awc::Client;
+use futures_util::stream::{LocalBoxStream, StreamExt};
+use std::error;
+use std::io;
+
+use super::tags::Tags;
+use super::{m4a, ogg};
+use crate::parser::{self, StreamItem, VideoInfo};
+use crate::util;
+
+pub struct Extracted {
+    pub content_type: &'static str,
+    // Ogg无法预先得到长度
+    pub len: Option<u64>,
+    pub etag: Option<String>,
+    pub title: String,
+    pub label: String,
+    pub body: LocalBoxStream<'static, Result<Bytes, io::Error>>,
+}
+
+// 取指定音轨中码率最高的audio-only格式, 转为普通的m4a或Ogg Opus
+pub async fn extract(
+    client: &web::Data<Client>,
+    vid: &String,
+    ext: &str,
+    lang: &str,
+    tags: Option<&Tags>,
+) -> Result<Extracted, Box<dyn error::Error>> {
+    let info = parser::parse(client, vid).await?;
+    let (mime, codec) = match ext {
+        "opus" => ("audio/webm", "opus"),
+        _ => ("audio/mp4", "mp4a"),
+    };
+    let Some(item) = best(&info, mime, codec, lang).or_else(|| best(&info, mime, codec, "")) else {
+        return Err(Box::new(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no {} audio", codec),
+        )));
+    };
+    let (content_type, len, body) = match ext {
+        "opus" => (
+            "audio/ogg",
+            None,
+            ogg::remux(client, vid, item, tags).await?.boxed_local(),
+        ),
+        _ => {
+            let (len, body) = m4a::remux(client, vid, item, tags).await?;
+            ("audio/mp4", Some(len), body.boxed_local())
+        }
+    };
+    Ok(Extracted {
+        content_type,
+        len,
+        etag: util::query_param(&item.url, "lmt")
+            .map(|lmt| format!("\"{}-{}.{}\"", item.itag, lmt, ext)),
+        title: info.title.clone(),
+        label: item.label(),
+        body,
+    })
+}
+
+pub fn best<'a>(
+    info: &'a VideoInfo,
+    mime: &str,
+    codec: &str,
+    lang: &str,
+) -> Option<&'a StreamItem> {
+    let lang = info.audio_lang(lang)?;
+    info.streams
+        .values()
+        .filter(|s| s.index().is_some() && s.lang() == lang)
+        .filter(|s| s.mime().0 == mime && s.mime().1.starts_with(codec))
+        .max_by_key(|s| s.bitrate.unwrap_or_default())
+}
@@ -0,0 +1,80 @@
+use super::subtitle::{self, Cue};
+use crate::parser::Chapter;
+
+// 以1970-01-01为第0秒的ISO 8601时间, 用于HLS的EXT-X-DATERANGE
+pub fn date(seconds: f64) -> String {
+    let seconds = seconds.max(0.0);
+    let days = (seconds / 86400.0) as u64;
+    let (y, m, d) = civil(days);
+    format!(
+        "{:04}-{:02}-{:02}T{}Z",
+        y,
+        m,
+        d,
+        subtitle::clock(seconds - days as f64 * 86400.0, '.')
+    )
+}
+
+// 1970-01-01之后的第days天的年月日
+fn civil(days: u64) -> (u64, u64, u64) {
+    // 以0000-03-01为起点, 每400年146097天
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z % 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    let y = yoe + era * 400 + u64::from(m <= 2);
+    (y, m, d)
+}
+
+// WebVTT章节, cue的编号从1开始, 与query参数chapter一致
+pub fn vtt(chapters: &[Chapter]) -> String {
+    let cues: Vec<Cue> = chapters
+        .iter()
+        .map(|c| Cue {
+            start: c.start,
+            end: c.end,
+            text: c.title.clone(),
+        })
+        .collect();
+    subtitle::vtt(&cues)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dates() {
+        assert_eq!(date(0.0), "1970-01-01T00:00:00.000Z");
+        assert_eq!(date(-5.0), "1970-01-01T00:00:00.000Z");
+        // 超过31天
+        assert_eq!(date(40.0 * 86400.0 + 3661.5), "1970-02-10T01:01:01.500Z");
+        assert_eq!(date(400.0 * 86400.0), "1971-02-05T00:00:00.000Z");
+        assert_eq!(date(1_709_164_800.0), "2024-02-29T00:00:00.000Z");
+    }
+
+    #[test]
+    fn chapter_vtt() {
+        let chapters = [
+            Chapter {
+                title: "Intro & <b>".to_owned(),
+                start: 0.0,
+                end: 90.5,
+            },
+            Chapter {
+                title: "End".to_owned(),
+                start: 90.5,
+                end: 3725.0,
+            },
+        ];
+        assert_eq!(
+            vtt(&chapters),
+            "WEBVTT\n\n1\n00:00:00.000 --> 00:01:30.500\nIntro &amp; &lt;b&gt;\n\n\
+             2\n00:01:30.500 --> 01:02:05.000\nEnd\n\n"
+        );
+    }
+}
@@ -0,0 +1,482 @@
+use actix_web::web::{self, Bytes};
+use awc::Client;
+use std::error;
+use std::io;
+
+use super::moov::{self, Moov, Sample, Track};
+use super::mp4::{self, atom, u32_at};
+use super::tags::Tags;
+use crate::parser::StreamItem;
+
+pub struct Clip {
+    // 实际开始的时间, 秒, 对齐到关键帧
+    pub start: f64,
+    // ftyp + moov + mdat header
+    pub head: Bytes,
+    // mdat的数据, 原文件中的字节范围
+    pub range: (u64, u64),
+}
+
+// 单个chunk的最大字节数
+const MAX_CHUNK: u64 = 1 << 20;
+
+fn invalid(msg: String) -> Box<dyn error::Error> {
+    Box::new(io::Error::new(io::ErrorKind::InvalidData, msg))
+}
+
+// 截取progressive mp4的[start, end)秒, 生成moov在前的独立文件
+// 新的mdat为原文件中包含所选sample的连续区间, 相邻的sample合并为chunk, 偏移指向区间中的位置
+pub async fn clip(
+    client: &web::Data<Client>,
+    item: &StreamItem,
+    start: f64,
+    end: Option<f64>,
+    tags: Option<&Tags>,
+) -> Result<Clip, Box<dyn error::Error>> {
+    if item.ext() != "mp4" || item.index().is_some() {
+        return Err(invalid(format!("{} is not progressive mp4", item.itag)));
+    }
+    let (_, data) = moov::fetch(client, item).await?;
+    let moov = moov::parse(&data).ok_or_else(|| invalid(format!("{} moov", item.itag)))?;
+    let udta = tags.map(|t| t.udta()).unwrap_or_default();
+    build(&moov, start, end, &udta).ok_or_else(|| invalid(format!("{} empty clip", item.itag)))
+}
+
+pub struct Selected<'a, 'b> {
+    pub track: &'b Track<'a>,
+    pub samples: &'b [Sample],
+    // 以track的timescale为单位
+    pub duration: u64,
+}
+
+fn build(moov: &Moov, start: f64, end: Option<f64>, udta: &[u8]) -> Option<Clip> {
+    let main = moov.main()?;
+    let key = main.keyframe(main.units(start))?;
+    let actual = main.seconds(main.samples[key].time);
+    let selected: Vec<Selected> = moov
+        .tracks
+        .iter()
+        .map(|t| {
+            let a = t.samples.partition_point(|s| s.time < t.units(actual));
+            let z = match end {
+                Some(end) => t.samples.partition_point(|s| s.time < t.units(end)),
+                None => t.samples.len(),
+            };
+            let samples = t.samples.get(a..z).unwrap_or_default();
+            Selected {
+                track: t,
+                samples,
+                duration: samples.iter().map(|s| s.duration as u64).sum(),
+            }
+        })
+        .filter(|s| !s.samples.is_empty())
+        .collect();
+    let from = selected.iter().map(|s| s.samples[0].offset).min()?;
+    let to = selected
+        .iter()
+        .flat_map(|s| s.samples.iter().map(|v| v.offset + v.size as u64))
+        .max()?;
+    let head = head(
+        moov,
+        &selected,
+        b"isom\0\0\x02\0isomiso2avc1mp41",
+        udta,
+        from,
+        to - from,
+    );
+    Some(Clip {
+        start: actual,
+        head: Bytes::from(head),
+        range: (from, to - 1),
+    })
+}
+
+// 生成ftyp + moov + mdat header, mdat的数据为[from, from+span), sample的偏移相应平移
+// udta不为空时替换原有的udta
+pub fn head(
+    moov: &Moov,
+    selected: &[Selected],
+    brands: &[u8],
+    udta: &[u8],
+    from: u64,
+    span: u64,
+) -> Vec<u8> {
+    let large = span > u32::MAX as u64 / 2;
+    let ftyp = atom(b"ftyp", brands);
+    let mdat_header = match span + 8 > u32::MAX as u64 {
+        true => 16,
+        false => 8,
+    };
+    // moov的长度与偏移的值无关, 先生成一次得到长度
+    let len = moov_box(moov, selected, udta, 0, large).len();
+    let data_start = (ftyp.len() + len + mdat_header) as u64;
+    let moov = moov_box(moov, selected, udta, data_start as i64 - from as i64, large);
+    let mut head = ftyp;
+    head.extend_from_slice(&moov);
+    if mdat_header == 16 {
+        head.extend_from_slice(&1u32.to_be_bytes());
+        head.extend_from_slice(b"mdat");
+        head.extend_from_slice(&(span + 16).to_be_bytes());
+    } else {
+        head.extend_from_slice(&(span as u32 + 8).to_be_bytes());
+        head.extend_from_slice(b"mdat");
+    }
+    head
+}
+
+fn full(kind: &[u8; 4], version: u8, payload: &[u8]) -> Vec<u8> {
+    let mut data = vec![version, 0, 0, 0];
+    data.extend_from_slice(payload);
+    atom(kind, &data)
+}
+
+// 按version修改full box中的duration字段, v0为32位, v1为64位
+fn set_duration(raw: &[u8], header: usize, v0: usize, v1: usize, duration: u64) -> Vec<u8> {
+    let mut out = raw.to_vec();
+    let data = &mut out[header..];
+    if data.first() == Some(&1) {
+        if let Some(d) = data.get_mut(v1..v1 + 8) {
+            d.copy_from_slice(&duration.to_be_bytes());
+        }
+    } else if let Some(d) = data.get_mut(v0..v0 + 4) {
+        d.copy_from_slice(&(duration.min(u32::MAX as u64) as u32).to_be_bytes());
+    }
+    out
+}
+
+fn movie_scale(moov: &Moov) -> u32 {
+    let d = &moov.mvhd[8..];
+    match d.first() {
+        Some(1) => u32_at(d, 20),
+        _ => u32_at(d, 12),
+    }
+    .unwrap_or(1000)
+    .max(1)
+}
+
+// 重写后的moov, 只保留selected中的track, 去掉mvex
+pub fn moov_box(
+    moov: &Moov,
+    selected: &[Selected],
+    udta: &[u8],
+    shift: i64,
+    large: bool,
+) -> Vec<u8> {
+    let scale = movie_scale(moov) as u64;
+    let movie = |s: &Selected| s.duration * scale / s.track.timescale.max(1) as u64;
+    let duration = selected.iter().map(movie).max().unwrap_or_default();
+    let mut out = Vec::new();
+    for a in mp4::atoms(moov.data) {
+        match &a.kind {
+            b"mvhd" => out.extend(set_duration(a.raw, a.header, 16, 24, duration)),
+            b"trak" => {
+                if let Some(s) = selected
+                    .iter()
+                    .find(|s| std::ptr::eq(s.track.raw.as_ptr(), a.raw.as_ptr()))
+                {
+                    out.extend(trak_box(s, movie(s), shift, large));
+                }
+            }
+            b"mvex" => {}
+            b"udta" if !udta.is_empty() => {}
+            _ => out.extend_from_slice(a.raw),
+        }
+    }
+    out.extend_from_slice(udta);
+    atom(b"moov", &out)
+}
+
+fn trak_box(s: &Selected, movie: u64, shift: i64, large: bool) -> Vec<u8> {
+    let trak = mp4::find(s.track.raw, b"trak")
+        .map(|a| a.data)
+        .unwrap_or_default();
+    let mut out = Vec::new();
+    for a in mp4::atoms(trak) {
+        match &a.kind {
+            b"tkhd" => out.extend(set_duration(a.raw, a.header, 20, 28, movie)),
+            b"edts" => out.extend(edts_box(a.data, movie)),
+            b"mdia" => {
+                let mut mdia = Vec::new();
+                for b in mp4::atoms(a.data) {
+                    match &b.kind {
+                        b"mdhd" => mdia.extend(set_duration(b.raw, b.header, 16, 24, s.duration)),
+                        b"minf" => {
+                            let mut minf = Vec::new();
+                            for c in mp4::atoms(b.data) {
+                                match &c.kind {
+                                    b"stbl" => {
+                                        minf.extend(stbl_box(c.data, s.samples, shift, large))
+                                    }
+                                    _ => minf.extend_from_slice(c.raw),
+                                }
+                            }
+                            mdia.extend(atom(b"minf", &minf));
+                        }
+                        _ => mdia.extend_from_slice(b.raw),
+                    }
+                }
+                out.extend(atom(b"mdia", &mdia));
+            }
+            _ => out.extend_from_slice(a.raw),
+        }
+    }
+    atom(b"trak", &out)
+}
+
+// 保留原有的media_time(编码延迟), 去掉空的edit
+fn edts_box(edts: &[u8], movie: u64) -> Vec<u8> {
+    let Some(elst) = mp4::find(edts, b"elst") else {
+        return vec![];
+    };
+    let d = elst.data;
+    let version = d.first().copied().unwrap_or_default();
+    let count = u32_at(d, 4).unwrap_or_default() as usize;
+    let media_time = (0..count).find_map(|i| {
+        let t = match version {
+            1 => mp4::u64_at(d, 8 + i * 20 + 8)? as i64,
+            _ => u32_at(d, 8 + i * 12 + 4)? as i32 as i64,
+        };
+        (t >= 0).then_some(t)
+    });
+    let Some(media_time) = media_time else {
+        return vec![];
+    };
+    let mut payload = 1u32.to_be_bytes().to_vec();
+    payload.extend_from_slice(&movie.to_be_bytes());
+    payload.extend_from_slice(&media_time.to_be_bytes());
+    payload.extend_from_slice(&0x0001_0000u32.to_be_bytes());
+    atom(b"edts", &full(b"elst", 1, &payload))
+}
+
+// (数量, 值)的游程编码
+fn runs(values: impl Iterator<Item = u32>) -> Vec<(u32, u32)> {
+    let mut res: Vec<(u32, u32)> = Vec::new();
+    for v in values {
+        match res.last_mut() {
+            Some((n, last)) if *last == v => *n += 1,
+            _ => res.push((1, v)),
+        }
+    }
+    res
+}
+
+fn table(entries: &[(u32, u32)]) -> Vec<u8> {
+    let mut out = (entries.len() as u32).to_be_bytes().to_vec();
+    for (a, b) in entries {
+        out.extend_from_slice(&a.to_be_bytes());
+        out.extend_from_slice(&b.to_be_bytes());
+    }
+    out
+}
+
+fn stbl_box(stbl: &[u8], samples: &[Sample], shift: i64, large: bool) -> Vec<u8> {
+    let mut out = Vec::new();
+    if let Some(stsd) = mp4::find(stbl, b"stsd") {
+        out.extend_from_slice(stsd.raw);
+    }
+    out.extend(full(
+        b"stts",
+        0,
+        &table(&runs(samples.iter().map(|s| s.duration))),
+    ));
+    if let Some(ctts) = mp4::find(stbl, b"ctts") {
+        let version = ctts.data.first().copied().unwrap_or_default();
+        out.extend(full(
+            b"ctts",
+            version,
+            &table(&runs(samples.iter().map(|s| s.cto))),
+        ));
+    }
+    // 没有sample时(fMP4的init)不输出stss, 空的stss表示没有关键帧
+    if mp4::find(stbl, b"stss").is_some() && !samples.is_empty() {
+        let sync: Vec<u32> = (1..)
+            .zip(samples)
+            .filter(|(_, s)| s.sync)
+            .map(|(i, _)| i)
+            .collect();
+        let mut payload = (sync.len() as u32).to_be_bytes().to_vec();
+        sync.iter()
+            .for_each(|i| payload.extend_from_slice(&i.to_be_bytes()));
+        out.extend(full(b"stss", 0, &payload));
+    }
+    let chunks = chunks(samples);
+    let mut stsc = Vec::new();
+    let mut first = 1;
+    for (n, per) in runs(chunks.iter().map(|c| c.1)) {
+        stsc.push((first, per));
+        first += n;
+    }
+    let mut payload = (stsc.len() as u32).to_be_bytes().to_vec();
+    for (first, per) in stsc {
+        for v in [first, per, 1] {
+            payload.extend_from_slice(&v.to_be_bytes());
+        }
+    }
+    out.extend(full(b"stsc", 0, &payload));
+    let mut stsz = 0u32.to_be_bytes().to_vec();
+    stsz.extend_from_slice(&(samples.len() as u32).to_be_bytes());
+    samples
+        .iter()
+        .for_each(|s| stsz.extend_from_slice(&s.size.to_be_bytes()));
+    out.extend(full(b"stsz", 0, &stsz));
+    let mut stco = (chunks.len() as u32).to_be_bytes().to_vec();
+    for (offset, _) in chunks {
+        let offset = (offset as i64 + shift) as u64;
+        match large {
+            true => stco.extend_from_slice(&offset.to_be_bytes()),
+            false => stco.extend_from_slice(&(offset as u32).to_be_bytes()),
+        }
+    }
+    let kind = match large {
+        true => b"co64",
+        false => b"stco",
+    };
+    out.extend(full(kind, 0, &stco));
+    atom(b"stbl", &out)
+}
+
+// 相邻的sample合并为一个chunk, 每个chunk不超过MAX_CHUNK, 返回(偏移, sample数量)
+fn chunks(samples: &[Sample]) -> Vec<(u64, u32)> {
+    let mut res: Vec<(u64, u32)> = Vec::new();
+    let mut end = 0;
+    for s in samples {
+        match res.last_mut() {
+            Some((offset, n)) if s.offset == end && end + s.size as u64 - *offset <= MAX_CHUNK => {
+                *n += 1
+            }
+            _ => res.push((s.offset, 1)),
+        }
+        end = s.offset + s.size as u64;
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::media::moov::tests::{AUDIO, VIDEO, moov, trak};
+
+    fn offsets(t: &Track) -> Vec<u64> {
+        t.samples.iter().map(|s| s.offset).collect()
+    }
+
+    #[test]
+    fn clip_range() {
+        let data = moov(&[trak(&VIDEO), trak(&AUDIO)]);
+        let m = moov::parse(&data).unwrap();
+        // 从0.35秒之前的关键帧(0.3秒)开始, 音频在此之前已结束
+        let clip = build(&m, 0.35, Some(0.55), &[]).unwrap();
+        assert_eq!(clip.start, 0.3);
+        assert_eq!(clip.range, (2030, 4059));
+        let head = &clip.head;
+        let l = head.len() as u64;
+        assert_eq!(u32_at(head, head.len() - 8), Some(2030 + 8));
+        assert_eq!(&head[head.len() - 4..], b"mdat");
+        let out = moov::parse(head).unwrap();
+        assert_eq!(out.tracks.len(), 1);
+        let v = &out.tracks[0];
+        assert_eq!(offsets(v), vec![l, l + 970, l + 1970]);
+        let times: Vec<u64> = v.samples.iter().map(|s| s.time).collect();
+        assert_eq!(times, vec![0, 100, 200]);
+        let sync: Vec<bool> = v.samples.iter().map(|s| s.sync).collect();
+        assert_eq!(sync, vec![true, false, false]);
+        assert!(v.samples.iter().all(|s| s.cto == 50));
+        // mvhd及mdhd的时长
+        assert_eq!(u32_at(out.mvhd, 24), Some(300));
+        let mdhd = mp4::find(v.raw, b"trak")
+            .and_then(|t| mp4::find(t.data, b"mdia"))
+            .and_then(|m| mp4::find(m.data, b"mdhd"))
+            .unwrap();
+        assert_eq!(u32_at(mdhd.data, 16), Some(300));
+        // 结束时间不晚于开始时没有sample
+        assert!(build(&m, 0.0, Some(0.0), &[]).is_none());
+    }
+
+    #[test]
+    fn clip_all_tracks() {
+        let data = moov(&[trak(&VIDEO), trak(&AUDIO)]);
+        let m = moov::parse(&data).unwrap();
+        let udta = atom(b"udta", &atom(b"meta", b"test"));
+        let clip = build(&m, 0.0, None, &udta).unwrap();
+        assert_eq!(clip.range, (1000, 5027));
+        let l = clip.head.len() as u64;
+        let out = moov::parse(&clip.head).unwrap();
+        assert_eq!(out.tracks.len(), 2);
+        let v = out.main().unwrap();
+        assert_eq!(offsets(v)[..3], [l, l + 10, l + 1000]);
+        assert_eq!(
+            offsets(&out.tracks[1]),
+            vec![l + 4000, l + 4007, l + 4014, l + 4021]
+        );
+        assert!(mp4::find(out.data, b"udta").is_some_and(|u| u.raw == udta));
+    }
+
+    #[test]
+    fn large_offsets() {
+        let data = moov(&[trak(&VIDEO)]);
+        let m = moov::parse(&data).unwrap();
+        let t = m.main().unwrap();
+        let selected = [Selected {
+            track: t,
+            samples: &t.samples,
+            duration: 600,
+        }];
+        // 超过4G的mdat使用co64及16字节的header
+        let span = 5 << 30;
+        let h = head(&m, &selected, b"isom", &[], 1000, span);
+        assert_eq!(u32_at(&h, h.len() - 16), Some(1));
+        assert_eq!(mp4::u64_at(&h, h.len() - 8), Some(span + 16));
+        let l = h.len() as u64;
+        let out = moov::parse(&h).unwrap();
+        let v = out.main().unwrap();
+        assert_eq!(
+            offsets(v),
+            vec![l, l + 10, l + 1000, l + 1030, l + 2000, l + 3000]
+        );
+        let stbl = mp4::find(v.raw, b"trak")
+            .and_then(|t| mp4::find(t.data, b"mdia"))
+            .and_then(|m| mp4::find(m.data, b"minf"))
+            .and_then(|m| mp4::find(m.data, b"stbl"))
+            .unwrap();
+        assert!(mp4::find(stbl.data, b"co64").is_some());
+        assert!(mp4::find(stbl.data, b"stco").is_none());
+    }
+
+    #[test]
+    fn chunk_runs() {
+        let sample = |offset, size| Sample {
+            offset,
+            size,
+            time: 0,
+            duration: 0,
+            cto: 0,
+            sync: true,
+        };
+        let samples = [
+            sample(0, 10),
+            sample(10, 10),
+            sample(30, 10),
+            sample(40, MAX_CHUNK as u32),
+        ];
+        // 不相邻或超过MAX_CHUNK时开始新的chunk
+        assert_eq!(chunks(&samples), vec![(0, 2), (30, 1), (40, 1)]);
+        assert_eq!(runs([5, 5, 3, 5].into_iter()), vec![(2, 5), (1, 3), (1, 5)]);
+    }
+
+    #[test]
+    fn edit_list() {
+        // 空的edit之后为media_time 1024的edit
+        let mut d = vec![0; 4];
+        for w in [2u32, 100, u32::MAX, 0x10000, 600, 1024, 0x10000] {
+            d.extend_from_slice(&w.to_be_bytes());
+        }
+        let edts = edts_box(&atom(b"elst", &d), 300);
+        let edts = mp4::find(&edts, b"edts").unwrap();
+        let elst = mp4::find(edts.data, b"elst").unwrap().data;
+        assert_eq!(elst[0], 1);
+        assert_eq!(u32_at(elst, 4), Some(1));
+        assert_eq!(mp4::u64_at(elst, 8), Some(300));
+        assert_eq!(mp4::u64_at(elst, 16), Some(1024));
+        assert!(edts_box(&[], 300).is_empty());
+    }
+}
@@ -0,0 +1,253 @@
+use actix_web::web::{self, Bytes};
+use awc::Client;
+use std::error;
+use std::io;
+
+use super::clip::{self, Selected};
+use super::moov::{self, Moov, Sample, Track};
+use super::mp4::{self, atom, u32_at};
+use crate::parser::StreamItem;
+use crate::upstream::{chunk, limit};
+
+// 每个分片的最短时长, 秒, 在此之后的第一个关键帧处切分
+const TARGET: f64 = 6.0;
+
+fn invalid(msg: String) -> Box<dyn error::Error> {
+    Box::new(io::Error::new(io::ErrorKind::InvalidData, msg))
+}
+
+// 获取并解析moov后调用f
+async fn parsed<T>(
+    client: &web::Data<Client>,
+    item: &StreamItem,
+    f: impl FnOnce(&Moov) -> Option<T>,
+) -> Result<T, Box<dyn error::Error>> {
+    if item.ext() != "mp4" || item.index().is_some() {
+        return Err(invalid(format!("{} is not progressive mp4", item.itag)));
+    }
+    let (_, data) = moov::fetch(client, item).await?;
+    let moov = moov::parse(&data).ok_or_else(|| invalid(format!("{} moov", item.itag)))?;
+    f(&moov).ok_or_else(|| invalid(format!("{} fragment", item.itag)))
+}
+
+// progressive mp4转为fMP4分片, init与各分片均由moov的sample表实时生成
+// 返回各分片的时长, 秒
+pub async fn durations(
+    client: &web::Data<Client>,
+    item: &StreamItem,
+) -> Result<Vec<f64>, Box<dyn error::Error>> {
+    parsed(client, item, |moov| {
+        let (starts, end) = boundaries(moov)?;
+        let ends = starts.iter().skip(1).copied().chain([end]);
+        Some(starts.iter().zip(ends).map(|(a, z)| z - a).collect())
+    })
+    .await
+}
+
+// ftyp + moov, sample表为空, 加上mvex
+pub async fn init(
+    client: &web::Data<Client>,
+    item: &StreamItem,
+) -> Result<Bytes, Box<dyn error::Error>> {
+    parsed(client, item, |moov| {
+        let selected: Vec<Selected> = moov
+            .tracks
+            .iter()
+            .map(|track| Selected {
+                track,
+                samples: &[],
+                duration: 0,
+            })
+            .collect();
+        let mut out = atom(b"ftyp", b"iso6\0\0\0\x01iso6mp41");
+        let rewritten = clip::moov_box(moov, &selected, &[], 0, false);
+        let mut mvex = Vec::new();
+        for track in &moov.tracks {
+            // track_ID, default_sample_description_index, 其余默认值均为0
+            let mut trex = vec![0; 4];
+            trex.extend_from_slice(&track_id(track)?.to_be_bytes());
+            trex.extend_from_slice(&1u32.to_be_bytes());
+            trex.extend_from_slice(&[0; 12]);
+            mvex.extend(atom(b"trex", &trex));
+        }
+        let mut body = rewritten.get(8..)?.to_vec();
+        body.extend(atom(b"mvex", &mvex));
+        out.extend(atom(b"moov", &body));
+        Some(Bytes::from(out))
+    })
+    .await
+}
+
+// 第n个分片的moof + mdat, 各track在分片时间范围内的sample所在的字节范围一次获取
+pub async fn fragment(
+    client: &web::Data<Client>,
+    vid: &str,
+    item: &StreamItem,
+    n: usize,
+) -> Result<Bytes, Box<dyn error::Error>> {
+    let (runs, from, to) = parsed(client, item, |moov| {
+        let (starts, _) = boundaries(moov)?;
+        let start = *starts.get(n)?;
+        let end = starts.get(n + 1).copied();
+        let runs: Vec<(u32, Vec<Sample>)> = moov
+            .tracks
+            .iter()
+            .filter_map(|t| {
+                let a = t.samples.partition_point(|s| s.time < t.units(start));
+                let z = match end {
+                    Some(end) => t.samples.partition_point(|s| s.time < t.units(end)),
+                    None => t.samples.len(),
+                };
+                let samples = t.samples.get(a..z).filter(|s| !s.is_empty())?;
+                Some((track_id(t)?, samples.to_vec()))
+            })
+            .collect();
+        let samples = runs.iter().flat_map(|(_, s)| s);
+        let from = samples.clone().map(|s| s.offset).min()?;
+        let to = samples.map(|s| s.offset + s.size as u64).max()?;
+        Some((runs, from, to))
+    })
+    .await?;
+    let src = chunk::source(client, vid, item);
+    let data = chunk::read(src, from, to - 1, limit::TS).await?;
+    let mut mdat = Vec::new();
+    for (_, samples) in &runs {
+        for s in samples {
+            let at = (s.offset - from) as usize;
+            mdat.extend_from_slice(&data[at..at + s.size as usize]);
+        }
+    }
+    // moof的长度与data_offset的值无关, 先生成一次得到长度
+    let len = moof(n as u32 + 1, &runs, 0).len();
+    let mut out = moof(n as u32 + 1, &runs, len + 8);
+    out.extend(atom(b"mdat", &mdat));
+    Ok(Bytes::from(out))
+}
+
+// 按主轨道的关键帧切分, 每段不少于TARGET秒, 返回各段开始的时间及总时长
+fn boundaries(moov: &Moov) -> Option<(Vec<f64>, f64)> {
+    let main = moov.main()?;
+    let mut starts = vec![0.0];
+    for s in main.samples.iter().filter(|s| s.sync) {
+        let t = main.seconds(s.time);
+        if t - starts.last()? >= TARGET {
+            starts.push(t);
+        }
+    }
+    let end = moov
+        .tracks
+        .iter()
+        .filter_map(|t| {
+            let last = t.samples.last()?;
+            Some(t.seconds(last.time + last.duration as u64))
+        })
+        .fold(0.0, f64::max);
+    Some((starts, end))
+}
+
+fn track_id(track: &Track) -> Option<u32> {
+    let trak = mp4::find(track.raw, b"trak")?.data;
+    let tkhd = mp4::find(trak, b"tkhd")?.data;
+    match tkhd.first()? {
+        1 => u32_at(tkhd, 20),
+        _ => u32_at(tkhd, 12),
+    }
+}
+
+// data_offset为mdat数据相对moof开始的偏移
+fn moof(seq: u32, runs: &[(u32, Vec<Sample>)], data_offset: usize) -> Vec<u8> {
+    let mut out = full(b"mfhd", 0, 0, &seq.to_be_bytes());
+    let mut offset = data_offset;
+    for (id, samples) in runs {
+        // default-base-is-moof
+        let mut traf = full(b"tfhd", 0, 0x020000, &id.to_be_bytes());
+        traf.extend(full(b"tfdt", 1, 0, &samples[0].time.to_be_bytes()));
+        // data_offset, duration, size, flags, composition time offset
+        let mut trun = (samples.len() as u32).to_be_bytes().to_vec();
+        trun.extend_from_slice(&(offset as u32).to_be_bytes());
+        for s in samples {
+            let flags: u32 = match s.sync {
+                true => 0x0200_0000,
+                false => 0x0101_0000,
+            };
+            for v in [s.duration, s.size, flags, s.cto] {
+                trun.extend_from_slice(&v.to_be_bytes());
+            }
+            offset += s.size as usize;
+        }
+        traf.extend(full(b"trun", 1, 0x000f01, &trun));
+        out.extend(atom(b"traf", &traf));
+    }
+    atom(b"moof", &out)
+}
+
+fn full(kind: &[u8; 4], version: u8, flags: u32, payload: &[u8]) -> Vec<u8> {
+    let mut data = (flags | (version as u32) << 24).to_be_bytes().to_vec();
+    data.extend_from_slice(payload);
+    atom(kind, &data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::media::moof;
+    use crate::media::moov::tests::{AUDIO, Spec, VIDEO, moov, trak};
+
+    #[test]
+    fn moof_round_trip() {
+        let data = moov(&[trak(&VIDEO), trak(&AUDIO)]);
+        let m = moov::parse(&data).unwrap();
+        let runs = vec![
+            (1, m.tracks[0].samples[3..].to_vec()),
+            (2, m.tracks[1].samples.clone()),
+        ];
+        let len = moof(7, &runs, 0).len();
+        let out = moof(7, &runs, len + 8);
+        assert_eq!(out.len(), len);
+        let mfhd = mp4::find(mp4::find(&out, b"moof").unwrap().data, b"mfhd").unwrap();
+        assert_eq!(u32_at(mfhd.data, 4), Some(7));
+        // 解析得到的偏移依次指向mdat中的数据
+        let base = 9000;
+        let parsed = moof::samples(&out, base, moof::Defaults::default()).unwrap();
+        let offsets: Vec<u64> = parsed.iter().map(|s| s.offset).collect();
+        let start = base + len as u64 + 8;
+        let sizes = [40, 50, 60, 7, 7, 7, 7];
+        let expected: Vec<u64> = sizes
+            .iter()
+            .scan(start, |pos, size| {
+                let at = *pos;
+                *pos += size;
+                Some(at)
+            })
+            .collect();
+        assert_eq!(offsets, expected);
+        let sync: Vec<bool> = parsed.iter().map(|s| s.sync).collect();
+        assert_eq!(sync, vec![true, false, false, true, true, true, true]);
+        assert!(parsed[..3].iter().all(|s| s.cto == 50 && s.duration == 100));
+        assert_eq!(parsed[6].duration, 512);
+        // tfdt为分片开始的解码时间
+        let traf = mp4::atoms(&out[8..]).find(|a| &a.kind == b"traf").unwrap();
+        let tfdt = mp4::find(traf.data, b"tfdt").unwrap();
+        assert_eq!(mp4::u64_at(tfdt.data, 4), Some(300));
+    }
+
+    #[test]
+    fn keyframe_boundaries() {
+        // 每10秒一个sample, 关键帧在0及30秒
+        let slow = Spec {
+            timescale: 10,
+            ..VIDEO
+        };
+        let data = moov(&[trak(&slow)]);
+        let m = moov::parse(&data).unwrap();
+        let (starts, end) = boundaries(&m).unwrap();
+        assert_eq!(starts, vec![0.0, 30.0]);
+        assert_eq!(end, 60.0);
+        // 关键帧间隔小于TARGET时不切分
+        let data = moov(&[trak(&VIDEO)]);
+        let m = moov::parse(&data).unwrap();
+        let (starts, end) = boundaries(&m).unwrap();
+        assert_eq!(starts, vec![0.0]);
+        assert_eq!(end, 0.6);
+    }
+}
@@ -0,0 +1,176 @@
+use actix_web::web::{self, Bytes};
+use awc::Client;
+use serde::Serialize;
+use std::error;
+use std::io;
+use std::sync::Arc;
+
+use super::{mp4, webm};
+use crate::parser::{self, StreamItem};
+use crate::request;
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Segment {
+    // 秒
+    pub start: f64,
+    pub duration: f64,
+    pub offset: u64,
+    pub size: u64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Index {
+    itag: String,
+    init: (u64, u64),
+    segments: Vec<Segment>,
+}
+
+pub async fn index(
+    client: &web::Data<Client>,
+    vid: &String,
+    itag: &str,
+    lang: &str,
+) -> Result<Index, Box<dyn error::Error>> {
+    let info = parser::parse(client, vid).await?;
+    let Some(item) = info.stream(itag, lang) else {
+        return Err(Box::new(io::Error::new(
+            io::ErrorKind::NotFound,
+            "itag not found",
+        )));
+    };
+    Ok(Index {
+        itag: item.itag.clone(),
+        init: item.init().unwrap_or_default(),
+        segments: segments(client, item).await?,
+    })
+}
+
+fn not_found(item: &StreamItem) -> Box<dyn error::Error> {
+    Box::new(io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("{} index", item.itag),
+    ))
+}
+
+// 文件头部(init + index), 经由CACHEDATA缓存
+pub async fn head(
+    client: &web::Data<Client>,
+    item: &StreamItem,
+) -> Result<Arc<Bytes>, Box<dyn error::Error>> {
+    let (_, index_end) = item.index().ok_or_else(|| not_found(item))?;
+    let url = format!("{}&range=0-{}", item.url, index_end);
+    request::req_get_cache(client, &url, 3600, 5 << 20).await
+}
+
+// 获取adaptive格式的分片索引
+pub async fn segments(
+    client: &web::Data<Client>,
+    item: &StreamItem,
+) -> Result<Vec<Segment>, Box<dyn error::Error>> {
+    let data = head(client, item).await?;
+    parse(&data, item).ok_or_else(|| not_found(item))
+}
+
+pub fn parse(data: &[u8], item: &StreamItem) -> Option<Vec<Segment>> {
+    let total: u64 = item.len.parse().unwrap_or_default();
+    let segments = if item.ext() == "webm" {
+        let duration = item.duration_ms.map(|ms| ms as f64 / 1000.0);
+        from_cues(data, total, duration)
+    } else {
+        from_sidx(data)
+    };
+    segments.filter(|s| !s.is_empty())
+}
+
+fn from_sidx(data: &[u8]) -> Option<Vec<Segment>> {
+    let sidx = mp4::parse_sidx(data, 0)?;
+    let scale = sidx.timescale.max(1) as f64;
+    let mut offset = sidx.first;
+    let mut time = sidx.earliest;
+    let mut res = Vec::with_capacity(sidx.refs.len());
+    for (size, duration) in sidx.refs {
+        res.push(Segment {
+            start: time as f64 / scale,
+            duration: duration as f64 / scale,
+            offset,
+            size: size as u64,
+        });
+        offset += size as u64;
+        time += duration as u64;
+    }
+    Some(res)
+}
+
+fn from_cues(data: &[u8], total: u64, duration: Option<f64>) -> Option<Vec<Segment>> {
+    let cues = webm::parse_cues(data)?;
+    let scale = cues.timecode_scale as f64 / 1e9;
+    let points = &cues.points;
+    let mut res = Vec::with_capacity(points.len());
+    for (i, (time, offset)) in points.iter().enumerate() {
+        // 最后一个cluster直到文件末尾, 时长以总时长计算
+        let start = *time as f64 * scale;
+        let (end, next_offset) = match points.get(i + 1) {
+            Some((t, o)) => (*t as f64 * scale, *o),
+            None => (duration.unwrap_or(start).max(start), total),
+        };
+        res.push(Segment {
+            start,
+            duration: end - start,
+            offset: *offset,
+            size: next_offset.saturating_sub(*offset),
+        });
+    }
+    Some(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sidx_segments() {
+        let mut d = vec![0; 4];
+        d.extend_from_slice(&1u32.to_be_bytes());
+        d.extend_from_slice(&1000u32.to_be_bytes());
+        d.extend_from_slice(&500u32.to_be_bytes());
+        d.extend_from_slice(&0u32.to_be_bytes());
+        d.extend_from_slice(&[0, 0, 0, 2]);
+        for (size, duration) in [(100u32, 2000u32), (300, 1500)] {
+            d.extend_from_slice(&size.to_be_bytes());
+            d.extend_from_slice(&duration.to_be_bytes());
+            d.extend_from_slice(&[0; 4]);
+        }
+        let data = mp4::atom(b"sidx", &d);
+        let s = from_sidx(&data).unwrap();
+        let first = data.len() as u64;
+        assert_eq!(s.len(), 2);
+        assert_eq!((s[0].start, s[0].duration), (0.5, 2.0));
+        assert_eq!((s[0].offset, s[0].size), (first, 100));
+        assert_eq!((s[1].start, s[1].duration), (2.5, 1.5));
+        assert_eq!((s[1].offset, s[1].size), (first + 100, 300));
+    }
+
+    #[test]
+    fn cue_segments() {
+        let point = |time: u64, pos: u64| {
+            let positions = webm::uint_element(webm::CUE_CLUSTER_POSITION, pos);
+            let point = [
+                webm::uint_element(webm::CUE_TIME, time),
+                webm::element(webm::CUE_TRACK_POSITIONS, &positions),
+            ];
+            webm::element(webm::CUE_POINT, &point.concat())
+        };
+        let cues = webm::element(webm::CUES, &[point(0, 50), point(4000, 1050)].concat());
+        let data = webm::element(webm::SEGMENT, &cues);
+        let base = (data.len() - cues.len()) as u64;
+        let s = from_cues(&data, 5000, Some(10.0)).unwrap();
+        assert_eq!(s.len(), 2);
+        assert_eq!((s[0].start, s[0].duration), (0.0, 4.0));
+        assert_eq!((s[0].offset, s[0].size), (base + 50, 1000));
+        // 最后一个cluster到文件末尾, 时长到总时长
+        assert_eq!((s[1].start, s[1].duration), (4.0, 6.0));
+        assert_eq!(s[1].size, 5000 - base - 1050);
+        let s = from_cues(&data, 5000, None).unwrap();
+        assert_eq!(s[1].duration, 0.0);
+    }
+}
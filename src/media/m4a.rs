@@ -0,0 +1,289 @@
+use actix_web::web::{self, Bytes};
+use awc::Client;
+use futures_util::stream::{self, Stream, StreamExt, TryStreamExt};
+use std::error;
+use std::io;
+
+use super::clip::{self, Selected};
+use super::index::{self, Segment};
+use super::moof::{self, Defaults};
+use super::moov::{self, Sample};
+use super::mp4::{self, u32_at};
+use super::tags::Tags;
+use crate::parser::StreamItem;
+use crate::upstream::chunk::{self, Body, Source};
+use crate::upstream::limit;
+
+// 预扫描时每个分片读取的大小, 音频分片的moof通常只有几KB
+const PROBE: u64 = 16 << 10;
+
+// 预扫描同时进行的请求数
+const PARALLEL: usize = 8;
+
+// 待输出的一个分片, ranges为预扫描得到的sample所在的[开始, 结束), 相邻的已合并
+struct Part {
+    ranges: Vec<(u64, u64)>,
+}
+
+fn invalid(msg: String) -> Box<dyn error::Error> {
+    Box::new(io::Error::new(io::ErrorKind::InvalidData, msg))
+}
+
+// 将fMP4的audio-only格式转为moov在前的普通m4a, 不转码
+// 先读取所有分片的moof得到sample表, 新的mdat为各分片sample数据依次拼接, 因此总长度可以预先算出
+// 输出时只请求各分片中sample所在的范围, 边获取边输出
+pub async fn remux(
+    client: &web::Data<Client>,
+    vid: &str,
+    item: &StreamItem,
+    tags: Option<&Tags>,
+) -> Result<(u64, impl Stream<Item = Result<Bytes, io::Error>> + use<>), Box<dyn error::Error>> {
+    if item.ext() != "mp4" || item.index().is_none() {
+        return Err(invalid(format!("{} is not adaptive mp4", item.itag)));
+    }
+    let data = index::head(client, item).await?;
+    let init = mp4::find(&data, b"moov").ok_or_else(|| invalid(format!("{} init", item.itag)))?;
+    let moov = moov::parse(init.raw).ok_or_else(|| invalid(format!("{} moov", item.itag)))?;
+    let track = moov
+        .tracks
+        .first()
+        .ok_or_else(|| invalid(format!("{} no track", item.itag)))?;
+    let defaults = moof::defaults(init.data);
+    let segments =
+        index::parse(&data, item).ok_or_else(|| invalid(format!("{} index", item.itag)))?;
+    let src = chunk::source(client, vid, item);
+    let fragments: Vec<Vec<Sample>> = stream::iter(
+        segments
+            .iter()
+            .map(|s| scan(src.clone(), item, s, defaults)),
+    )
+    .buffered(PARALLEL)
+    .collect::<Vec<_>>()
+    .await
+    .into_iter()
+    .collect::<Result<_, _>>()?;
+
+    let mut samples = Vec::new();
+    let mut pos = 0;
+    let mut time = 0;
+    for s in fragments.iter().flatten() {
+        samples.push(Sample {
+            offset: pos,
+            time,
+            ..*s
+        });
+        pos += s.size as u64;
+        time += s.duration as u64;
+    }
+    let selected = [Selected {
+        track,
+        samples: &samples,
+        duration: time,
+    }];
+    let udta = tags.map(|t| t.udta()).unwrap_or_default();
+    let head = clip::head(
+        &moov,
+        &selected,
+        b"M4A \0\0\x02\0M4A mp42isom",
+        &udta,
+        0,
+        pos,
+    );
+    let len = head.len() as u64 + pos;
+    let parts: Vec<Part> = fragments.iter().map(|f| ranges(f)).collect();
+    let body = stream::iter(parts)
+        .filter(|part| std::future::ready(!part.ranges.is_empty()))
+        .then(move |part| {
+            let src = src.clone();
+            async move {
+                let (start, end) = (part.ranges[0].0, part.ranges[part.ranges.len() - 1].1);
+                let (_, body) = chunk::open(src, start, end - 1, limit::FILE, 1).await?;
+                Ok::<_, io::Error>(select(body, start, part.ranges))
+            }
+        })
+        .try_flatten();
+    Ok((
+        len,
+        stream::once(async move { Ok(Bytes::from(head)) }).chain(body),
+    ))
+}
+
+// 读取分片开头的moof, 只用于计算sample表, 不缓存
+async fn scan(
+    src: Source,
+    item: &StreamItem,
+    seg: &Segment,
+    defaults: Defaults,
+) -> Result<Vec<Sample>, Box<dyn error::Error>> {
+    let end = seg.offset + seg.size.min(PROBE) - 1;
+    let mut data = chunk::read(src.clone(), seg.offset, end, limit::FILE).await?;
+    // moof超过PROBE时再读取完整的moof
+    if let Some(moof) = mp4::find(&data, b"moof")
+        && let Some(size) = u32_at(moof.raw, 0)
+        && size as usize > moof.size()
+    {
+        let end = seg.offset + size as u64 - 1;
+        data = chunk::read(src, seg.offset, end, limit::FILE).await?;
+    }
+    let samples = moof::samples(&data, seg.offset, defaults)
+        .ok_or_else(|| invalid(format!("{} moof at {}", item.itag, seg.offset)))?;
+    // 输出时按偏移顺序读取, sample需要在分片内且按偏移递增
+    let end = seg.offset + seg.size;
+    let mut pos = seg.offset;
+    for s in &samples {
+        if s.offset < pos || s.offset + s.size as u64 > end {
+            return Err(invalid(format!("{} sample at {}", item.itag, s.offset)));
+        }
+        pos = s.offset + s.size as u64;
+    }
+    Ok(samples)
+}
+
+// 预扫描时已检查sample按偏移递增且不重叠
+fn ranges(samples: &[Sample]) -> Part {
+    let mut ranges: Vec<(u64, u64)> = Vec::new();
+    for s in samples {
+        let end = s.offset + s.size as u64;
+        match ranges.last_mut() {
+            Some(last) if last.1 == s.offset => last.1 = end,
+            _ => ranges.push((s.offset, end)),
+        }
+    }
+    Part { ranges }
+}
+
+// body从start开始, 只输出ranges中的部分, 通常sample是连续的, 直接输出上游的数据不复制
+fn select(body: Body, start: u64, ranges: Vec<(u64, u64)>) -> Body {
+    let mut pos = start;
+    let mut i = 0;
+    body.map_ok(move |b| {
+        let from = pos;
+        pos += b.len() as u64;
+        let mut pieces = Vec::new();
+        while let Some(&(a, z)) = ranges.get(i) {
+            let (lo, hi) = (a.max(from), z.min(pos));
+            if lo < hi {
+                pieces.push(b.slice((lo - from) as usize..(hi - from) as usize));
+            }
+            if z > pos {
+                break;
+            }
+            i += 1;
+        }
+        match pieces.len() {
+            1 => pieces.swap_remove(0),
+            _ => Bytes::from(pieces.concat()),
+        }
+    })
+    .boxed_local()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::media::mp4::atom;
+    use actix_web::{App, HttpResponse, HttpServer};
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    fn sample(offset: u64, size: u32) -> Sample {
+        Sample {
+            offset,
+            size,
+            time: 0,
+            duration: 1024,
+            cto: 0,
+            sync: true,
+        }
+    }
+
+    // 一个分片: moof中的trun有n个sample, 每个100字节, 之后是mdat
+    fn fragment(n: u32) -> Vec<u8> {
+        let mut trun = 0x201u32.to_be_bytes().to_vec();
+        trun.extend_from_slice(&n.to_be_bytes());
+        trun.extend_from_slice(&[0; 4]);
+        for _ in 0..n {
+            trun.extend_from_slice(&100u32.to_be_bytes());
+        }
+        let tfhd = atom(b"tfhd", &[0, 0, 0, 0, 0, 0, 0, 1]);
+        let traf = atom(b"traf", &[tfhd, atom(b"trun", &trun)].concat());
+        let mut moof = atom(b"moof", &traf);
+        let offset = moof.len() as u32 + 8;
+        let pos = moof.windows(4).position(|w| w == b"trun").unwrap();
+        moof[pos + 12..pos + 16].copy_from_slice(&offset.to_be_bytes());
+        [moof, atom(b"mdat", &vec![0; 100 * n as usize])].concat()
+    }
+
+    #[actix_web::test]
+    async fn scan_large_moof() {
+        let data = Arc::new(fragment(5000));
+        let ranges = Arc::new(Mutex::new(Vec::new()));
+        let (file, log) = (data.clone(), ranges.clone());
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = format!("http://{}/f?id=1", listener.local_addr().unwrap());
+        let server = HttpServer::new(move || {
+            let (file, log) = (file.clone(), log.clone());
+            App::new().default_service(web::to(move |q: web::Query<HashMap<String, String>>| {
+                let (file, log) = (file.clone(), log.clone());
+                async move {
+                    let (a, b) = q["range"].split_once('-').unwrap();
+                    let (a, b): (usize, usize) = (a.parse().unwrap(), b.parse().unwrap());
+                    log.lock().unwrap().push((a, b));
+                    HttpResponse::Ok().body(file[a..=b].to_vec())
+                }
+            }))
+        })
+        .workers(1)
+        .listen(listener)
+        .unwrap()
+        .run();
+        actix_web::rt::spawn(server);
+        let (_, item) = crate::parser::stream_item(&serde_json::json!({
+            "itag": 140,
+            "mimeType": "audio/mp4; codecs=\"mp4a.40.2\"",
+            "url": addr,
+        }));
+        let seg = Segment {
+            start: 0.0,
+            duration: 1.0,
+            offset: 0,
+            size: data.len() as u64,
+        };
+        let src = Source {
+            url: item.url.clone(),
+            refresh: None,
+        };
+        let samples = scan(src, &item, &seg, Defaults::default()).await.unwrap();
+        assert_eq!(samples.len(), 5000);
+        assert_eq!(samples[4999].offset + 100, data.len() as u64);
+        // moof超过PROBE时再读取完整的moof, 每次都直接请求上游
+        let moof = u32_at(&data, 0).unwrap() as usize;
+        let expected = vec![(0, PROBE as usize - 1), (0, moof - 1)];
+        assert_eq!(ranges.lock().unwrap().clone(), expected);
+    }
+
+    #[test]
+    fn merged_ranges() {
+        let samples = [sample(100, 10), sample(110, 5), sample(120, 10)];
+        assert_eq!(ranges(&samples).ranges, vec![(100, 115), (120, 130)]);
+        assert!(ranges(&[]).ranges.is_empty());
+    }
+
+    #[actix_web::test]
+    async fn select_pieces() {
+        let data: Vec<u8> = (0..30).collect();
+        // 上游从100开始, 分为两块输出
+        let parts = vec![
+            Ok(Bytes::copy_from_slice(&data[..12])),
+            Ok(Bytes::copy_from_slice(&data[12..])),
+        ];
+        let body = stream::iter(parts).boxed_local();
+        let out: Vec<Bytes> = select(body, 100, vec![(100, 105), (108, 115), (120, 130)])
+            .map(|b| b.unwrap())
+            .collect()
+            .await;
+        let expected: Vec<u8> = [&data[..5], &data[8..15], &data[20..]].concat();
+        assert_eq!(out.concat(), expected);
+        assert_eq!(out[0][..], [0, 1, 2, 3, 4, 8, 9, 10, 11][..]);
+    }
+}
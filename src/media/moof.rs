@@ -0,0 +1,167 @@
+// fMP4分片(moof)中sample的解析
+use super::moov::Sample;
+use super::mp4::{self, u32_at, u64_at};
+
+// trex中的默认值
+#[derive(Clone, Copy, Default)]
+pub struct Defaults {
+    pub duration: u32,
+    pub size: u32,
+    pub flags: u32,
+}
+
+// init部分moov/mvex/trex中的默认值, 只取第一个track
+pub fn defaults(moov: &[u8]) -> Defaults {
+    let trex = mp4::find(moov, b"mvex").and_then(|a| mp4::find(a.data, b"trex"));
+    let Some(d) = trex.map(|a| a.data) else {
+        return Defaults::default();
+    };
+    Defaults {
+        duration: u32_at(d, 12).unwrap_or_default(),
+        size: u32_at(d, 16).unwrap_or_default(),
+        flags: u32_at(d, 20).unwrap_or_default(),
+    }
+}
+
+// data从moof开始, base为data在文件中的偏移, 返回的sample偏移为文件中的绝对偏移
+// 只需要moof部分即可解析, sample的time为相对分片开始的解码时间
+pub fn samples(data: &[u8], base: u64, defaults: Defaults) -> Option<Vec<Sample>> {
+    let moof = mp4::find(data, b"moof")?;
+    let moof_start = base + moof.offset as u64;
+    let mut res = Vec::new();
+    let mut time = 0;
+    for traf in mp4::atoms(moof.data).filter(|a| &a.kind == b"traf") {
+        let tfhd = mp4::find(traf.data, b"tfhd")?.data;
+        let flags = u32_at(tfhd, 0)? & 0xffffff;
+        let mut pos = 8;
+        let mut d = defaults;
+        let mut data_base = moof_start;
+        if flags & 0x1 != 0 {
+            data_base = u64_at(tfhd, pos)?;
+            pos += 8;
+        }
+        if flags & 0x2 != 0 {
+            pos += 4;
+        }
+        if flags & 0x8 != 0 {
+            d.duration = u32_at(tfhd, pos)?;
+            pos += 4;
+        }
+        if flags & 0x10 != 0 {
+            d.size = u32_at(tfhd, pos)?;
+            pos += 4;
+        }
+        if flags & 0x20 != 0 {
+            d.flags = u32_at(tfhd, pos)?;
+        }
+        // 没有data_offset的trun紧接上一个trun的数据
+        let mut offset = data_base;
+        for trun in mp4::atoms(traf.data).filter(|a| &a.kind == b"trun") {
+            let t = trun.data;
+            let flags = u32_at(t, 0)? & 0xffffff;
+            let count = u32_at(t, 4)?;
+            let mut pos = 8;
+            if flags & 0x1 != 0 {
+                offset = data_base.checked_add_signed(u32_at(t, pos)? as i32 as i64)?;
+                pos += 4;
+            }
+            let mut first_flags = None;
+            if flags & 0x4 != 0 {
+                first_flags = Some(u32_at(t, pos)?);
+                pos += 4;
+            }
+            for i in 0..count {
+                let mut field = |bit: u32, default: u32| -> Option<u32> {
+                    if flags & bit == 0 {
+                        return Some(default);
+                    }
+                    let v = u32_at(t, pos)?;
+                    pos += 4;
+                    Some(v)
+                };
+                let duration = field(0x100, d.duration)?;
+                let size = field(0x200, d.size)?;
+                let sample_flags = field(0x400, d.flags)?;
+                let cto = field(0x800, 0)?;
+                let sample_flags = match (i, first_flags) {
+                    (0, Some(f)) => f,
+                    _ => sample_flags,
+                };
+                res.push(Sample {
+                    offset,
+                    size,
+                    time,
+                    duration,
+                    cto,
+                    // sample_is_non_sync_sample
+                    sync: sample_flags & 0x0001_0000 == 0,
+                });
+                offset += size as u64;
+                time += duration as u64;
+            }
+        }
+    }
+    Some(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::media::mp4::atom;
+
+    fn full(kind: &[u8; 4], flags: u32, words: &[u32]) -> Vec<u8> {
+        let mut d = flags.to_be_bytes().to_vec();
+        for w in words {
+            d.extend_from_slice(&w.to_be_bytes());
+        }
+        atom(kind, &d)
+    }
+
+    #[test]
+    fn trex_defaults() {
+        let trex = full(b"trex", 0, &[1, 1, 1024, 200, 0x0001_0000]);
+        let moov = [atom(b"mvhd", &[0; 8]), atom(b"mvex", &trex)].concat();
+        let d = defaults(&moov);
+        assert_eq!((d.duration, d.size, d.flags), (1024, 200, 0x0001_0000));
+        assert_eq!(defaults(&[]).duration, 0);
+    }
+
+    #[test]
+    fn trun_samples() {
+        let d = Defaults {
+            duration: 1024,
+            size: 0,
+            flags: 0x0001_0000,
+        };
+        // tfhd中的默认sample大小覆盖trex
+        let tfhd = full(b"tfhd", 0x10, &[1, 100]);
+        // 第一个trun有data_offset及first_sample_flags, 第二个紧接其后
+        let trun1 = full(b"trun", 0x1 | 0x4 | 0x200, &[2, 0, 0, 300, 400]);
+        let trun2 = full(b"trun", 0x100 | 0x800, &[1, 512, 7]);
+        let traf = atom(b"traf", &[tfhd, trun1, trun2].concat());
+        let mut moof = atom(b"moof", &[full(b"mfhd", 0, &[1]), traf].concat());
+        // data_offset指向mdat的数据
+        let offset = moof.len() as u32 + 8;
+        let pos = moof.windows(4).position(|w| w == b"trun").unwrap();
+        moof[pos + 12..pos + 16].copy_from_slice(&offset.to_be_bytes());
+        let base = 5000;
+        let s = samples(&moof, base, d).unwrap();
+        let start = base + offset as u64;
+        let offsets: Vec<u64> = s.iter().map(|v| v.offset).collect();
+        assert_eq!(offsets, vec![start, start + 300, start + 700]);
+        let sizes: Vec<u32> = s.iter().map(|v| v.size).collect();
+        assert_eq!(sizes, vec![300, 400, 100]);
+        let times: Vec<u64> = s.iter().map(|v| v.time).collect();
+        assert_eq!(times, vec![0, 1024, 2048]);
+        assert_eq!(s[2].duration, 512);
+        assert_eq!(s[2].cto, 7);
+        let sync: Vec<bool> = s.iter().map(|v| v.sync).collect();
+        assert_eq!(sync, vec![true, false, false]);
+        // trun的数据不完整
+        let short = atom(
+            b"traf",
+            &[full(b"tfhd", 0, &[1]), full(b"trun", 0x200, &[2, 1])].concat(),
+        );
+        assert!(samples(&atom(b"moof", &short), 0, d).is_none());
+    }
+}
@@ -0,0 +1,403 @@
+use actix_web::web::{self, Bytes};
+use awc::Client;
+use std::error;
+use std::io;
+
+use super::mp4::{self, u32_at, u64_at};
+use crate::parser::StreamItem;
+use crate::request;
+
+// 每次探测文件头部读取的大小
+const PROBE: u64 = 256 << 10;
+
+// moov的最大大小
+const MAX_MOOV: u64 = 64 << 20;
+
+#[derive(Clone, Copy)]
+pub struct Sample {
+    pub offset: u64,
+    pub size: u32,
+    // 解码时间, 以track的timescale为单位
+    pub time: u64,
+    pub duration: u32,
+    // ctts, 显示时间与解码时间之差
+    pub cto: u32,
+    pub sync: bool,
+}
+
+pub struct Track<'a> {
+    // 完整的trak box
+    pub raw: &'a [u8],
+    // vide, soun等
+    pub handler: [u8; 4],
+    pub timescale: u32,
+    pub samples: Vec<Sample>,
+}
+
+impl Track<'_> {
+    pub fn seconds(&self, time: u64) -> f64 {
+        time as f64 / self.timescale.max(1) as f64
+    }
+
+    pub fn units(&self, seconds: f64) -> u64 {
+        (seconds.max(0.0) * self.timescale as f64) as u64
+    }
+
+    // 不晚于time的最后一个关键帧
+    pub fn keyframe(&self, time: u64) -> Option<usize> {
+        let end = self.samples.partition_point(|s| s.time <= time);
+        self.samples[..end]
+            .iter()
+            .rposition(|s| s.sync)
+            .or_else(|| self.samples.iter().position(|s| s.sync))
+    }
+}
+
+pub struct Moov<'a> {
+    // moov box的内容
+    pub data: &'a [u8],
+    pub mvhd: &'a [u8],
+    pub tracks: Vec<Track<'a>>,
+}
+
+impl<'a> Moov<'a> {
+    // 以视频轨道为准, 没有视频时使用第一个轨道
+    pub fn main(&self) -> Option<&Track<'a>> {
+        self.tracks
+            .iter()
+            .find(|t| &t.handler == b"vide")
+            .or(self.tracks.first())
+    }
+}
+
+fn invalid(msg: String) -> Box<dyn error::Error> {
+    Box::new(io::Error::new(io::ErrorKind::InvalidData, msg))
+}
+
+// 获取progressive格式的moov box(包含header), 依次读取顶层box直到找到moov, 经由CACHEDATA缓存
+pub async fn fetch(
+    client: &web::Data<Client>,
+    item: &StreamItem,
+) -> Result<(u64, Bytes), Box<dyn error::Error>> {
+    let total: u64 = item.len.parse().unwrap_or_default();
+    let mut pos = 0;
+    while pos + 8 <= total {
+        let end = total.min(pos + PROBE) - 1;
+        let url = format!("{}&range={}-{}", item.url, pos, end);
+        let data = request::req_get_cache(client, &url, 3600, PROBE as u32).await?;
+        let mut next = pos;
+        for atom in mp4::atoms(&data) {
+            let offset = pos + atom.offset as u64;
+            let size = match (atom.header, mp4::u32_at(atom.raw, 0)) {
+                (16, _) => u64_at(atom.raw, 8).unwrap_or_default(),
+                (_, Some(0)) => total - offset,
+                (_, size) => size.unwrap_or_default() as u64,
+            };
+            if &atom.kind == b"moov" {
+                if atom.size() as u64 == size {
+                    return Ok((offset, Bytes::copy_from_slice(atom.raw)));
+                }
+                if size > MAX_MOOV {
+                    return Err(invalid(format!("{} moov too large {}", item.itag, size)));
+                }
+                let url = format!("{}&range={}-{}", item.url, offset, offset + size - 1);
+                let data = request::req_get_cache(client, &url, 3600, size as u32).await?;
+                return Ok((offset, data.as_ref().clone()));
+            }
+            next = offset + size;
+        }
+        // 最后一个box不完整时从它的结束位置继续
+        if next <= pos {
+            break;
+        }
+        pos = next;
+    }
+    Err(invalid(format!("{} moov not found", item.itag)))
+}
+
+pub fn parse(moov: &[u8]) -> Option<Moov<'_>> {
+    let moov = mp4::find(moov, b"moov")?;
+    let mvhd = mp4::find(moov.data, b"mvhd")?.raw;
+    let tracks = mp4::atoms(moov.data)
+        .filter(|a| &a.kind == b"trak")
+        .filter_map(|a| track(a.raw, a.data))
+        .collect();
+    Some(Moov {
+        data: moov.data,
+        mvhd,
+        tracks,
+    })
+}
+
+fn track<'a>(raw: &'a [u8], trak: &[u8]) -> Option<Track<'a>> {
+    let mdia = mp4::find(trak, b"mdia")?.data;
+    let mdhd = mp4::find(mdia, b"mdhd")?.data;
+    let timescale = match mdhd.first()? {
+        1 => u32_at(mdhd, 20)?,
+        _ => u32_at(mdhd, 12)?,
+    };
+    let hdlr = mp4::find(mdia, b"hdlr")?.data;
+    let handler = hdlr.get(8..12)?.try_into().ok()?;
+    let minf = mp4::find(mdia, b"minf")?.data;
+    let stbl = mp4::find(minf, b"stbl")?.data;
+    Some(Track {
+        raw,
+        handler,
+        timescale,
+        samples: samples(stbl)?,
+    })
+}
+
+// full box的entry列表, 跳过version/flags及entry数量
+fn entries<'a>(stbl: &'a [u8], kind: &[u8; 4], width: usize) -> Option<Vec<&'a [u8]>> {
+    let d = mp4::find(stbl, kind)?.data;
+    let count = u32_at(d, 4)? as usize;
+    let body = d.get(8..)?;
+    if body.len() < count * width {
+        return None;
+    }
+    Some(body.chunks_exact(width).take(count).collect())
+}
+
+fn samples(stbl: &[u8]) -> Option<Vec<Sample>> {
+    let stsz = mp4::find(stbl, b"stsz")?.data;
+    let fixed = u32_at(stsz, 4)?;
+    let count = u32_at(stsz, 8)? as usize;
+    let sizes: Vec<u32> = match fixed {
+        0 => (0..count)
+            .map(|i| u32_at(stsz, 12 + i * 4))
+            .collect::<Option<_>>()?,
+        _ => vec![fixed; count],
+    };
+    let chunks: Vec<u64> = match entries(stbl, b"stco", 4) {
+        Some(e) => e
+            .iter()
+            .map(|v| u32_at(v, 0).map(|v| v as u64))
+            .collect::<Option<_>>()?,
+        None => entries(stbl, b"co64", 8)?
+            .iter()
+            .map(|v| u64_at(v, 0))
+            .collect::<Option<_>>()?,
+    };
+    let stsc: Vec<(u32, u32)> = entries(stbl, b"stsc", 12)?
+        .iter()
+        .map(|v| Some((u32_at(v, 0)?, u32_at(v, 4)?)))
+        .collect::<Option<_>>()?;
+    let mut deltas = Vec::with_capacity(count);
+    for e in entries(stbl, b"stts", 8)? {
+        let (n, delta) = (u32_at(e, 0)?, u32_at(e, 4)?);
+        deltas.extend(std::iter::repeat_n(delta, n as usize));
+    }
+    let mut ctts = Vec::new();
+    for e in entries(stbl, b"ctts", 8).unwrap_or_default() {
+        let (n, offset) = (u32_at(e, 0)?, u32_at(e, 4)?);
+        ctts.extend(std::iter::repeat_n(offset, n as usize));
+    }
+    // 没有stss时所有sample都是关键帧
+    let sync: Option<Vec<u32>> = entries(stbl, b"stss", 4)
+        .map(|e| e.iter().map(|v| u32_at(v, 0)).collect::<Option<_>>())
+        .unwrap_or(None);
+
+    let mut res = Vec::with_capacity(count);
+    let mut time = 0;
+    let mut i = 0;
+    let mut entry = 0;
+    for (c, chunk) in chunks.iter().enumerate() {
+        let c = c as u32 + 1;
+        while stsc.get(entry + 1).is_some_and(|(first, _)| *first <= c) {
+            entry += 1;
+        }
+        let per = stsc.get(entry).map(|(_, n)| *n).unwrap_or_default();
+        let mut offset = *chunk;
+        for _ in 0..per {
+            if i >= count {
+                break;
+            }
+            let duration = deltas.get(i).copied().unwrap_or_default();
+            res.push(Sample {
+                offset,
+                size: sizes[i],
+                time,
+                duration,
+                cto: ctts.get(i).copied().unwrap_or_default(),
+                sync: sync
+                    .as_ref()
+                    .is_none_or(|s| s.binary_search(&(i as u32 + 1)).is_ok()),
+            });
+            offset += sizes[i] as u64;
+            time += duration as u64;
+            i += 1;
+        }
+    }
+    Some(res)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::media::mp4::atom;
+
+    pub(crate) struct Spec<'a> {
+        pub handler: &'a [u8; 4],
+        pub timescale: u32,
+        pub sizes: &'a [u32],
+        // (数量, 时长)
+        pub stts: &'a [(u32, u32)],
+        // (第一个chunk, 每个chunk的sample数)
+        pub stsc: &'a [(u32, u32)],
+        pub chunks: &'a [u64],
+        pub co64: bool,
+        pub stss: Option<&'a [u32]>,
+        pub ctts: &'a [(u32, u32)],
+    }
+
+    fn full(kind: &[u8; 4], words: &[u32]) -> Vec<u8> {
+        let mut d = vec![0; 4];
+        for w in words {
+            d.extend_from_slice(&w.to_be_bytes());
+        }
+        atom(kind, &d)
+    }
+
+    fn pairs(kind: &[u8; 4], entries: &[(u32, u32)], third: bool) -> Vec<u8> {
+        let mut words = vec![entries.len() as u32];
+        for (a, b) in entries {
+            words.extend([*a, *b]);
+            if third {
+                words.push(1);
+            }
+        }
+        full(kind, &words)
+    }
+
+    pub(crate) fn trak(s: &Spec) -> Vec<u8> {
+        let mut stbl = atom(b"stsd", &[0; 8]);
+        stbl.extend(pairs(b"stts", s.stts, false));
+        stbl.extend(pairs(b"stsc", s.stsc, true));
+        let mut stsz = vec![0, s.sizes.len() as u32];
+        stsz.extend_from_slice(s.sizes);
+        stbl.extend(full(b"stsz", &stsz));
+        if s.co64 {
+            let mut d = vec![0; 4];
+            d.extend_from_slice(&(s.chunks.len() as u32).to_be_bytes());
+            for c in s.chunks {
+                d.extend_from_slice(&c.to_be_bytes());
+            }
+            stbl.extend(atom(b"co64", &d));
+        } else {
+            let mut stco = vec![s.chunks.len() as u32];
+            stco.extend(s.chunks.iter().map(|c| *c as u32));
+            stbl.extend(full(b"stco", &stco));
+        }
+        if let Some(stss) = s.stss {
+            let mut words = vec![stss.len() as u32];
+            words.extend_from_slice(stss);
+            stbl.extend(full(b"stss", &words));
+        }
+        if !s.ctts.is_empty() {
+            stbl.extend(pairs(b"ctts", s.ctts, false));
+        }
+        let minf = atom(b"minf", &atom(b"stbl", &stbl));
+        let mdhd = full(b"mdhd", &[0, 0, s.timescale, 0, 0]);
+        let mut hdlr = vec![0; 8];
+        hdlr.extend_from_slice(s.handler);
+        hdlr.extend_from_slice(&[0; 13]);
+        let mut mdia = mdhd;
+        mdia.extend(atom(b"hdlr", &hdlr));
+        mdia.extend(minf);
+        let mut trak = full(b"tkhd", &[0; 20]);
+        trak.extend(atom(b"mdia", &mdia));
+        atom(b"trak", &trak)
+    }
+
+    pub(crate) fn moov(traks: &[Vec<u8>]) -> Vec<u8> {
+        let mut d = full(b"mvhd", &[0, 0, 1000, 600, 0x10000, 0x1000000, 0, 0]);
+        for t in traks {
+            d.extend_from_slice(t);
+        }
+        atom(b"moov", &d)
+    }
+
+    // 6个sample, 每100一个, 关键帧为第1及第4个
+    pub(crate) const VIDEO: Spec = Spec {
+        handler: b"vide",
+        timescale: 1000,
+        sizes: &[10, 20, 30, 40, 50, 60],
+        stts: &[(6, 100)],
+        stsc: &[(1, 2), (3, 1)],
+        chunks: &[1000, 2000, 3000, 4000],
+        co64: false,
+        stss: Some(&[1, 4]),
+        ctts: &[(6, 50)],
+    };
+
+    pub(crate) const AUDIO: Spec = Spec {
+        handler: b"soun",
+        timescale: 48000,
+        sizes: &[7; 4],
+        stts: &[(3, 1024), (1, 512)],
+        stsc: &[(1, 4)],
+        chunks: &[5000],
+        co64: true,
+        stss: None,
+        ctts: &[],
+    };
+
+    #[test]
+    fn sample_table() {
+        let data = moov(&[trak(&AUDIO), trak(&VIDEO)]);
+        let m = parse(&data).unwrap();
+        assert_eq!(m.tracks.len(), 2);
+        // 优先视频轨道
+        let v = m.main().unwrap();
+        assert_eq!(&v.handler, b"vide");
+        let offsets: Vec<u64> = v.samples.iter().map(|s| s.offset).collect();
+        assert_eq!(offsets, vec![1000, 1010, 2000, 2030, 3000, 4000]);
+        let times: Vec<u64> = v.samples.iter().map(|s| s.time).collect();
+        assert_eq!(times, vec![0, 100, 200, 300, 400, 500]);
+        let sync: Vec<bool> = v.samples.iter().map(|s| s.sync).collect();
+        assert_eq!(sync, vec![true, false, false, true, false, false]);
+        assert!(v.samples.iter().all(|s| s.cto == 50 && s.duration == 100));
+        let a = &m.tracks[0];
+        assert_eq!(a.timescale, 48000);
+        assert!(a.samples.iter().all(|s| s.sync));
+        assert_eq!(a.samples[3].offset, 5021);
+        assert_eq!(a.samples[3].time, 3072);
+        assert_eq!(a.samples[3].duration, 512);
+    }
+
+    #[test]
+    fn keyframes() {
+        let data = moov(&[trak(&VIDEO)]);
+        let m = parse(&data).unwrap();
+        let v = m.main().unwrap();
+        assert_eq!(v.keyframe(v.units(0.25)), Some(0));
+        assert_eq!(v.keyframe(300), Some(3));
+        assert_eq!(v.keyframe(10_000), Some(3));
+        assert_eq!(v.seconds(v.units(0.3)), 0.3);
+        // time之前没有关键帧时使用第一个关键帧
+        let late = Spec {
+            stss: Some(&[3]),
+            ..VIDEO
+        };
+        let data = moov(&[trak(&late)]);
+        let m = parse(&data).unwrap();
+        assert_eq!(m.main().unwrap().keyframe(0), Some(2));
+    }
+
+    #[test]
+    fn truncated_table() {
+        let short = Spec {
+            sizes: &[10, 20],
+            ..VIDEO
+        };
+        let mut data = trak(&short);
+        // stsz的数量大于实际的entry, 该轨道被忽略
+        let stsz = data.windows(4).position(|w| w == b"stsz").unwrap();
+        data[stsz + 12..stsz + 16].copy_from_slice(&6u32.to_be_bytes());
+        let data = moov(&[data, trak(&AUDIO)]);
+        let m = parse(&data).unwrap();
+        assert_eq!(m.tracks.len(), 1);
+        assert_eq!(&m.main().unwrap().handler, b"soun");
+    }
+}
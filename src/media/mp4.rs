@@ -0,0 +1,196 @@
+// ISO BMFF box 解析
+pub struct Atom<'a> {
+    pub kind: [u8; 4],
+    // box在所给数据中的偏移
+    pub offset: usize,
+    // header长度,8或16
+    pub header: usize,
+    pub data: &'a [u8],
+    // 包含header的完整box
+    pub raw: &'a [u8],
+}
+
+impl Atom<'_> {
+    pub fn size(&self) -> usize {
+        self.raw.len()
+    }
+}
+
+pub fn atom(kind: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(payload.len() + 8);
+    out.extend_from_slice(&(payload.len() as u32 + 8).to_be_bytes());
+    out.extend_from_slice(kind);
+    out.extend_from_slice(payload);
+    out
+}
+
+pub fn atoms(data: &[u8]) -> Atoms<'_> {
+    Atoms { data, pos: 0 }
+}
+
+pub struct Atoms<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Iterator for Atoms<'a> {
+    type Item = Atom<'a>;
+
+    fn next(&mut self) -> Option<Atom<'a>> {
+        let rest = self.data.get(self.pos..)?;
+        if rest.len() < 8 {
+            return None;
+        }
+        let mut size = u32_at(rest, 0)? as usize;
+        let kind = [rest[4], rest[5], rest[6], rest[7]];
+        let mut header = 8;
+        if size == 1 {
+            size = u64_at(rest, 8)? as usize;
+            header = 16;
+        } else if size == 0 {
+            size = rest.len();
+        }
+        // 数据不完整的box只返回已有部分
+        if size < header {
+            return None;
+        }
+        let end = size.min(rest.len());
+        let atom = Atom {
+            kind,
+            offset: self.pos,
+            header,
+            data: rest.get(header..end)?,
+            raw: &rest[..end],
+        };
+        self.pos += size;
+        Some(atom)
+    }
+}
+
+pub fn find<'a>(data: &'a [u8], kind: &[u8; 4]) -> Option<Atom<'a>> {
+    atoms(data).find(|a| &a.kind == kind)
+}
+
+pub fn u16_at(data: &[u8], pos: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(data.get(pos..pos + 2)?.try_into().ok()?))
+}
+
+pub fn u32_at(data: &[u8], pos: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(data.get(pos..pos + 4)?.try_into().ok()?))
+}
+
+pub fn u64_at(data: &[u8], pos: usize) -> Option<u64> {
+    Some(u64::from_be_bytes(data.get(pos..pos + 8)?.try_into().ok()?))
+}
+
+pub struct Sidx {
+    pub timescale: u32,
+    pub earliest: u64,
+    // 第一个分片的绝对偏移
+    pub first: u64,
+    // (size, duration)
+    pub refs: Vec<(u32, u32)>,
+}
+
+// base为data在文件中的偏移
+pub fn parse_sidx(data: &[u8], base: u64) -> Option<Sidx> {
+    let atom = find(data, b"sidx")?;
+    let d = atom.data;
+    let version = *d.first()?;
+    let timescale = u32_at(d, 8)?;
+    let (earliest, first_offset, mut pos) = if version == 0 {
+        (u32_at(d, 12)? as u64, u32_at(d, 16)? as u64, 20)
+    } else {
+        (u64_at(d, 12)?, u64_at(d, 20)?, 28)
+    };
+    let count = u16_at(d, pos + 2)? as usize;
+    pos += 4;
+    let mut refs = Vec::with_capacity(count);
+    for _ in 0..count {
+        let size = u32_at(d, pos)? & 0x7fff_ffff;
+        let duration = u32_at(d, pos + 4)?;
+        refs.push((size, duration));
+        pos += 12;
+    }
+    // sidx中的偏移以sidx box结束位置为锚点
+    let anchor = base + (atom.offset + atom.size()) as u64;
+    Some(Sidx {
+        timescale,
+        earliest,
+        first: anchor + first_offset,
+        refs,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sidx(version: u8, earliest: u64, first_offset: u64, refs: &[(u32, u32)]) -> Vec<u8> {
+        let mut d = vec![version, 0, 0, 0];
+        d.extend_from_slice(&1u32.to_be_bytes());
+        d.extend_from_slice(&1000u32.to_be_bytes());
+        if version == 0 {
+            d.extend_from_slice(&(earliest as u32).to_be_bytes());
+            d.extend_from_slice(&(first_offset as u32).to_be_bytes());
+        } else {
+            d.extend_from_slice(&earliest.to_be_bytes());
+            d.extend_from_slice(&first_offset.to_be_bytes());
+        }
+        d.extend_from_slice(&[0, 0]);
+        d.extend_from_slice(&(refs.len() as u16).to_be_bytes());
+        for (size, duration) in refs {
+            d.extend_from_slice(&size.to_be_bytes());
+            d.extend_from_slice(&duration.to_be_bytes());
+            d.extend_from_slice(&0x9000_0000u32.to_be_bytes());
+        }
+        atom(b"sidx", &d)
+    }
+
+    #[test]
+    fn atoms_large_and_open_ended() {
+        let mut data = atom(b"ftyp", b"isom");
+        data.extend_from_slice(&1u32.to_be_bytes());
+        data.extend_from_slice(b"free");
+        data.extend_from_slice(&20u64.to_be_bytes());
+        data.extend_from_slice(b"abcd");
+        data.extend_from_slice(&0u32.to_be_bytes());
+        data.extend_from_slice(b"mdat");
+        data.extend_from_slice(b"rest");
+        let list: Vec<Atom> = atoms(&data).collect();
+        assert_eq!(list.len(), 3);
+        assert_eq!(&list[1].kind, b"free");
+        assert_eq!((list[1].header, list[1].data), (16, &b"abcd"[..]));
+        assert_eq!((list[2].offset, list[2].data), (32, &b"rest"[..]));
+    }
+
+    #[test]
+    fn atoms_truncated() {
+        let data = atom(b"moov", &[0; 16]);
+        let list: Vec<Atom> = atoms(&data[..12]).collect();
+        assert_eq!(list.len(), 1);
+        assert_eq!(list[0].data.len(), 4);
+        assert!(atoms(&data[..6]).next().is_none());
+    }
+
+    #[test]
+    fn sidx_anchor() {
+        for version in [0, 1] {
+            let mut data = atom(b"ftyp", b"isom");
+            let box_end = data.len() + sidx(version, 0, 0, &[]).len() + 24;
+            data.extend(sidx(version, 500, 7, &[(100, 2000), (0x8000_0050, 1000)]));
+            let s = parse_sidx(&data, 1000).unwrap();
+            assert_eq!((s.timescale, s.earliest), (1000, 500));
+            assert_eq!(s.first, 1000 + box_end as u64 + 7);
+            // 最高位为reference_type
+            assert_eq!(s.refs, vec![(100, 2000), (0x50, 1000)]);
+        }
+    }
+
+    #[test]
+    fn sidx_truncated() {
+        let data = sidx(0, 0, 0, &[(100, 2000), (200, 2000)]);
+        assert!(parse_sidx(&data[..data.len() - 8], 0).is_none());
+        assert!(parse_sidx(&atom(b"moov", &[]), 0).is_none());
+    }
+}
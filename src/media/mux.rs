@@ -0,0 +1,648 @@
+use actix_web::web::{self, Bytes};
+use awc::Client;
+use futures_util::stream::{self, Stream, StreamExt};
+use std::error;
+use std::io;
+
+use super::index::{self, Segment};
+use super::tags::Tags;
+use super::{mp4, webm};
+use crate::parser;
+use crate::upstream::chunk::{self, Source};
+use crate::upstream::limit;
+use crate::util;
+
+const VIDEO: u32 = 1;
+const AUDIO: u32 = 2;
+
+// 待输出的一个分片, offset为源文件中的偏移, out为输出中的偏移
+struct Part {
+    src: Source,
+    track: u32,
+    offset: u64,
+    size: u64,
+    out: u64,
+}
+
+pub struct Muxed<S> {
+    pub content_type: &'static str,
+    pub len: u64,
+    pub etag: Option<String>,
+    pub title: String,
+    pub label: String,
+    pub body: S,
+}
+
+fn invalid(msg: String) -> Box<dyn error::Error> {
+    Box::new(io::Error::new(io::ErrorKind::InvalidData, msg))
+}
+
+// 将video-only与audio-only的adaptive格式合并为一个文件, 不转码
+// fMP4合并为两个track的fMP4, WebM合并为两个track的WebM, 均按时间交错输出分片
+pub async fn mux(
+    client: &web::Data<Client>,
+    vid: &String,
+    vitag: &str,
+    aitag: &str,
+    ext: &str,
+    lang: &str,
+    tags: Option<&Tags>,
+) -> Result<Muxed<impl Stream<Item = Result<Bytes, io::Error>> + use<>>, Box<dyn error::Error>> {
+    let info = parser::parse(client, vid).await?;
+    let (Some(video), Some(audio)) = (info.stream(vitag, ""), info.stream(aitag, lang)) else {
+        return Err(Box::new(io::Error::new(
+            io::ErrorKind::NotFound,
+            "itag not found",
+        )));
+    };
+    for item in [video, audio] {
+        if item.ext() != ext || item.index().is_none() {
+            return Err(invalid(format!("{} is not adaptive {}", item.itag, ext)));
+        }
+    }
+    let vhead = index::head(client, video).await?;
+    let ahead = index::head(client, audio).await?;
+    let init = if ext == "webm" {
+        webm_init(
+            &vhead,
+            &ahead,
+            tags.map(|t| t.matroska()).unwrap_or_default(),
+        )
+    } else {
+        mp4_init(&vhead, &ahead, tags.map(|t| t.udta()).unwrap_or_default())
+    }
+    .ok_or_else(|| invalid(format!("{} {}+{} init", vid, vitag, aitag)))?;
+    let vsegs = index::parse(&vhead, video).ok_or_else(|| invalid(format!("{} index", vitag)))?;
+    let asegs = index::parse(&ahead, audio).ok_or_else(|| invalid(format!("{} index", aitag)))?;
+    let vsrc = chunk::source(client, vid, video);
+    let asrc = chunk::source(client, vid, audio);
+    let parts = interleave(init.len() as u64, (vsrc, vsegs), (asrc, asegs));
+    let len = parts.last().map_or(init.len() as u64, |p| p.out + p.size);
+    let webm = ext == "webm";
+    let client = client.clone();
+    let body = stream::unfold(
+        (client, parts.into_iter(), 0u32, None),
+        move |(client, mut parts, mut seq, mut cur)| async move {
+            loop {
+                let Some((body, patcher)) = cur.as_mut() else {
+                    let part = parts.next()?;
+                    let (start, end) = (part.offset, part.offset + part.size - 1);
+                    match chunk::open(part.src.clone(), start, end, limit::FILE, 1).await {
+                        Ok((_, body)) => cur = Some((body, Patcher::new(part, webm))),
+                        // 出错后不再继续
+                        Err(e) => {
+                            return Some((Err(e), (client, Vec::new().into_iter(), seq, None)));
+                        }
+                    }
+                    continue;
+                };
+                let res = match body.next().await {
+                    Some(Ok(b)) => patcher.feed(b, &mut seq),
+                    Some(Err(e)) => Err(e),
+                    None => {
+                        let res = patcher.finish();
+                        cur = None;
+                        match res {
+                            Ok(()) => continue,
+                            Err(e) => Err(e),
+                        }
+                    }
+                };
+                match res {
+                    Ok(b) if b.is_empty() => continue,
+                    Ok(b) => return Some((Ok(b), (client, parts, seq, cur))),
+                    Err(e) => return Some((Err(e), (client, Vec::new().into_iter(), seq, None))),
+                }
+            }
+        },
+    );
+    let etag = match (
+        util::query_param(&video.url, "lmt"),
+        util::query_param(&audio.url, "lmt"),
+    ) {
+        (Some(v), Some(a)) => Some(format!("\"{}+{}-{}-{}\"", vitag, aitag, v, a)),
+        _ => None,
+    };
+    Ok(Muxed {
+        content_type: if webm { "video/webm" } else { "video/mp4" },
+        len,
+        etag,
+        title: info.title.clone(),
+        label: video.label(),
+        body: stream::once(async move { Ok(Bytes::from(init)) }).chain(body),
+    })
+}
+
+fn interleave(
+    start: u64,
+    video: (Source, Vec<Segment>),
+    audio: (Source, Vec<Segment>),
+) -> Vec<Part> {
+    let mut all: Vec<(u32, &Source, Segment)> = video
+        .1
+        .into_iter()
+        .map(|s| (VIDEO, &video.0, s))
+        .chain(audio.1.into_iter().map(|s| (AUDIO, &audio.0, s)))
+        .collect();
+    all.sort_by(|a, b| a.2.start.total_cmp(&b.2.start).then(a.0.cmp(&b.0)));
+    let mut out = start;
+    all.into_iter()
+        .map(|(track, src, s)| {
+            let part = Part {
+                src: src.clone(),
+                track,
+                offset: s.offset,
+                size: s.size,
+                out,
+            };
+            out += s.size;
+            part
+        })
+        .collect()
+}
+
+// 分片中下一个element或atom的处理方式
+enum Next {
+    // 完整读取后改写, 长度包括头部
+    Patch(usize),
+    // 只输出头部, 继续处理其中的子element
+    Descend(usize),
+    // 直接输出, 长度包括头部
+    Pass(u64),
+    // 长度为0的atom, 直到分片结束都直接输出
+    Rest,
+}
+
+// 流式改写一个分片: fMP4只需完整读取moof, mdat直接输出; WebM只需完整读取每个Block
+struct Patcher {
+    part: Part,
+    webm: bool,
+    buf: Vec<u8>,
+    // 之后直接输出的字节数
+    pass: u64,
+    // 遇到长度为0的atom后, 剩余的数据都直接输出, 不计入pass
+    to_end: bool,
+}
+
+fn malformed(part: &Part, msg: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{} at {}", msg, part.offset),
+    )
+}
+
+impl Patcher {
+    fn new(part: Part, webm: bool) -> Self {
+        Patcher {
+            part,
+            webm,
+            buf: Vec::new(),
+            pass: 0,
+            to_end: false,
+        }
+    }
+
+    fn feed(&mut self, b: Bytes, seq: &mut u32) -> Result<Bytes, io::Error> {
+        if self.to_end {
+            return Ok(b);
+        }
+        if self.buf.is_empty() && self.pass >= b.len() as u64 {
+            self.pass -= b.len() as u64;
+            return Ok(b);
+        }
+        self.buf.extend_from_slice(&b);
+        let mut out = Vec::new();
+        loop {
+            if self.pass > 0 {
+                let n = self.pass.min(self.buf.len() as u64) as usize;
+                if n == 0 {
+                    break;
+                }
+                out.extend(self.buf.drain(..n));
+                self.pass -= n as u64;
+                continue;
+            }
+            let next = match self.webm {
+                true => self.webm_next()?,
+                false => self.mp4_next()?,
+            };
+            match next {
+                None => break,
+                Some(Next::Pass(size)) => self.pass = size,
+                Some(Next::Rest) => {
+                    self.to_end = true;
+                    out.append(&mut self.buf);
+                    break;
+                }
+                Some(Next::Descend(header)) => out.extend(self.buf.drain(..header)),
+                Some(Next::Patch(size)) => {
+                    if self.buf.len() < size {
+                        break;
+                    }
+                    let mut el: Vec<u8> = self.buf.drain(..size).collect();
+                    match self.webm {
+                        true => patch_block(&mut el, self.part.track as u8),
+                        false => patch_fragments(&mut el, &self.part, seq)
+                            .ok_or_else(|| malformed(&self.part, "base_data_offset"))?,
+                    }
+                    out.extend(el);
+                }
+            }
+        }
+        Ok(Bytes::from(out))
+    }
+
+    // 分片结束时不应有未处理完的数据
+    fn finish(&self) -> Result<(), io::Error> {
+        if !self.buf.is_empty() || self.pass > 0 {
+            return Err(malformed(&self.part, "truncated"));
+        }
+        Ok(())
+    }
+
+    fn mp4_next(&self) -> Result<Option<Next>, io::Error> {
+        let buf = &self.buf;
+        let (Some(size), Some(kind)) = (mp4::u32_at(buf, 0), buf.get(4..8)) else {
+            return Ok(None);
+        };
+        let (size, header) = match size {
+            0 => return Ok(Some(Next::Rest)),
+            1 => match mp4::u64_at(buf, 8) {
+                Some(size) => (size, 16),
+                None => return Ok(None),
+            },
+            size => (size as u64, 8),
+        };
+        if size < header {
+            return Err(malformed(&self.part, "atom size"));
+        }
+        Ok(Some(match kind {
+            b"moof" => Next::Patch(size as usize),
+            _ => Next::Pass(size),
+        }))
+    }
+
+    fn webm_next(&self) -> Result<Option<Next>, io::Error> {
+        let buf = &self.buf;
+        if buf.first() == Some(&0) {
+            return Err(malformed(&self.part, "element id"));
+        }
+        let Some((id, id_len)) = webm::vint(buf, true) else {
+            return Ok(None);
+        };
+        if buf.get(id_len) == Some(&0) {
+            return Err(malformed(&self.part, "element size"));
+        }
+        let Some((size, size_len)) = buf.get(id_len..).and_then(|b| webm::vint(b, false)) else {
+            return Ok(None);
+        };
+        let header = id_len + size_len;
+        let unknown = size == (1 << (7 * size_len)) - 1;
+        if id as u32 == webm::CLUSTER {
+            return Ok(Some(Next::Descend(header)));
+        }
+        if unknown {
+            return Err(malformed(&self.part, "unknown size"));
+        }
+        Ok(Some(match id as u32 {
+            webm::SIMPLE_BLOCK | webm::BLOCK_GROUP => Next::Patch(header + size as usize),
+            _ => Next::Pass(header as u64 + size),
+        }))
+    }
+}
+
+// udta为下载时的元数据
+fn mp4_init(video: &[u8], audio: &[u8], udta: Vec<u8>) -> Option<Vec<u8>> {
+    let ftyp = mp4::find(video, b"ftyp")?;
+    let vmoov = mp4::find(video, b"moov")?.data;
+    let amoov = mp4::find(audio, b"moov")?.data;
+    let vmvhd = mp4::find(vmoov, b"mvhd")?.data;
+    let scale = timescale(vmvhd)?;
+    let ascale = timescale(mp4::find(amoov, b"mvhd")?.data)?;
+
+    let mut mvhd = vmvhd.to_vec();
+    let n = mvhd.len();
+    mvhd[n - 4..].copy_from_slice(&(AUDIO + 1).to_be_bytes());
+    let mut moov = mp4::atom(b"mvhd", &mvhd);
+    moov.extend(trak(mp4::find(vmoov, b"trak")?.data, VIDEO, scale, scale)?);
+    moov.extend(trak(mp4::find(amoov, b"trak")?.data, AUDIO, scale, ascale)?);
+    let mut mvex = Vec::new();
+    let vmvex = mp4::find(vmoov, b"mvex")?.data;
+    if let Some(mehd) = mp4::find(vmvex, b"mehd") {
+        mvex.extend_from_slice(mehd.raw);
+    }
+    mvex.extend(trex(mp4::find(vmvex, b"trex")?.data, VIDEO)?);
+    mvex.extend(trex(
+        mp4::find(mp4::find(amoov, b"mvex")?.data, b"trex")?.data,
+        AUDIO,
+    )?);
+    moov.extend(mp4::atom(b"mvex", &mvex));
+    moov.extend(udta);
+
+    let mut out = ftyp.raw.to_vec();
+    out.extend(mp4::atom(b"moov", &moov));
+    Some(out)
+}
+
+fn timescale(mvhd: &[u8]) -> Option<u32> {
+    let pos = if *mvhd.first()? == 1 { 20 } else { 12 };
+    mp4::u32_at(mvhd, pos)
+}
+
+fn rescale(v: u64, to: u32, from: u32) -> u64 {
+    (v as u128 * to as u128 / from.max(1) as u128) as u64
+}
+
+// 修改track_ID, 并将以movie timescale计的时长换算到新的timescale
+fn trak(data: &[u8], id: u32, to: u32, from: u32) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    for a in mp4::atoms(data) {
+        match &a.kind {
+            b"tkhd" => {
+                let mut d = a.data.to_vec();
+                if *d.first()? == 1 {
+                    d.get_mut(20..24)?.copy_from_slice(&id.to_be_bytes());
+                    let v = rescale(mp4::u64_at(&d, 28)?, to, from);
+                    d.get_mut(28..36)?.copy_from_slice(&v.to_be_bytes());
+                } else {
+                    d.get_mut(12..16)?.copy_from_slice(&id.to_be_bytes());
+                    let v = rescale(mp4::u32_at(&d, 20)? as u64, to, from) as u32;
+                    d.get_mut(20..24)?.copy_from_slice(&v.to_be_bytes());
+                }
+                out.extend(mp4::atom(b"tkhd", &d));
+            }
+            b"edts" => {
+                let Some(elst) = mp4::find(a.data, b"elst") else {
+                    continue;
+                };
+                let mut d = elst.data.to_vec();
+                let v1 = *d.first()? == 1;
+                let count = mp4::u32_at(&d, 4)? as usize;
+                let step = if v1 { 20 } else { 12 };
+                for i in 0..count {
+                    let pos = 8 + i * step;
+                    if v1 {
+                        let v = rescale(mp4::u64_at(&d, pos)?, to, from);
+                        d.get_mut(pos..pos + 8)?.copy_from_slice(&v.to_be_bytes());
+                    } else {
+                        let v = rescale(mp4::u32_at(&d, pos)? as u64, to, from) as u32;
+                        d.get_mut(pos..pos + 4)?.copy_from_slice(&v.to_be_bytes());
+                    }
+                }
+                out.extend(mp4::atom(b"edts", &mp4::atom(b"elst", &d)));
+            }
+            _ => out.extend_from_slice(a.raw),
+        }
+    }
+    Some(mp4::atom(b"trak", &out))
+}
+
+fn trex(data: &[u8], id: u32) -> Option<Vec<u8>> {
+    let mut d = data.to_vec();
+    d.get_mut(4..8)?.copy_from_slice(&id.to_be_bytes());
+    Some(mp4::atom(b"trex", &d))
+}
+
+// 重写moof中的sequence_number与track_ID, 以及绝对的base_data_offset
+// base_data_offset小于分片的偏移时返回None
+fn patch_fragments(buf: &mut [u8], part: &Part, seq: &mut u32) -> Option<()> {
+    let mut patches: Vec<(usize, Vec<u8>)> = Vec::new();
+    for moof in mp4::atoms(buf).filter(|a| &a.kind == b"moof") {
+        let base = moof.offset + moof.header;
+        for child in mp4::atoms(moof.data) {
+            let pos = base + child.offset + child.header;
+            match &child.kind {
+                b"mfhd" => {
+                    *seq += 1;
+                    patches.push((pos + 4, seq.to_be_bytes().to_vec()));
+                }
+                b"traf" => {
+                    let Some(tfhd) = mp4::find(child.data, b"tfhd") else {
+                        continue;
+                    };
+                    let pos = pos + tfhd.offset + tfhd.header;
+                    patches.push((pos + 4, part.track.to_be_bytes().to_vec()));
+                    let flags = mp4::u32_at(tfhd.data, 0).unwrap_or_default() & 0xffffff;
+                    if flags & 1 != 0
+                        && let Some(v) = mp4::u64_at(tfhd.data, 8)
+                    {
+                        let v = v.checked_sub(part.offset)? + part.out;
+                        patches.push((pos + 8, v.to_be_bytes().to_vec()));
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    for (pos, bytes) in patches {
+        if let Some(dst) = buf.get_mut(pos..pos + bytes.len()) {
+            dst.copy_from_slice(&bytes);
+        }
+    }
+    Some(())
+}
+
+// tags为下载时的元数据, 位于Tracks之后
+fn webm_init(video: &[u8], audio: &[u8], tags: Vec<u8>) -> Option<Vec<u8>> {
+    let vseg = webm::find(video, webm::SEGMENT)?;
+    let aseg = webm::find(audio, webm::SEGMENT)?;
+    let vinfo = webm::find(vseg.data, webm::INFO)?;
+    let ainfo = webm::find(aseg.data, webm::INFO)?;
+    let scale = |info: &[u8]| webm::find(info, webm::TIMECODE_SCALE).map(|e| webm::uint(e.data));
+    // cluster的时间戳直接复用, 两者的TimecodeScale必须一致
+    if scale(vinfo.data) != scale(ainfo.data) {
+        return None;
+    }
+    let ventry = webm::find(webm::find(vseg.data, webm::TRACKS)?.data, webm::TRACK_ENTRY)?;
+    let aentry = webm::find(webm::find(aseg.data, webm::TRACKS)?.data, webm::TRACK_ENTRY)?;
+    let mut tracks = track_entry(ventry.data, VIDEO as u64);
+    tracks.extend(track_entry(aentry.data, AUDIO as u64));
+
+    let mut out = webm::find(video, webm::EBML)?.raw.to_vec();
+    out.extend(webm::id_bytes(webm::SEGMENT));
+    out.extend(webm::UNKNOWN_SIZE);
+    out.extend_from_slice(vinfo.raw);
+    out.extend(webm::element(webm::TRACKS, &tracks));
+    out.extend(tags);
+    Some(out)
+}
+
+fn track_entry(data: &[u8], number: u64) -> Vec<u8> {
+    let mut out = Vec::new();
+    for el in webm::elements(data) {
+        match el.id {
+            webm::TRACK_NUMBER => out.extend(webm::uint_element(webm::TRACK_NUMBER, number)),
+            webm::TRACK_UID => out.extend(webm::uint_element(webm::TRACK_UID, number)),
+            _ => out.extend_from_slice(el.raw),
+        }
+    }
+    webm::element(webm::TRACK_ENTRY, &out)
+}
+
+// 源文件都只有track 1, 改写SimpleBlock或BlockGroup中Block的track number
+fn patch_block(el: &mut [u8], track: u8) {
+    let Some(block) = webm::elements(el).next() else {
+        return;
+    };
+    let base = block.header;
+    let pos = match block.id {
+        webm::BLOCK_GROUP => match webm::find(block.data, webm::BLOCK) {
+            Some(b) => base + b.offset + b.header,
+            None => return,
+        },
+        _ => base,
+    };
+    // 1字节的vint才能原地修改, YouTube的track number总是1
+    if let Some(b) = el.get_mut(pos)
+        && *b & 0x80 != 0
+    {
+        *b = 0x80 | track;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source() -> Source {
+        Source {
+            url: String::new(),
+            refresh: None,
+        }
+    }
+
+    fn part(track: u32, offset: u64, out: u64) -> Part {
+        Part {
+            src: source(),
+            track,
+            offset,
+            size: 0,
+            out,
+        }
+    }
+
+    // styp + moof(mfhd, traf(tfhd带base_data_offset)) + mdat
+    fn fragment(base: u64) -> Vec<u8> {
+        let mfhd = mp4::atom(b"mfhd", &[0, 0, 0, 0, 0, 0, 0, 9]);
+        let mut tfhd = vec![0, 0, 0, 1, 0, 0, 0, 1];
+        tfhd.extend_from_slice(&base.to_be_bytes());
+        let traf = mp4::atom(b"traf", &mp4::atom(b"tfhd", &tfhd));
+        let mut out = mp4::atom(b"styp", b"msdh");
+        out.extend(mp4::atom(b"moof", &[mfhd, traf].concat()));
+        out.extend(mp4::atom(b"mdat", &[7; 300]));
+        out
+    }
+
+    fn cluster() -> Vec<u8> {
+        let block = [0x81, 0, 0, 0x80, 1, 2, 3];
+        let group = webm::element(webm::BLOCK_GROUP, &webm::element(webm::BLOCK, &block));
+        let payload = [
+            webm::uint_element(0xE7, 0),
+            webm::element(webm::SIMPLE_BLOCK, &block),
+            group,
+        ];
+        webm::element(webm::CLUSTER, &payload.concat())
+    }
+
+    fn run(part: Part, webm: bool, data: &[u8], step: usize) -> Result<Vec<u8>, io::Error> {
+        let mut patcher = Patcher::new(part, webm);
+        let mut seq = 0;
+        let mut out = Vec::new();
+        for c in data.chunks(step) {
+            out.extend(patcher.feed(Bytes::copy_from_slice(c), &mut seq)?);
+        }
+        patcher.finish()?;
+        Ok(out)
+    }
+
+    #[test]
+    fn patch_mp4() {
+        let data = fragment(1000);
+        let mut expected = data.clone();
+        let mut seq = 0;
+        patch_fragments(&mut expected, &part(AUDIO, 1000, 5000), &mut seq).unwrap();
+        let moof = mp4::find(&expected, b"moof").unwrap();
+        let traf = mp4::find(moof.data, b"traf").unwrap();
+        let tfhd = mp4::find(traf.data, b"tfhd").unwrap();
+        assert_eq!(
+            mp4::u32_at(mp4::find(moof.data, b"mfhd").unwrap().data, 4),
+            Some(1)
+        );
+        assert_eq!(mp4::u32_at(tfhd.data, 4), Some(AUDIO));
+        assert_eq!(mp4::u64_at(tfhd.data, 8), Some(5000));
+        for step in [1, 7, 100, 4096] {
+            let out = run(part(AUDIO, 1000, 5000), false, &data, step).unwrap();
+            assert_eq!(out, expected, "step {}", step);
+        }
+    }
+
+    #[test]
+    fn patch_mp4_bad_offset() {
+        let data = fragment(999);
+        assert!(run(part(AUDIO, 1000, 5000), false, &data, 64).is_err());
+    }
+
+    #[test]
+    fn patch_mp4_truncated() {
+        let data = fragment(1000);
+        assert!(run(part(AUDIO, 1000, 0), false, &data[..data.len() - 1], 64).is_err());
+        assert!(run(part(AUDIO, 1000, 0), false, &data[..40], 64).is_err());
+    }
+
+    #[test]
+    fn patch_mp4_open_mdat() {
+        // 最后的mdat长度为0, 直到分片结束
+        let mut data = fragment(1000);
+        let mdat = data.len() - 308;
+        data[mdat..mdat + 4].copy_from_slice(&[0; 4]);
+        let mut expected = data.clone();
+        let mut seq = 0;
+        patch_fragments(&mut expected, &part(AUDIO, 1000, 5000), &mut seq).unwrap();
+        for step in [1, 7, 100, 4096] {
+            let out = run(part(AUDIO, 1000, 5000), false, &data, step).unwrap();
+            assert_eq!(out, expected, "step {}", step);
+        }
+    }
+
+    #[test]
+    fn patch_webm() {
+        let data = cluster();
+        for step in [1, 3, 17] {
+            let out = run(part(AUDIO, 0, 0), true, &data, step).unwrap();
+            assert_eq!(out.len(), data.len());
+            let c = webm::find(&out, webm::CLUSTER).unwrap();
+            let simple = webm::find(c.data, webm::SIMPLE_BLOCK).unwrap();
+            let group = webm::find(c.data, webm::BLOCK_GROUP).unwrap();
+            let block = webm::find(group.data, webm::BLOCK).unwrap();
+            assert_eq!((simple.data[0], block.data[0]), (0x82, 0x82));
+            // 其余字节不变
+            let changed = out.iter().zip(&data).filter(|(a, b)| a != b).count();
+            assert_eq!(changed, 2);
+        }
+        assert!(run(part(AUDIO, 0, 0), true, &data[..data.len() - 2], 5).is_err());
+    }
+
+    #[test]
+    fn interleave_by_time() {
+        let seg = |start: f64, size: u64| Segment {
+            start,
+            duration: 1.0,
+            offset: size * 10,
+            size,
+        };
+        let video = vec![seg(0.0, 100), seg(2.0, 200)];
+        let audio = vec![seg(0.0, 10), seg(1.0, 20), seg(2.0, 30)];
+        let parts = interleave(50, (source(), video), (source(), audio));
+        let order: Vec<(u32, u64, u64)> = parts.iter().map(|p| (p.track, p.size, p.out)).collect();
+        assert_eq!(
+            order,
+            vec![
+                (VIDEO, 100, 50),
+                (AUDIO, 10, 150),
+                (AUDIO, 20, 160),
+                (VIDEO, 200, 180),
+                (AUDIO, 30, 380),
+            ]
+        );
+    }
+}
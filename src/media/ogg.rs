@@ -0,0 +1,541 @@
+use actix_web::web::{self, Bytes};
+use awc::Client;
+use futures_util::stream::{self, Stream, StreamExt};
+use std::error;
+use std::io;
+
+use super::index::{self, Segment};
+use super::tags::Tags;
+use super::webm;
+use crate::parser::StreamItem;
+use crate::upstream::chunk::{self, Source};
+use crate::upstream::limit;
+
+// 每页最多的Opus包数量, 20ms一包约为1秒
+const PAGE_PACKETS: usize = 50;
+
+// 没有CodecPrivate时OpusHead使用的pre-skip
+const PRE_SKIP: u16 = 312;
+
+const SERIAL: u32 = 0x7670_7273;
+
+// Ogg的crc32, 多项式0x04c11db7, 不反转, 初始值为0
+const CRC: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut r = (i as u32) << 24;
+        let mut j = 0;
+        while j < 8 {
+            r = if r & 0x8000_0000 != 0 {
+                (r << 1) ^ 0x04c1_1db7
+            } else {
+                r << 1
+            };
+            j += 1;
+        }
+        table[i] = r;
+        i += 1;
+    }
+    table
+};
+
+fn invalid(msg: String) -> Box<dyn error::Error> {
+    Box::new(io::Error::new(io::ErrorKind::InvalidData, msg))
+}
+
+// 将WebM的Opus音频转为Ogg Opus, 不转码
+// 按Cues逐个获取Cluster, 每个Block作为一个Ogg包, 页的大小取决于每个包的长度, 因此无法预先得到总长度
+pub async fn remux(
+    client: &web::Data<Client>,
+    vid: &str,
+    item: &StreamItem,
+    tags: Option<&Tags>,
+) -> Result<impl Stream<Item = Result<Bytes, io::Error>> + use<>, Box<dyn error::Error>> {
+    if item.ext() != "webm" || item.mime().1 != "opus" || item.index().is_none() {
+        return Err(invalid(format!("{} is not adaptive opus", item.itag)));
+    }
+    let data = index::head(client, item).await?;
+    let segments =
+        index::parse(&data, item).ok_or_else(|| invalid(format!("{} index", item.itag)))?;
+    let head = opus_head(&data, item);
+    let mut writer = Writer {
+        seq: 0,
+        granule: 0,
+        last: 0,
+    };
+    let mut init = Vec::new();
+    writer.page(&mut init, &[&head], 0x02);
+    let comments = tags.map(|t| t.vorbis()).unwrap_or_default();
+    writer.page(&mut init, &[&opus_tags(&comments)], 0);
+    let client = client.clone();
+    let src = chunk::source(&client, vid, item);
+    let count = segments.len();
+    let body = stream::unfold(
+        (client, src, segments.into_iter().enumerate(), writer),
+        move |(client, src, mut segments, mut writer)| async move {
+            let (i, seg) = segments.next()?;
+            let res = fetch(&src, &seg).await.and_then(|data| {
+                let (packets, padding) = packets(&data).ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("cluster at {}", seg.offset),
+                    )
+                })?;
+                let trim = match i + 1 == count {
+                    true => Some(padding),
+                    false => None,
+                };
+                Ok(Bytes::from(writer.pages(&packets, trim)))
+            });
+            // 出错后不再继续
+            if res.is_err() {
+                segments = Vec::new().into_iter().enumerate();
+            }
+            Some((res, (client, src, segments, writer)))
+        },
+    );
+    Ok(stream::once(async move { Ok(Bytes::from(init)) }).chain(body))
+}
+
+// 整个Cluster解析后才能分页, 读取完整的Cluster
+async fn fetch(src: &Source, seg: &Segment) -> Result<Vec<u8>, io::Error> {
+    let end = seg.offset + seg.size - 1;
+    chunk::read(src.clone(), seg.offset, end, limit::FILE).await
+}
+
+// CodecPrivate即为OpusHead, 没有时按声道数及CodecDelay生成
+fn opus_head(data: &[u8], item: &StreamItem) -> Vec<u8> {
+    let entry = webm::find(data, webm::SEGMENT)
+        .and_then(|s| webm::find(s.data, webm::TRACKS))
+        .and_then(|t| webm::find(t.data, webm::TRACK_ENTRY));
+    if let Some(private) = entry
+        .as_ref()
+        .and_then(|e| webm::find(e.data, webm::CODEC_PRIVATE))
+        && private.data.starts_with(b"OpusHead")
+    {
+        return private.data.to_vec();
+    }
+    // CodecDelay为纳秒
+    let pre_skip = entry
+        .as_ref()
+        .and_then(|e| webm::find(e.data, webm::CODEC_DELAY))
+        .map_or(PRE_SKIP, |d| samples(webm::uint(d.data) as i64) as u16);
+    let channels = entry
+        .and_then(|e| webm::find(e.data, webm::AUDIO))
+        .and_then(|a| webm::find(a.data, webm::CHANNELS))
+        .map(|c| webm::uint(c.data))
+        .or(item.channels)
+        .unwrap_or(2);
+    let mut head = b"OpusHead\x01".to_vec();
+    head.push(channels as u8);
+    head.extend_from_slice(&pre_skip.to_le_bytes());
+    head.extend_from_slice(&(item.sample_rate.unwrap_or(48000) as u32).to_le_bytes());
+    head.extend_from_slice(&[0, 0, 0]);
+    head
+}
+
+fn opus_tags(comments: &[String]) -> Vec<u8> {
+    let vendor = b"videoproxy";
+    let mut tags = b"OpusTags".to_vec();
+    tags.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
+    tags.extend_from_slice(vendor);
+    tags.extend_from_slice(&(comments.len() as u32).to_le_bytes());
+    for c in comments {
+        tags.extend_from_slice(&(c.len() as u32).to_le_bytes());
+        tags.extend_from_slice(c.as_bytes());
+    }
+    tags
+}
+
+// 纳秒转为48kHz的sample数, 负数为0
+fn samples(ns: i64) -> u64 {
+    (ns.max(0) as u128 * 48000 / 1_000_000_000) as u64
+}
+
+// Cluster中所有Block的帧, 以及最后一个Block的DiscardPadding(sample数)
+fn packets(data: &[u8]) -> Option<(Vec<&[u8]>, u64)> {
+    let cluster = webm::find(data, webm::CLUSTER)?;
+    let mut res = Vec::new();
+    let mut padding = 0;
+    for el in webm::elements(cluster.data) {
+        let block = match el.id {
+            webm::SIMPLE_BLOCK => el.data,
+            webm::BLOCK_GROUP => match webm::find(el.data, webm::BLOCK) {
+                Some(b) => b.data,
+                None => continue,
+            },
+            _ => continue,
+        };
+        padding = match el.id {
+            webm::BLOCK_GROUP => {
+                webm::find(el.data, webm::DISCARD_PADDING).map_or(0, |d| samples(webm::int(d.data)))
+            }
+            _ => 0,
+        };
+        res.extend(frames(block)?);
+    }
+    Some((res, padding))
+}
+
+// Block的数据, 处理Xiph/EBML/固定长度三种lacing
+fn frames(block: &[u8]) -> Option<Vec<&[u8]>> {
+    let (_, n) = webm::vint(block, false)?;
+    let flags = *block.get(n + 2)?;
+    let data = block.get(n + 3..)?;
+    let lacing = (flags >> 1) & 3;
+    if lacing == 0 {
+        return Some(vec![data]);
+    }
+    let count = *data.first()? as usize + 1;
+    let mut pos = 1;
+    let mut sizes = Vec::with_capacity(count);
+    match lacing {
+        1 => {
+            for _ in 1..count {
+                let mut size = 0;
+                loop {
+                    let b = *data.get(pos)?;
+                    pos += 1;
+                    size += b as usize;
+                    if b != 255 {
+                        break;
+                    }
+                }
+                sizes.push(size);
+            }
+        }
+        3 => {
+            let (first, len) = webm::vint(data.get(pos..)?, false)?;
+            pos += len;
+            let mut size = first as i64;
+            sizes.push(first as usize);
+            for _ in 2..count {
+                let (v, len) = webm::vint(data.get(pos..)?, false)?;
+                pos += len;
+                // 有符号vint, 减去中间值
+                size += v as i64 - ((1i64 << (7 * len - 1)) - 1);
+                sizes.push(usize::try_from(size).ok()?);
+            }
+        }
+        _ => sizes = vec![(data.len() - 1) / count; count - 1],
+    }
+    let used: usize = sizes.iter().sum();
+    sizes.push((data.len() - pos).checked_sub(used)?);
+    let mut res = Vec::with_capacity(count);
+    for size in sizes {
+        res.push(data.get(pos..pos + size)?);
+        pos += size;
+    }
+    Some(res)
+}
+
+// Opus包的时长, 48kHz下的sample数, 由TOC字节得到
+fn duration(packet: &[u8]) -> u64 {
+    let Some(toc) = packet.first() else {
+        return 0;
+    };
+    let config = (toc >> 3) as usize;
+    let frame = match config {
+        0..=11 => [480, 960, 1920, 2880][config % 4],
+        12..=15 => [480, 960][config % 2],
+        _ => [120, 240, 480, 960][config % 4],
+    };
+    let count = match toc & 3 {
+        0 => 1,
+        1 | 2 => 2,
+        _ => packet.get(1).map_or(0, |v| v & 0x3f) as u64,
+    };
+    frame * count
+}
+
+struct Writer {
+    seq: u32,
+    // 已输出的sample数
+    granule: u64,
+    // 上一个有包结束的页的granule
+    last: u64,
+}
+
+impl Writer {
+    // 将一个Cluster的包分页输出, 最后一个Cluster时trim为末尾需要丢弃的sample数, 最后一页标记为结束
+    // 超过65025字节的包需要的lacing值超过255个, 单独成页后由page拆分到多页
+    fn pages(&mut self, packets: &[&[u8]], trim: Option<u64>) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut page: Vec<&[u8]> = Vec::new();
+        let mut lacing = 0;
+        for p in packets {
+            let n = p.len() / 255 + 1;
+            if !page.is_empty() && (lacing + n > 255 || page.len() >= PAGE_PACKETS) {
+                self.page(&mut out, &page, 0);
+                page.clear();
+                lacing = 0;
+            }
+            self.granule += duration(p);
+            page.push(p);
+            lacing += n;
+        }
+        match trim {
+            Some(trim) => {
+                // 结束页的granule小于包的总时长时, 解码器丢弃多出的部分, 但不能早于上一页
+                self.granule = self.granule.saturating_sub(trim).max(self.last);
+                self.page(&mut out, &page, 0x04);
+            }
+            None if !page.is_empty() => self.page(&mut out, &page, 0),
+            None => {}
+        }
+        out
+    }
+
+    // granule为页中最后一个包结束时的位置, 调用前已累加
+    // 超过255个lacing值时拆分为多页, 包跨页时后续页标记为continued, 没有包结束的页granule为-1
+    fn page(&mut self, out: &mut Vec<u8>, packets: &[&[u8]], flags: u8) {
+        let mut lacing = Vec::new();
+        for p in packets {
+            lacing.extend(std::iter::repeat_n(255, p.len() / 255));
+            lacing.push((p.len() % 255) as u8);
+        }
+        let data = packets.concat();
+        let pages: Vec<&[u8]> = match lacing.is_empty() {
+            true => vec![&[]],
+            false => lacing.chunks(255).collect(),
+        };
+        let mut pos = 0;
+        let mut continued = false;
+        for (i, segments) in pages.iter().enumerate() {
+            let mut page_flags = flags;
+            if i > 0 {
+                page_flags &= !0x02;
+            }
+            if i + 1 < pages.len() {
+                page_flags &= !0x04;
+            }
+            if continued {
+                page_flags |= 0x01;
+            }
+            continued = segments.last() == Some(&255);
+            let granule = match continued {
+                true => u64::MAX,
+                false => self.granule,
+            };
+            if !continued {
+                self.last = granule;
+            }
+            let size: usize = segments.iter().map(|v| *v as usize).sum();
+            let start = out.len();
+            out.extend_from_slice(b"OggS\0");
+            out.push(page_flags);
+            out.extend_from_slice(&granule.to_le_bytes());
+            out.extend_from_slice(&SERIAL.to_le_bytes());
+            out.extend_from_slice(&self.seq.to_le_bytes());
+            out.extend_from_slice(&[0; 4]);
+            out.push(segments.len() as u8);
+            out.extend_from_slice(segments);
+            out.extend_from_slice(&data[pos..pos + size]);
+            pos += size;
+            let crc = out[start..].iter().fold(0u32, |crc, b| {
+                (crc << 8) ^ CRC[((crc >> 24) as u8 ^ b) as usize]
+            });
+            out[start + 22..start + 26].copy_from_slice(&crc.to_le_bytes());
+            self.seq += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::media::webm::{element, uint_element};
+
+    // 轨道1, 相对时间0
+    fn block(flags: u8, data: &[u8]) -> Vec<u8> {
+        let mut b = vec![0x81, 0, 0, flags];
+        b.extend_from_slice(data);
+        b
+    }
+
+    fn crc(data: &[u8]) -> u32 {
+        data.iter().fold(0u32, |crc, b| {
+            (crc << 8) ^ CRC[((crc >> 24) as u8 ^ b) as usize]
+        })
+    }
+
+    struct Page {
+        flags: u8,
+        granule: u64,
+        seq: u32,
+        lacing: Vec<u8>,
+        data: Vec<u8>,
+    }
+
+    // 解析输出的页并检查CRC
+    fn parse(mut out: &[u8]) -> Vec<Page> {
+        let mut pages = Vec::new();
+        while !out.is_empty() {
+            assert_eq!(&out[..5], b"OggS\0");
+            let n = out[26] as usize;
+            let lacing = out[27..27 + n].to_vec();
+            let size: usize = lacing.iter().map(|v| *v as usize).sum();
+            let len = 27 + n + size;
+            let mut raw = out[..len].to_vec();
+            raw[22..26].fill(0);
+            assert_eq!(crc(&raw).to_le_bytes(), out[22..26]);
+            pages.push(Page {
+                flags: out[5],
+                granule: u64::from_le_bytes(out[6..14].try_into().unwrap()),
+                seq: u32::from_le_bytes(out[18..22].try_into().unwrap()),
+                lacing,
+                data: out[27 + n..len].to_vec(),
+            });
+            out = &out[len..];
+        }
+        pages
+    }
+
+    // 按lacing值重新组合出包
+    fn join(pages: &[Page]) -> Vec<Vec<u8>> {
+        let mut res = Vec::new();
+        let mut cur = Vec::new();
+        for p in pages {
+            let mut pos = 0;
+            for v in &p.lacing {
+                cur.extend_from_slice(&p.data[pos..pos + *v as usize]);
+                pos += *v as usize;
+                if *v < 255 {
+                    res.push(std::mem::take(&mut cur));
+                }
+            }
+        }
+        res
+    }
+
+    fn writer() -> Writer {
+        Writer {
+            seq: 0,
+            granule: 0,
+            last: 0,
+        }
+    }
+
+    #[test]
+    fn crc_table() {
+        assert_eq!(crc(b"123456789"), 0x89A1_897F);
+    }
+
+    #[test]
+    fn lacing() {
+        let (a, b, c) = (vec![1u8; 300], vec![2u8; 5], vec![3u8; 7]);
+        let all = [a.clone(), b.clone(), c.clone()].concat();
+        let raw = block(0x80, &all);
+        assert_eq!(frames(&raw).unwrap(), vec![&all[..]]);
+        // Xiph
+        let mut data = vec![2, 255, 45, 5];
+        data.extend_from_slice(&all);
+        let raw = block(0x02, &data);
+        let f = frames(&raw).unwrap();
+        assert_eq!(f, vec![&a[..], &b[..], &c[..]]);
+        // EBML, 第二个长度为与前一个的差值5-300
+        let mut data = vec![2, 0x41, 0x2C, 0x5E, 0xD8];
+        data.extend_from_slice(&all);
+        let raw = block(0x06, &data);
+        let f = frames(&raw).unwrap();
+        assert_eq!(f, vec![&a[..], &b[..], &c[..]]);
+        // 固定长度
+        let mut data = vec![2];
+        data.extend_from_slice(&[4u8; 12]);
+        let raw = block(0x04, &data);
+        let f = frames(&raw).unwrap();
+        assert_eq!(f, vec![&[4u8; 4][..]; 3]);
+        // 长度超过数据时无效
+        let data = [2, 255, 255, 5, 0, 0];
+        assert!(frames(&block(0x02, &data)).is_none());
+    }
+
+    #[test]
+    fn toc_duration() {
+        assert_eq!(duration(&[]), 0);
+        // SILK 10ms, 20ms
+        assert_eq!(duration(&[0x00]), 480);
+        assert_eq!(duration(&[0x08]), 960);
+        // Hybrid 10ms
+        assert_eq!(duration(&[12 << 3]), 480);
+        // CELT 20ms, 两帧
+        assert_eq!(duration(&[31 << 3 | 1]), 1920);
+        // 任意帧数, 第二个字节的低6位为帧数
+        assert_eq!(duration(&[19 << 3 | 3, 0x43]), 2880);
+    }
+
+    #[test]
+    fn cluster_padding() {
+        let simple = element(webm::SIMPLE_BLOCK, &block(0x80, b"ab"));
+        let mut group = element(webm::BLOCK, &block(0, b"cd"));
+        // 6.5ms
+        group.extend(uint_element(webm::DISCARD_PADDING, 6_500_000));
+        let cluster = element(
+            webm::CLUSTER,
+            &[
+                uint_element(0xE7, 0),
+                simple.clone(),
+                element(webm::BLOCK_GROUP, &group),
+            ]
+            .concat(),
+        );
+        let (frames, padding) = packets(&cluster).unwrap();
+        assert_eq!(frames, vec![&b"ab"[..], &b"cd"[..]]);
+        assert_eq!(padding, 312);
+        // 只有最后一个Block的DiscardPadding有效
+        let cluster = element(
+            webm::CLUSTER,
+            &[element(webm::BLOCK_GROUP, &group), simple].concat(),
+        );
+        assert_eq!(packets(&cluster).unwrap().1, 0);
+        assert_eq!(samples(-1), 0);
+    }
+
+    #[test]
+    fn large_packets() {
+        for len in [65025, 70000] {
+            let p: Vec<u8> = (0..len).map(|i| (i % 7) as u8 | 0x08).collect();
+            let mut w = writer();
+            let out = w.pages(&[&p, b"\x08"], None);
+            let pages = parse(&out);
+            // 超过255个lacing值的包单独成页并拆分到两页, 之后的包在新的一页
+            assert_eq!(pages.len(), 3);
+            assert_eq!(pages[0].granule, u64::MAX);
+            assert_eq!(pages[0].flags, 0);
+            assert_eq!(pages[0].lacing.len(), 255);
+            assert_eq!(pages[1].flags, 0x01);
+            assert_eq!(pages[1].lacing.len(), (len - 65025) / 255 + 1);
+            assert_eq!(pages[1].granule, 960);
+            assert_eq!(pages[2].flags, 0);
+            assert_eq!(pages[2].granule, 1920);
+            let seqs: Vec<u32> = pages.iter().map(|p| p.seq).collect();
+            assert_eq!(seqs, vec![0, 1, 2]);
+            assert_eq!(join(&pages), vec![p, vec![0x08]]);
+            assert_eq!(w.last, 1920);
+        }
+    }
+
+    #[test]
+    fn trim_last_page() {
+        let p = [0x08u8, 0, 0];
+        let packets = vec![&p[..]; 60];
+        let mut w = writer();
+        let out = w.pages(&packets, None);
+        let pages = parse(&out);
+        // 每页最多PAGE_PACKETS个包
+        assert_eq!(pages.len(), 2);
+        assert_eq!(pages[0].granule, 50 * 960);
+        assert_eq!(pages[1].granule, 60 * 960);
+        let out = w.pages(&packets[..2], Some(1000));
+        let pages = parse(&out);
+        assert_eq!(pages[0].flags, 0x04);
+        assert_eq!(pages[0].granule, 62 * 960 - 1000);
+        // 丢弃的部分超过最后一页时不早于上一页
+        let mut w = writer();
+        w.pages(&packets[..1], None);
+        let out = w.pages(&packets[..1], Some(5000));
+        assert_eq!(parse(&out)[0].granule, 960);
+        assert_eq!(join(&parse(&out)), vec![p.to_vec()]);
+    }
+}
@@ -0,0 +1,118 @@
+use actix_web::web;
+use awc::Client;
+use std::error;
+use std::io;
+
+use super::index;
+use crate::parser::StreamItem;
+
+pub struct Seek {
+    // 实际开始的时间, 秒, 对齐到关键帧
+    pub start: f64,
+    // 需要先输出的init部分
+    pub init: Option<(u64, u64)>,
+    pub range: (u64, u64),
+}
+
+fn invalid(msg: String) -> Box<dyn error::Error> {
+    Box::new(io::Error::new(io::ErrorKind::InvalidData, msg))
+}
+
+// 将[start, end)秒映射为字节范围, 按sidx/Cues的分片对齐, 输出init加上对应的分片
+// progressive格式单独的字节范围无法播放, 由clip生成新的moov
+pub async fn seek(
+    client: &web::Data<Client>,
+    item: &StreamItem,
+    start: f64,
+    end: Option<f64>,
+) -> Result<Seek, Box<dyn error::Error>> {
+    if item.index().is_none() {
+        return Err(invalid(format!("{} can not seek", item.itag)));
+    }
+    let segments = index::segments(client, item).await?;
+    let first = segments.partition_point(|s| s.start <= start).max(1) - 1;
+    let last = match end {
+        Some(end) => segments.partition_point(|s| s.start < end).max(first + 1) - 1,
+        None => segments.len() - 1,
+    };
+    let (a, z) = (&segments[first], &segments[last]);
+    Ok(Seek {
+        start: a.start,
+        init: item.init().map(|(_, end)| (0, end)),
+        range: (a.offset, z.offset + z.size - 1),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::media::mp4::atom;
+    use crate::parser;
+    use actix_web::{App, HttpResponse, HttpServer};
+    use serde_json::json;
+
+    // ftyp及3个1秒的分片的sidx
+    fn head() -> Vec<u8> {
+        let mut d = vec![0; 4];
+        for w in [1u32, 1000, 0, 0, 3] {
+            d.extend_from_slice(&w.to_be_bytes());
+        }
+        for size in [1000u32, 2000, 3000] {
+            for w in [size, 1000, 0x9000_0000] {
+                d.extend_from_slice(&w.to_be_bytes());
+            }
+        }
+        let mut head = atom(b"ftyp", b"dash");
+        head.extend(atom(b"sidx", &d));
+        head
+    }
+
+    fn item(url: &str, index: bool) -> StreamItem {
+        let mut v = json!({
+            "itag": 137,
+            "mimeType": "video/mp4; codecs=\"avc1.640028\"",
+            "url": url,
+            "contentLength": "10000",
+            "initRange": {"start": "0", "end": "11"},
+        });
+        if index {
+            v["indexRange"] = json!({"start": "12", "end": "79"});
+        }
+        parser::stream_item(&v).1
+    }
+
+    #[actix_web::test]
+    async fn segments_range() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = format!("http://{}/seek?id=1", listener.local_addr().unwrap());
+        let server = HttpServer::new(|| {
+            App::new().default_service(web::to(|| async { HttpResponse::Ok().body(head()) }))
+        })
+        .workers(1)
+        .listen(listener)
+        .unwrap()
+        .run();
+        actix_web::rt::spawn(server);
+        let client = web::Data::new(Client::default());
+        let item = item(&addr, true);
+        // 分片从sidx之后开始, 依次为[80, 1080), [1080, 3080), [3080, 6080)
+        let s = seek(&client, &item, 1.5, Some(2.5)).await.unwrap();
+        assert_eq!(s.start, 1.0);
+        assert_eq!(s.init, Some((0, 11)));
+        assert_eq!(s.range, (1080, 6079));
+        let s = seek(&client, &item, 0.5, None).await.unwrap();
+        assert_eq!((s.start, s.range), (0.0, (80, 6079)));
+        // end不超过start所在的分片时至少输出一个分片
+        let s = seek(&client, &item, 2.0, Some(1.0)).await.unwrap();
+        assert_eq!((s.start, s.range), (2.0, (3080, 6079)));
+        let s = seek(&client, &item, 9.0, None).await.unwrap();
+        assert_eq!(s.range, (3080, 6079));
+    }
+
+    #[actix_web::test]
+    async fn progressive() {
+        let client = web::Data::new(Client::default());
+        let item = item("http://127.0.0.1:1/seek?id=2", false);
+        assert!(seek(&client, &item, 1.0, None).await.is_err());
+    }
+}
@@ -0,0 +1,402 @@
+use actix_web::web;
+use awc::Client;
+use std::error;
+use std::fmt::Write;
+use std::io;
+
+use crate::parser::{self, Caption};
+use crate::request;
+
+pub struct Cue {
+    // 秒
+    pub start: f64,
+    pub end: f64,
+    pub text: String,
+}
+
+// 时:分:秒.毫秒, SRT的毫秒前为逗号
+pub fn clock(seconds: f64, sep: char) -> String {
+    let ms = (seconds.max(0.0) * 1000.0).round() as u64;
+    format!(
+        "{:02}:{:02}:{:02}{}{:03}",
+        ms / 3_600_000,
+        ms / 60_000 % 60,
+        ms / 1000 % 60,
+        sep,
+        ms % 1000
+    )
+}
+
+// cue的编号从1开始
+pub fn vtt(cues: &[Cue]) -> String {
+    let mut out = String::from("WEBVTT\n\n");
+    for (i, c) in cues.iter().enumerate() {
+        let _ = write!(
+            out,
+            "{}\n{} --> {}\n{}\n\n",
+            i + 1,
+            clock(c.start, '.'),
+            clock(c.end, '.'),
+            // cue内容中的&<>需要转义
+            c.text
+                .replace('&', "&amp;")
+                .replace('<', "&lt;")
+                .replace('>', "&gt;")
+        );
+    }
+    out
+}
+
+pub fn srt(cues: &[Cue]) -> String {
+    let mut out = String::new();
+    for (i, c) in cues.iter().enumerate() {
+        let _ = write!(
+            out,
+            "{}\n{} --> {}\n{}\n\n",
+            i + 1,
+            clock(c.start, ','),
+            clock(c.end, ','),
+            c.text
+        );
+    }
+    out
+}
+
+fn invalid(kind: io::ErrorKind, msg: String) -> Box<dyn error::Error> {
+    Box::new(io::Error::new(kind, msg))
+}
+
+// 获取json3格式的timedtext并转为vtt或srt, 获取或解析失败时改用srv3格式, tlang不为空时由YouTube翻译
+pub async fn subtitle(
+    client: &web::Data<Client>,
+    vid: &String,
+    lang: &str,
+    ext: &str,
+    tlang: &str,
+    auto: bool,
+) -> Result<String, Box<dyn error::Error>> {
+    let info = parser::parse(client, vid).await?;
+    let Some(track) = track(&info.captions, lang, auto) else {
+        let msg = format!("no {} captions", lang);
+        return Err(invalid(io::ErrorKind::NotFound, msg));
+    };
+    // 去掉baseUrl中原有的fmt及tlang
+    let mut url: String = track
+        .url
+        .split('&')
+        .filter(|p| !p.starts_with("fmt=") && !p.starts_with("tlang="))
+        .collect::<Vec<&str>>()
+        .join("&");
+    if !tlang.is_empty() {
+        let valid =
+            tlang.len() <= 16 && tlang.chars().all(|c| c.is_ascii_alphanumeric() || c == '-');
+        if !valid || !track.translatable {
+            let msg = format!("{} can not be translated to {}", track.lang, tlang);
+            return Err(invalid(io::ErrorKind::InvalidInput, msg));
+        }
+        url.push_str("&tlang=");
+        url.push_str(tlang);
+    }
+    let json = format!("{}&fmt=json3", url);
+    let cues = match request::req_get_cache(client, &json, 3600, 8 << 20).await {
+        Ok(data) => json3(&data, track.auto),
+        Err(_) => None,
+    };
+    let cues = match cues {
+        Some(cues) => cues,
+        None => {
+            let url = format!("{}&fmt=srv3", url);
+            let data = request::req_get_cache(client, &url, 3600, 8 << 20).await?;
+            srv3(&data, track.auto)
+                .ok_or_else(|| invalid(io::ErrorKind::InvalidData, format!("{} timedtext", vid)))?
+        }
+    };
+    Ok(match ext {
+        "srt" => srt(&cues),
+        _ => vtt(&cues),
+    })
+}
+
+// 默认优先人工字幕, auto时优先自动生成的字幕; 同类中优先完全相同的语言代码, 其次主语言相同, 例如en可匹配en-US
+fn track<'a>(captions: &'a [Caption], lang: &str, auto: bool) -> Option<&'a Caption> {
+    let exact = |c: &&Caption| c.lang.eq_ignore_ascii_case(lang);
+    let primary = |c: &&Caption| {
+        let base = c.lang.split('-').next().unwrap_or_default();
+        base.eq_ignore_ascii_case(lang.split('-').next().unwrap_or_default())
+    };
+    let preferred = |c: &&Caption| c.auto == auto;
+    captions
+        .iter()
+        .filter(exact)
+        .find(preferred)
+        .or_else(|| captions.iter().filter(primary).find(preferred))
+        .or_else(|| captions.iter().find(exact))
+        .or_else(|| captions.iter().find(primary))
+}
+
+// events中带有segs的为字幕, 没有segs的为窗口定义
+fn json3(data: &[u8], auto: bool) -> Option<Vec<Cue>> {
+    let v: serde_json::Value = serde_json::from_slice(data).ok()?;
+    let mut cues: Vec<Cue> = Vec::new();
+    for e in v["events"].as_array()? {
+        let Some(segs) = e["segs"].as_array() else {
+            continue;
+        };
+        let text: String = segs.iter().filter_map(|s| s["utf8"].as_str()).collect();
+        let text = clean(&text);
+        if text.is_empty() {
+            continue;
+        }
+        let start = e["tStartMs"].as_f64().unwrap_or_default() / 1000.0;
+        let end = start + e["dDurationMs"].as_f64().unwrap_or_default() / 1000.0;
+        cues.push(Cue { start, end, text });
+    }
+    Some(finish(cues, auto))
+}
+
+// srv3为XML, body中每个p为一条字幕, t及d为毫秒; 自动生成的字幕中每个词为p中的一个s
+fn srv3(data: &[u8], auto: bool) -> Option<Vec<Cue>> {
+    let xml = std::str::from_utf8(data).ok()?;
+    let mut rest = &xml[xml.find("<body")?..];
+    let mut cues: Vec<Cue> = Vec::new();
+    while let Some(i) = rest.find("<p ") {
+        rest = &rest[i + 3..];
+        let close = rest.find('>')?;
+        let attrs = &rest[..close];
+        // 没有内容的p为窗口定义
+        if attrs.ends_with('/') {
+            rest = &rest[close + 1..];
+            continue;
+        }
+        let end = rest.find("</p>")?;
+        let text = clean(&unescape(&strip_tags(&rest[close + 1..end])));
+        rest = &rest[end + 4..];
+        if text.is_empty() {
+            continue;
+        }
+        let start = attr(attrs, "t").unwrap_or_default() / 1000.0;
+        let end = start + attr(attrs, "d").unwrap_or_default() / 1000.0;
+        cues.push(Cue { start, end, text });
+    }
+    Some(finish(cues, auto))
+}
+
+fn attr(attrs: &str, name: &str) -> Option<f64> {
+    attrs.split_whitespace().find_map(|a| {
+        a.strip_prefix(name)?
+            .strip_prefix("=\"")?
+            .strip_suffix('"')?
+            .parse()
+            .ok()
+    })
+}
+
+fn strip_tags(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut tag = false;
+    for c in s.chars() {
+        match c {
+            '<' => tag = true,
+            '>' if tag => tag = false,
+            _ if !tag => out.push(c),
+            _ => {}
+        }
+    }
+    out
+}
+
+// XML的预定义实体及数字字符引用, 无法识别的保留原文
+fn unescape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(i) = rest.find('&') {
+        out.push_str(&rest[..i]);
+        rest = &rest[i..];
+        let decoded = rest.find(';').and_then(|end| {
+            let c = match &rest[1..end] {
+                "amp" => '&',
+                "lt" => '<',
+                "gt" => '>',
+                "quot" => '"',
+                "apos" => '\'',
+                e => {
+                    let n = match e.strip_prefix("#x").or_else(|| e.strip_prefix("#X")) {
+                        Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                        None => e.strip_prefix('#')?.parse().ok()?,
+                    };
+                    char::from_u32(n)?
+                }
+            };
+            Some((c, end))
+        });
+        match decoded {
+            Some((c, end)) => {
+                out.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+// 去掉每行首尾的空白及空行, 空行会结束cue
+fn clean(text: &str) -> String {
+    text.lines()
+        .map(|l| l.trim())
+        .filter(|l| !l.is_empty())
+        .collect::<Vec<&str>>()
+        .join("\n")
+}
+
+// 自动生成的字幕逐行滚动, 相邻两行的时间重叠, 截断到下一行开始
+fn finish(mut cues: Vec<Cue>, auto: bool) -> Vec<Cue> {
+    if auto {
+        for i in 1..cues.len() {
+            let next = cues[i].start;
+            let prev = &mut cues[i - 1];
+            prev.end = prev.end.min(next).max(prev.start);
+        }
+    }
+    cues
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn caption(lang: &str, auto: bool) -> Caption {
+        Caption {
+            lang: lang.to_owned(),
+            name: String::new(),
+            auto,
+            translatable: true,
+            url: format!("https://www.youtube.com/api/timedtext?lang={}", lang),
+        }
+    }
+
+    #[test]
+    fn clocks() {
+        assert_eq!(clock(0.0, '.'), "00:00:00.000");
+        assert_eq!(clock(3725.0006, ','), "01:02:05,001");
+        assert_eq!(clock(-1.0, '.'), "00:00:00.000");
+        assert_eq!(clock(100.0 * 3600.0, '.'), "100:00:00.000");
+    }
+
+    #[test]
+    fn vtt_and_srt() {
+        let cues = [
+            Cue {
+                start: 1.0,
+                end: 2.5,
+                text: "a < b".to_owned(),
+            },
+            Cue {
+                start: 2.5,
+                end: 4.0,
+                text: "c\nd".to_owned(),
+            },
+        ];
+        assert_eq!(
+            vtt(&cues),
+            "WEBVTT\n\n1\n00:00:01.000 --> 00:00:02.500\na &lt; b\n\n\
+             2\n00:00:02.500 --> 00:00:04.000\nc\nd\n\n"
+        );
+        assert_eq!(
+            srt(&cues),
+            "1\n00:00:01,000 --> 00:00:02,500\na < b\n\n\
+             2\n00:00:02,500 --> 00:00:04,000\nc\nd\n\n"
+        );
+    }
+
+    #[test]
+    fn track_selection() {
+        let captions = [
+            caption("en", true),
+            caption("en-US", false),
+            caption("zh-Hans", false),
+            caption("ja", true),
+        ];
+        let pick = |lang: &str, auto: bool| {
+            track(&captions, lang, auto).map(|c| (c.lang.as_str(), c.auto))
+        };
+        // 默认优先人工字幕, 主语言相同即可
+        assert_eq!(pick("en", false), Some(("en-US", false)));
+        assert_eq!(pick("en", true), Some(("en", true)));
+        assert_eq!(pick("EN-us", false), Some(("en-US", false)));
+        // 同类中没有完全相同的语言时, 优先同类中主语言相同的
+        assert_eq!(pick("en-US", true), Some(("en", true)));
+        assert_eq!(pick("zh", false), Some(("zh-Hans", false)));
+        // 没有所选类型时使用另一类
+        assert_eq!(pick("ja", false), Some(("ja", true)));
+        assert_eq!(pick("fr", false), None);
+    }
+
+    #[test]
+    fn json3_events() {
+        let data = br#"{"events": [
+            {"tStartMs": 0, "dDurationMs": 5000, "id": 1, "wpWinPosId": 1},
+            {"tStartMs": 1000, "dDurationMs": 3000, "segs": [{"utf8": "hello "}, {"utf8": "world"}]},
+            {"tStartMs": 2000, "dDurationMs": 3000, "segs": [{"utf8": " line1 \n\n line2 "}]},
+            {"tStartMs": 3000, "dDurationMs": 1000, "segs": [{"utf8": "\n"}]},
+            {"tStartMs": 4500, "dDurationMs": 500, "segs": [{"utf8": "end"}]}
+        ]}"#;
+        let cues = json3(data, false).unwrap();
+        let got: Vec<(f64, f64, &str)> =
+            cues.iter().map(|c| (c.start, c.end, &c.text[..])).collect();
+        // 窗口定义及空的cue被跳过, 空行被去掉
+        assert_eq!(
+            got,
+            vec![
+                (1.0, 4.0, "hello world"),
+                (2.0, 5.0, "line1\nline2"),
+                (4.5, 5.0, "end"),
+            ]
+        );
+        // 自动生成的字幕截断到下一行开始
+        let cues = json3(data, true).unwrap();
+        let ends: Vec<f64> = cues.iter().map(|c| c.end).collect();
+        assert_eq!(ends, vec![2.0, 4.5, 5.0]);
+        assert!(json3(b"{}", false).is_none());
+        assert!(json3(b"not json", false).is_none());
+    }
+
+    #[test]
+    fn srv3_paragraphs() {
+        let data = br#"<?xml version="1.0" encoding="utf-8" ?><timedtext format="3">
+<head><pen id="1" b="1"/><wp id="0" ap="7"/></head>
+<body>
+<w t="0" id="1" wp="0"/>
+<p t="1000" d="3000">Tom &amp; Jerry &#39;s &lt;b&gt;</p>
+<p t="2000" d="3000" w="1"><s ac="0">line1</s><s t="500" ac="0"> word</s>
+ line2 </p>
+<p t="3000" d="1000" w="1" a="1">
+</p>
+<p t="4500" d="500"><s>&#x4e2d;&#25991; &bogus; &</s></p>
+</body></timedtext>"#;
+        let cues = srv3(data, false).unwrap();
+        let got: Vec<(f64, f64, &str)> =
+            cues.iter().map(|c| (c.start, c.end, &c.text[..])).collect();
+        // 标签被去掉, 实体被解码, 空的p被跳过
+        assert_eq!(
+            got,
+            vec![
+                (1.0, 4.0, "Tom & Jerry 's <b>"),
+                (2.0, 5.0, "line1 word\nline2"),
+                (4.5, 5.0, "中文 &bogus; &"),
+            ]
+        );
+        let ends: Vec<f64> = srv3(data, true).unwrap().iter().map(|c| c.end).collect();
+        assert_eq!(ends, vec![2.0, 4.5, 5.0]);
+        assert!(srv3(b"<timedtext></timedtext>", false).is_none());
+        assert!(
+            srv3(b"<timedtext><body></body></timedtext>", false)
+                .unwrap()
+                .is_empty()
+        );
+    }
+}
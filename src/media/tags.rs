@@ -0,0 +1,200 @@
+use actix_web::web::{self, Bytes};
+use awc::Client;
+use std::sync::Arc;
+
+use super::{mp4, webm};
+use crate::parser::VideoInfo;
+use crate::{request, util};
+
+// 下载时写入容器的元数据
+pub struct Tags {
+    pub title: String,
+    pub artist: String,
+    pub date: String,
+    pub description: String,
+    pub url: String,
+    // jpg封面, 获取失败时为None
+    pub cover: Option<Arc<Bytes>>,
+}
+
+// 由视频信息生成, 封面与/video/{ID}.jpg相同, 经由CACHEDATA缓存
+pub async fn tags(client: &web::Data<Client>, info: &VideoInfo) -> Tags {
+    let url = util::thumbnail(&info.id, "jpg");
+    let cover = request::req_get_cache(client, &url, 86400, 1 << 20)
+        .await
+        .ok();
+    Tags {
+        title: info.title.clone(),
+        artist: info.author.clone(),
+        date: info.date.clone(),
+        description: info.description.clone(),
+        url: format!("https://www.youtube.com/watch?v={}", info.id),
+        cover,
+    }
+}
+
+impl Tags {
+    // MP4的moov/udta/meta/ilst, 链接写入注释
+    pub fn udta(&self) -> Vec<u8> {
+        let mut ilst = Vec::new();
+        let texts: [(&[u8; 4], &String); 5] = [
+            (b"\xa9nam", &self.title),
+            (b"\xa9ART", &self.artist),
+            (b"\xa9day", &self.date),
+            (b"desc", &self.description),
+            (b"\xa9cmt", &self.url),
+        ];
+        for (kind, value) in texts {
+            if !value.is_empty() {
+                ilst.extend(mp4::atom(kind, &data(1, value.as_bytes())));
+            }
+        }
+        if let Some(cover) = &self.cover {
+            ilst.extend(mp4::atom(b"covr", &data(13, cover)));
+        }
+        // hdlr: pre_defined, handler_type, reserved, name
+        let mut hdlr = vec![0; 8];
+        hdlr.extend_from_slice(b"mdirappl");
+        hdlr.extend_from_slice(&[0; 9]);
+        let mut meta = vec![0; 4];
+        meta.extend(mp4::atom(b"hdlr", &hdlr));
+        meta.extend(mp4::atom(b"ilst", &ilst));
+        mp4::atom(b"udta", &mp4::atom(b"meta", &meta))
+    }
+
+    // WebM的Tags, WebM不支持Attachments, 不写入封面
+    pub fn matroska(&self) -> Vec<u8> {
+        let mut tag = webm::element(webm::TARGETS, &[]);
+        let texts = [
+            ("TITLE", &self.title),
+            ("ARTIST", &self.artist),
+            ("DATE_RELEASED", &self.date),
+            ("DESCRIPTION", &self.description),
+            ("URL", &self.url),
+        ];
+        for (name, value) in texts {
+            if !value.is_empty() {
+                let mut simple = webm::element(webm::TAG_NAME, name.as_bytes());
+                simple.extend(webm::element(webm::TAG_STRING, value.as_bytes()));
+                tag.extend(webm::element(webm::SIMPLE_TAG, &simple));
+            }
+        }
+        webm::element(webm::TAGS, &webm::element(webm::TAG, &tag))
+    }
+
+    // Vorbis comment, 封面为base64编码的FLAC picture block
+    pub fn vorbis(&self) -> Vec<String> {
+        let texts = [
+            ("TITLE", &self.title),
+            ("ARTIST", &self.artist),
+            ("DATE", &self.date),
+            ("DESCRIPTION", &self.description),
+            ("COMMENT", &self.url),
+        ];
+        let mut out: Vec<String> = texts
+            .iter()
+            .filter(|(_, value)| !value.is_empty())
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect();
+        if let Some(cover) = &self.cover {
+            // 类型3为封面, 宽高及色深未知时填0
+            let mime = b"image/jpeg";
+            let mut picture = 3u32.to_be_bytes().to_vec();
+            picture.extend_from_slice(&(mime.len() as u32).to_be_bytes());
+            picture.extend_from_slice(mime);
+            picture.extend_from_slice(&[0; 20]);
+            picture.extend_from_slice(&(cover.len() as u32).to_be_bytes());
+            picture.extend_from_slice(cover);
+            out.push(format!("METADATA_BLOCK_PICTURE={}", util::base64(&picture)));
+        }
+        out
+    }
+}
+
+// ilst中的data box, 类型1为UTF-8文本, 13为jpg
+fn data(kind: u32, value: &[u8]) -> Vec<u8> {
+    let mut out = kind.to_be_bytes().to_vec();
+    out.extend_from_slice(&[0; 4]);
+    out.extend_from_slice(value);
+    mp4::atom(b"data", &out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(cover: Option<&'static [u8]>) -> Tags {
+        Tags {
+            title: "标题".to_owned(),
+            artist: "作者".to_owned(),
+            date: "2024-01-02".to_owned(),
+            description: String::new(),
+            url: "https://www.youtube.com/watch?v=x".to_owned(),
+            cover: cover.map(|c| Arc::new(Bytes::from_static(c))),
+        }
+    }
+
+    #[test]
+    fn vorbis_comments() {
+        let c = sample(None).vorbis();
+        // 空的字段不输出
+        assert_eq!(
+            c,
+            vec![
+                "TITLE=标题",
+                "ARTIST=作者",
+                "DATE=2024-01-02",
+                "COMMENT=https://www.youtube.com/watch?v=x",
+            ]
+        );
+        let c = sample(Some(b"\xff\xd8jpg")).vorbis();
+        let picture = c
+            .last()
+            .unwrap()
+            .strip_prefix("METADATA_BLOCK_PICTURE=")
+            .unwrap();
+        let mut expected = 3u32.to_be_bytes().to_vec();
+        expected.extend_from_slice(&10u32.to_be_bytes());
+        expected.extend_from_slice(b"image/jpeg");
+        expected.extend_from_slice(&[0; 20]);
+        expected.extend_from_slice(&5u32.to_be_bytes());
+        expected.extend_from_slice(b"\xff\xd8jpg");
+        assert_eq!(picture, util::base64(&expected));
+    }
+
+    #[test]
+    fn matroska_tags_only() {
+        let data = sample(Some(b"\xff\xd8jpg")).matroska();
+        let tags = webm::find(&data, webm::TAGS).unwrap();
+        assert_eq!(tags.raw.len(), data.len());
+        let tag = webm::find(tags.data, webm::TAG).unwrap();
+        let names: Vec<&[u8]> = webm::elements(tag.data)
+            .filter(|e| e.id == webm::SIMPLE_TAG)
+            .filter_map(|e| webm::find(e.data, webm::TAG_NAME).map(|n| n.data))
+            .collect();
+        assert_eq!(
+            names,
+            vec![&b"TITLE"[..], b"ARTIST", b"DATE_RELEASED", b"URL"]
+        );
+        // 不包含封面
+        assert!(!data.windows(3).any(|w| w == b"jpg"));
+    }
+
+    #[test]
+    fn mp4_ilst() {
+        let data = sample(Some(b"\xff\xd8jpg")).udta();
+        let meta = mp4::find(&data, b"udta")
+            .and_then(|u| mp4::find(u.data, b"meta"))
+            .unwrap();
+        let ilst = mp4::find(&meta.data[4..], b"ilst").unwrap();
+        let kinds: Vec<[u8; 4]> = mp4::atoms(ilst.data).map(|a| a.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![*b"\xa9nam", *b"\xa9ART", *b"\xa9day", *b"\xa9cmt", *b"covr"]
+        );
+        let covr = mp4::find(ilst.data, b"covr").unwrap();
+        let d = mp4::find(covr.data, b"data").unwrap().data;
+        assert_eq!(mp4::u32_at(d, 0), Some(13));
+        assert_eq!(&d[8..], b"\xff\xd8jpg");
+    }
+}
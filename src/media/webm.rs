@@ -0,0 +1,256 @@
+// Matroska/WebM EBML 解析
+pub const EBML: u32 = 0x1A45DFA3;
+pub const SEGMENT: u32 = 0x18538067;
+pub const INFO: u32 = 0x1549A966;
+pub const TIMECODE_SCALE: u32 = 0x2AD7B1;
+pub const TRACKS: u32 = 0x1654AE6B;
+pub const TRACK_ENTRY: u32 = 0xAE;
+pub const TRACK_NUMBER: u32 = 0xD7;
+pub const TRACK_UID: u32 = 0x73C5;
+pub const CODEC_PRIVATE: u32 = 0x63A2;
+pub const CODEC_DELAY: u32 = 0x56AA;
+pub const AUDIO: u32 = 0xE1;
+pub const CHANNELS: u32 = 0x9F;
+pub const CLUSTER: u32 = 0x1F43B675;
+pub const BLOCK_GROUP: u32 = 0xA0;
+pub const BLOCK: u32 = 0xA1;
+pub const SIMPLE_BLOCK: u32 = 0xA3;
+pub const DISCARD_PADDING: u32 = 0x75A2;
+pub const CUES: u32 = 0x1C53BB6B;
+pub const CUE_POINT: u32 = 0xBB;
+pub const CUE_TIME: u32 = 0xB3;
+pub const CUE_TRACK_POSITIONS: u32 = 0xB7;
+pub const CUE_CLUSTER_POSITION: u32 = 0xF1;
+pub const TAGS: u32 = 0x1254C367;
+pub const TAG: u32 = 0x7373;
+pub const TARGETS: u32 = 0x63C0;
+pub const SIMPLE_TAG: u32 = 0x67C8;
+pub const TAG_NAME: u32 = 0x45A3;
+pub const TAG_STRING: u32 = 0x4487;
+
+pub struct Element<'a> {
+    pub id: u32,
+    pub offset: usize,
+    pub header: usize,
+    pub data: &'a [u8],
+    pub raw: &'a [u8],
+}
+
+// 读取vint, 返回(值,长度), keep_marker为true时保留长度标记位(用于element id)
+pub fn vint(data: &[u8], keep_marker: bool) -> Option<(u64, usize)> {
+    let first = *data.first()?;
+    let len = first.leading_zeros() as usize + 1;
+    if len > 8 || data.len() < len {
+        return None;
+    }
+    let mut v = if keep_marker {
+        first as u64
+    } else {
+        (first as u64) & (0xff >> len)
+    };
+    for b in &data[1..len] {
+        v = (v << 8) | *b as u64;
+    }
+    Some((v, len))
+}
+
+pub fn elements(data: &[u8]) -> Elements<'_> {
+    Elements { data, pos: 0 }
+}
+
+pub struct Elements<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Iterator for Elements<'a> {
+    type Item = Element<'a>;
+
+    fn next(&mut self) -> Option<Element<'a>> {
+        let rest = self.data.get(self.pos..)?;
+        let (id, id_len) = vint(rest, true)?;
+        let (size, size_len) = vint(rest.get(id_len..)?, false)?;
+        let header = id_len + size_len;
+        // 全1表示未知长度,数据不完整时也只返回已有部分
+        let unknown = size == (1 << (7 * size_len)) - 1;
+        let end = if unknown {
+            rest.len()
+        } else {
+            (header + size as usize).min(rest.len())
+        };
+        let el = Element {
+            id: id as u32,
+            offset: self.pos,
+            header,
+            data: rest.get(header..end)?,
+            raw: &rest[..end],
+        };
+        self.pos += end;
+        Some(el)
+    }
+}
+
+// 未知长度的Segment, 用于流式输出
+pub const UNKNOWN_SIZE: [u8; 8] = [0x01, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF];
+
+pub fn id_bytes(id: u32) -> Vec<u8> {
+    let b = id.to_be_bytes();
+    let skip = b.iter().take_while(|v| **v == 0).count();
+    b[skip..].to_vec()
+}
+
+pub fn size_bytes(size: u64) -> Vec<u8> {
+    // 长度为n的vint可表示 2^(7n)-2, 全1保留为未知长度
+    let len = (1..=8).find(|n| size < (1 << (7 * n)) - 1).unwrap_or(8);
+    let mut out = size.to_be_bytes()[8 - len..].to_vec();
+    out[0] |= 0x80 >> (len - 1);
+    out
+}
+
+pub fn element(id: u32, payload: &[u8]) -> Vec<u8> {
+    let mut out = id_bytes(id);
+    out.extend(size_bytes(payload.len() as u64));
+    out.extend_from_slice(payload);
+    out
+}
+
+pub fn uint_element(id: u32, v: u64) -> Vec<u8> {
+    let b = v.to_be_bytes();
+    let skip = b.iter().take_while(|v| **v == 0).count().min(7);
+    element(id, &b[skip..])
+}
+
+pub fn find(data: &[u8], id: u32) -> Option<Element<'_>> {
+    elements(data).find(|e| e.id == id)
+}
+
+pub fn uint(data: &[u8]) -> u64 {
+    data.iter().fold(0, |v, b| (v << 8) | *b as u64)
+}
+
+// 有符号整数, 按长度符号扩展
+pub fn int(data: &[u8]) -> i64 {
+    match data.len() {
+        0 => 0,
+        n if n >= 8 => uint(data) as i64,
+        n => (uint(data) as i64) << (64 - 8 * n) >> (64 - 8 * n),
+    }
+}
+
+pub struct Cues {
+    // 纳秒
+    pub timecode_scale: u64,
+    // (time, cluster的绝对偏移)
+    pub points: Vec<(u64, u64)>,
+}
+
+// data需要从文件开头开始,包含Segment头部,Info以及Cues
+pub fn parse_cues(data: &[u8]) -> Option<Cues> {
+    let segment = find(data, SEGMENT)?;
+    let base = (segment.offset + segment.header) as u64;
+    let mut timecode_scale = 1_000_000;
+    let mut points = Vec::new();
+    for el in elements(segment.data) {
+        match el.id {
+            INFO => {
+                if let Some(scale) = find(el.data, TIMECODE_SCALE) {
+                    timecode_scale = uint(scale.data);
+                }
+            }
+            CUES => {
+                for point in elements(el.data).filter(|e| e.id == CUE_POINT) {
+                    let time = find(point.data, CUE_TIME).map(|e| uint(e.data));
+                    let pos = find(point.data, CUE_TRACK_POSITIONS)
+                        .and_then(|p| find(p.data, CUE_CLUSTER_POSITION))
+                        .map(|e| uint(e.data));
+                    if let (Some(time), Some(pos)) = (time, pos) {
+                        points.push((time, base + pos));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    if points.is_empty() {
+        return None;
+    }
+    Some(Cues {
+        timecode_scale,
+        points,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cue(time: u64, pos: u64) -> Vec<u8> {
+        let positions = [
+            uint_element(0xF7, 1),
+            uint_element(CUE_CLUSTER_POSITION, pos),
+        ]
+        .concat();
+        let point = [
+            uint_element(CUE_TIME, time),
+            element(CUE_TRACK_POSITIONS, &positions),
+        ]
+        .concat();
+        element(CUE_POINT, &point)
+    }
+
+    #[test]
+    fn vint_and_size() {
+        assert_eq!(vint(&[0x81], false), Some((1, 1)));
+        assert_eq!(vint(&[0x40, 0x02], false), Some((2, 2)));
+        assert_eq!(
+            vint(&[0x1A, 0x45, 0xDF, 0xA3], true),
+            Some((EBML as u64, 4))
+        );
+        assert_eq!(vint(&[0x40], false), None);
+        assert_eq!(vint(&[0], false), None);
+        // 127在一字节中为未知长度, 需要两字节
+        assert_eq!(size_bytes(126), vec![0xFE]);
+        assert_eq!(size_bytes(127), vec![0x40, 0x7F]);
+    }
+
+    #[test]
+    fn unknown_size() {
+        let mut data = id_bytes(CLUSTER);
+        data.extend_from_slice(&UNKNOWN_SIZE);
+        data.extend(uint_element(0xE7, 5));
+        let cluster = find(&data, CLUSTER).unwrap();
+        assert_eq!(cluster.header, 12);
+        assert_eq!(uint(find(cluster.data, 0xE7).unwrap().data), 5);
+    }
+
+    #[test]
+    fn cues() {
+        let info = element(INFO, &uint_element(TIMECODE_SCALE, 500_000));
+        let cues = element(CUES, &[cue(0, 100), cue(5000, 90_000)].concat());
+        let payload = [info, cues].concat();
+        let mut data = element(EBML, &element(0x4282, b"webm"));
+        let base = data.len() + element(SEGMENT, &payload).len() - payload.len();
+        data.extend(element(SEGMENT, &payload));
+        let c = parse_cues(&data).unwrap();
+        assert_eq!(c.timecode_scale, 500_000);
+        let base = base as u64;
+        assert_eq!(c.points, vec![(0, base + 100), (5000, base + 90_000)]);
+    }
+
+    #[test]
+    fn cues_missing() {
+        let info = element(INFO, &uint_element(TIMECODE_SCALE, 1_000_000));
+        assert!(parse_cues(&element(SEGMENT, &info)).is_none());
+        assert!(parse_cues(&element(EBML, &[])).is_none());
+    }
+
+    #[test]
+    fn signed_int() {
+        assert_eq!(int(&[]), 0);
+        assert_eq!(int(&[0x7F]), 127);
+        assert_eq!(int(&[0xFF]), -1);
+        assert_eq!(int(&[0x01, 0x00]), 256);
+        assert_eq!(int(&[0xFF, 0x38]), -200);
+        assert_eq!(int(&[0xFF; 8]), -1);
+    }
+}
@@ -1,3 +1,4 @@
+use crate::cache::map::CACHEJSON;
 use crate::request;
 use actix_web::web;
 use awc::Client;
@@ -8,35 +9,202 @@ use std::io;
 
 #[derive(Serialize, Debug)]
 pub struct StreamItem {
-    quality: String,
-    r#type: String,
+    pub quality: String,
+    pub r#type: String,
     #[serde(skip_serializing_if = "String::is_empty")]
     pub url: String,
-    itag: String,
-    len: String,
+    pub itag: String,
+    pub len: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bitrate: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub width: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub height: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fps: Option<u64>,
+    #[serde(rename = "sampleRate")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sample_rate: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub channels: Option<u64>,
+    #[serde(rename = "durationMs")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration_ms: Option<u64>,
     #[serde(rename = "initRange")]
     #[serde(skip_serializing_if = "Option::is_none")]
     init_range: Option<serde_json::Map<std::string::String, serde_json::Value>>,
     #[serde(rename = "indexRange")]
     #[serde(skip_serializing_if = "Option::is_none")]
     index_range: Option<serde_json::Map<std::string::String, serde_json::Value>>,
+    #[serde(rename = "audioTrack")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub audio_track: Option<AudioTrack>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct AudioTrack {
+    pub id: String,
+    pub lang: String,
+    pub name: String,
+    pub default: bool,
+}
+
+impl StreamItem {
+    // mimeType形如 video/mp4; codecs="avc1.640028"
+    pub fn mime(&self) -> (&str, &str) {
+        let (mime, codecs) = self.r#type.split_once(';').unwrap_or((&self.r#type, ""));
+        let codecs = codecs
+            .trim()
+            .trim_start_matches("codecs=")
+            .trim_matches('"');
+        (mime.trim(), codecs)
+    }
+
+    pub fn ext(&self) -> &str {
+        if self.mime().0.ends_with("webm") {
+            "webm"
+        } else {
+            "mp4"
+        }
+    }
+
+    // 非默认音轨的语言, 即streams中key的后缀
+    pub fn lang(&self) -> &str {
+        match &self.audio_track {
+            Some(t) if !t.default => &t.lang,
+            _ => "",
+        }
+    }
+
+    // streams中的key, 默认音轨为itag, 其他音轨为itag-lang
+    pub fn key(&self) -> String {
+        match self.lang() {
+            "" => self.itag.clone(),
+            lang => format!("{}-{}", self.itag, lang),
+        }
+    }
+
+    // 代理地址,非默认音轨需要附带lang参数
+    pub fn proxy_path(&self, vid: &str) -> String {
+        match self.lang() {
+            "" => format!("/video/{}/{}.{}", vid, self.itag, self.ext()),
+            lang => format!("/video/{}/{}.{}?lang={}", vid, self.itag, self.ext(), lang),
+        }
+    }
+
+    // 用于文件名的清晰度, 音频使用码率
+    pub fn label(&self) -> String {
+        let label = match (self.mime().0.starts_with("audio/"), self.bitrate) {
+            (true, Some(bitrate)) => format!("{}k", bitrate / 1000),
+            _ => self.quality.clone(),
+        };
+        label
+            .chars()
+            .filter(|c| c.is_ascii_alphanumeric() || *c == '-' || *c == '_')
+            .collect()
+    }
+
+    pub fn init(&self) -> Option<(u64, u64)> {
+        byte_range(&self.init_range)
+    }
+
+    pub fn index(&self) -> Option<(u64, u64)> {
+        byte_range(&self.index_range)
+    }
+}
+
+fn byte_range(
+    range: &Option<serde_json::Map<std::string::String, serde_json::Value>>,
+) -> Option<(u64, u64)> {
+    let range = range.as_ref()?;
+    let start = range.get("start")?.as_str()?.parse().ok()?;
+    let end = range.get("end")?.as_str()?.parse().ok()?;
+    Some((start, end))
+}
+
+#[derive(Serialize, Debug)]
+pub struct Chapter {
+    pub title: String,
+    // 秒
+    pub start: f64,
+    pub end: f64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Caption {
+    // languageCode, 形如en或zh-Hans
+    pub lang: String,
+    pub name: String,
+    // 自动生成的字幕, kind为asr
+    pub auto: bool,
+    // 是否可通过tlang翻译为其他语言
+    pub translatable: bool,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub url: String,
 }
 
 #[derive(Serialize, Debug)]
 pub struct VideoInfo {
-    id: String,
-    title: String,
-    duration: String,
-    author: String,
-    live: bool,
+    pub id: String,
+    pub title: String,
+    pub duration: String,
+    pub author: String,
+    pub description: String,
+    // 发布日期, 形如2024-01-31
+    pub date: String,
+    pub live: bool,
+    pub chapters: Vec<Chapter>,
+    pub captions: Vec<Caption>,
+    // 可作为tlang的语言
+    pub translations: Vec<String>,
     pub streams: HashMap<String, StreamItem>,
 }
 
 impl VideoInfo {
+    // 多音轨时,默认音轨以itag为key,其他音轨以itag-lang为key
+    pub fn stream(&self, itag: &str, lang: &str) -> Option<&StreamItem> {
+        if let Some(lang) = self.audio_lang(lang).filter(|l| !l.is_empty())
+            && let Some(item) = self.streams.get(&format!("{}-{}", itag, lang))
+        {
+            return Some(item);
+        }
+        self.streams
+            .get(itag)
+            .or_else(|| self.streams.values().find(|s| s.itag == itag))
+    }
+
+    // 与subtitle::track相同, 优先完全相同的语言代码, 其次主语言相同, 例如es可匹配es-US
+    // 返回streams中key的后缀, 匹配到默认音轨时为空, 没有该语言的音轨时为None
+    pub fn audio_lang(&self, lang: &str) -> Option<&str> {
+        if lang.is_empty() {
+            return Some("");
+        }
+        let primary = |l: &str| l.split('-').next().unwrap_or_default().to_ascii_lowercase();
+        let mut tracks: Vec<&AudioTrack> = self
+            .streams
+            .values()
+            .filter_map(|s| s.audio_track.as_ref())
+            .collect();
+        // 多个音轨主语言相同时优先默认音轨, 结果与HashMap的顺序无关
+        tracks.sort_by(|a, b| (!a.default, &a.lang).cmp(&(!b.default, &b.lang)));
+        let track = tracks
+            .iter()
+            .find(|t| t.lang.eq_ignore_ascii_case(lang))
+            .or_else(|| tracks.iter().find(|t| primary(&t.lang) == primary(lang)))?;
+        Some(match track.default {
+            true => "",
+            false => &track.lang,
+        })
+    }
+
     pub fn clean(mut self) -> VideoInfo {
         for (_, val) in self.streams.iter_mut() {
             val.url = "".to_owned();
         }
+        for val in self.captions.iter_mut() {
+            val.url = "".to_owned();
+        }
         self
     }
 }
@@ -65,9 +233,41 @@ pub async fn parse(client: &web::Data<Client>, vid: &String) -> Result<VideoInfo
             .as_str()
             .unwrap_or("")
             .to_owned(),
+        description: res["videoDetails"]["shortDescription"]
+            .as_str()
+            .unwrap_or("")
+            .to_owned(),
+        date: res["microformat"]["playerMicroformatRenderer"]["publishDate"]
+            .as_str()
+            .unwrap_or("")
+            .to_owned(),
         live: res["videoDetails"]["isLive"].as_bool().unwrap_or_default(),
+        chapters: Vec::new(),
+        captions: Vec::new(),
+        translations: Vec::new(),
         streams: stream_items,
     };
+    info.chapters = chapters(&HashMap::new(), &info.description, &info.duration);
+    let tracklist = &res["captions"]["playerCaptionsTracklistRenderer"];
+    if let Some(tracks) = tracklist["captionTracks"].as_array() {
+        info.captions = tracks
+            .iter()
+            .map(|t| Caption {
+                lang: t["languageCode"].as_str().unwrap_or("").to_owned(),
+                name: text(&t["name"]),
+                auto: t["kind"].as_str() == Some("asr"),
+                translatable: t["isTranslatable"].as_bool().unwrap_or_default(),
+                url: t["baseUrl"].as_str().unwrap_or("").to_owned(),
+            })
+            .filter(|c| !c.lang.is_empty() && !c.url.is_empty())
+            .collect();
+    }
+    if let Some(languages) = tracklist["translationLanguages"].as_array() {
+        info.translations = languages
+            .iter()
+            .filter_map(|l| l["languageCode"].as_str().map(|v| v.to_owned()))
+            .collect();
+    }
     let mut streams: Vec<serde_json::Value> = [].to_vec();
     if let Some(video_info_itags) = res["streamingData"]["formats"].as_array() {
         streams = [streams, video_info_itags.to_vec()].concat();
@@ -76,31 +276,191 @@ pub async fn parse(client: &web::Data<Client>, vid: &String) -> Result<VideoInfo
         streams = [streams, video_info_itags_adaptive.to_vec()].concat();
     }
     for item in streams {
-        let i = item["itag"].as_u64().unwrap_or(0);
-        let itag = i.to_string();
-        let itags = i.to_string();
-        let url = item["url"].as_str().unwrap_or("");
-        let len = item["contentLength"].as_str().unwrap_or("");
-        let mime = item["mimeType"].as_str().unwrap_or("");
-        let quality = item["qualityLabel"]
-            .as_str()
-            .unwrap_or_else(|| item["quality"].as_str().unwrap_or(""));
-        info.streams.insert(
-            itag,
-            StreamItem {
-                quality: quality.to_owned(),
-                len: len.to_owned(),
-                itag: itags,
-                url: url.to_owned(),
-                r#type: mime.to_owned(),
-                init_range: item["initRange"].as_object().cloned(),
-                index_range: item["indexRange"].as_object().cloned(),
-            },
-        );
+        let (key, item) = stream_item(&item);
+        info.streams.insert(key, item);
     }
     Ok(info)
 }
 
+// streamingData中的一个format, 返回streams中的key
+pub fn stream_item(item: &serde_json::Value) -> (String, StreamItem) {
+    let i = item["itag"].as_u64().unwrap_or(0);
+    let itags = i.to_string();
+    let track = &item["audioTrack"];
+    let audio_track = track.as_object().map(|_| {
+        let id = track["id"].as_str().unwrap_or("");
+        AudioTrack {
+            id: id.to_owned(),
+            lang: id.split('.').next().unwrap_or("").to_owned(),
+            name: track["displayName"].as_str().unwrap_or("").to_owned(),
+            default: track["audioIsDefault"].as_bool().unwrap_or_default(),
+        }
+    });
+    let itag = match &audio_track {
+        Some(t) if !t.default && !t.lang.is_empty() => format!("{}-{}", itags, t.lang),
+        _ => itags.clone(),
+    };
+    let url = item["url"].as_str().unwrap_or("");
+    let len = item["contentLength"].as_str().unwrap_or("");
+    let mime = item["mimeType"].as_str().unwrap_or("");
+    let quality = item["qualityLabel"]
+        .as_str()
+        .unwrap_or_else(|| item["quality"].as_str().unwrap_or(""));
+    let stream = StreamItem {
+        quality: quality.to_owned(),
+        len: len.to_owned(),
+        bitrate: item["bitrate"].as_u64(),
+        width: item["width"].as_u64(),
+        height: item["height"].as_u64(),
+        fps: item["fps"].as_u64(),
+        sample_rate: item["audioSampleRate"]
+            .as_str()
+            .and_then(|v| v.parse().ok()),
+        channels: item["audioChannels"].as_u64(),
+        duration_ms: item["approxDurationMs"]
+            .as_str()
+            .and_then(|v| v.parse().ok()),
+        itag: itags,
+        url: url.to_owned(),
+        r#type: mime.to_owned(),
+        init_range: item["initRange"].as_object().cloned(),
+        index_range: item["indexRange"].as_object().cloned(),
+        audio_track,
+    };
+    (itag, stream)
+}
+
+// 包含/next中章节的完整章节列表, 只在需要章节时请求/next, parse只从简介中解析
+pub async fn load_chapters(client: &web::Data<Client>, info: &VideoInfo) -> Vec<Chapter> {
+    let next = request::getnext_cache(client, &info.id, 3600).await;
+    chapters(&next, &info.description, &info.duration)
+}
+
+// 章节优先使用/next中的macroMarkersListRenderer, 没有时从简介中的时间戳解析
+fn chapters(
+    next: &HashMap<String, serde_json::Value>,
+    description: &str,
+    duration: &str,
+) -> Vec<Chapter> {
+    let mut marks = next
+        .get("engagementPanels")
+        .and_then(|v| find(v, "macroMarkersListRenderer"))
+        .map(markers)
+        .unwrap_or_default();
+    if marks.is_empty() {
+        marks = description_marks(description);
+    }
+    let duration: f64 = duration.parse().unwrap_or_default();
+    let ends: Vec<f64> = marks.iter().skip(1).map(|(t, _)| *t).collect();
+    marks
+        .iter()
+        .enumerate()
+        .map(|(i, (start, title))| Chapter {
+            title: title.clone(),
+            start: *start,
+            end: ends.get(i).copied().unwrap_or(duration.max(*start)),
+        })
+        .collect()
+}
+
+// 递归查找第一个key, 章节及自动生成的精彩片段都是macroMarkersListRenderer, 以第一个为准
+fn find<'a>(v: &'a serde_json::Value, key: &str) -> Option<&'a serde_json::Value> {
+    match v {
+        serde_json::Value::Object(map) => map
+            .get(key)
+            .or_else(|| map.values().find_map(|v| find(v, key))),
+        serde_json::Value::Array(list) => list.iter().find_map(|v| find(v, key)),
+        _ => None,
+    }
+}
+
+fn markers(list: &serde_json::Value) -> Vec<(f64, String)> {
+    let Some(contents) = list["contents"].as_array() else {
+        return Vec::new();
+    };
+    contents
+        .iter()
+        .filter_map(|v| {
+            let item = v.get("macroMarkersListItemRenderer")?;
+            let start = item["onTap"]["watchEndpoint"]["startTimeSeconds"]
+                .as_f64()
+                .or_else(|| timestamp(&text(&item["timeDescription"])))?;
+            Some((start, text(&item["title"])))
+        })
+        .collect()
+}
+
+// simpleText或runs形式的文本
+fn text(v: &serde_json::Value) -> String {
+    if let Some(s) = v["simpleText"].as_str() {
+        return s.to_owned();
+    }
+    v["runs"]
+        .as_array()
+        .map(|runs| runs.iter().filter_map(|r| r["text"].as_str()).collect())
+        .unwrap_or_default()
+}
+
+// 与YouTube的规则相同, 第一个为0:00, 至少3个且依次递增, 否则不作为章节
+fn description_marks(description: &str) -> Vec<(f64, String)> {
+    let mut marks: Vec<(f64, String)> = Vec::new();
+    for line in description.lines() {
+        let mut words = line.split_whitespace();
+        let Some((i, start)) = words.by_ref().enumerate().find_map(|(i, w)| {
+            Some((i, timestamp(w.trim_matches(|c: char| !c.is_ascii_digit()))?))
+        }) else {
+            continue;
+        };
+        let title: Vec<&str> = line.split_whitespace().take(i).chain(words).collect();
+        let title = title
+            .join(" ")
+            .trim_matches(|c: char| c.is_whitespace() || "-–—:|•·".contains(c))
+            .to_owned();
+        marks.push((start, title));
+    }
+    let ascending = marks.windows(2).all(|w| w[0].0 < w[1].0);
+    if marks.len() < 3 || marks[0].0 != 0.0 || !ascending {
+        return Vec::new();
+    }
+    marks
+}
+
+// 形如1:23或1:02:03的时间, 转为秒
+fn timestamp(s: &str) -> Option<f64> {
+    let parts: Vec<&str> = s.split(':').collect();
+    if !(2..=3).contains(&parts.len()) {
+        return None;
+    }
+    let mut v = 0;
+    for (i, p) in parts.iter().enumerate() {
+        let valid = match i {
+            0 => (1..=2).contains(&p.len()),
+            _ => p.len() == 2,
+        };
+        if !valid || !p.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        let n: u64 = p.parse().ok()?;
+        if i > 0 && n >= 60 {
+            return None;
+        }
+        v = v * 60 + n;
+    }
+    Some(v as f64)
+}
+
+// 上游地址过期时, 丢弃缓存的播放器数据重新获取
+pub async fn refresh_url(
+    client: &web::Data<Client>,
+    vid: &String,
+    itag: &str,
+    lang: &str,
+) -> Option<String> {
+    CACHEJSON.remove(vid).await;
+    let info = parse(client, vid).await.ok()?;
+    info.stream(itag, lang).map(|s| s.url.clone())
+}
+
 pub async fn parse_url(
     client: &web::Data<Client>,
     vid: &String,
@@ -123,3 +483,158 @@ pub async fn parse_url(
     };
     Ok(url.to_owned())
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn audio(itag: u64, id: &str, default: bool) -> serde_json::Value {
+        json!({
+            "itag": itag,
+            "mimeType": "audio/mp4; codecs=\"mp4a.40.2\"",
+            "url": format!("https://r1.googlevideo.com/videoplayback?itag={}&xtags={}", itag, id),
+            "audioTrack": {"id": id, "displayName": id, "audioIsDefault": default},
+        })
+    }
+
+    // 带有initRange及indexRange的adaptive格式, track为(音轨id, 是否默认)
+    pub(crate) fn adaptive(
+        itag: u64,
+        mime: &str,
+        bitrate: u64,
+        track: Option<(&str, bool)>,
+    ) -> serde_json::Value {
+        let mut v = json!({
+            "itag": itag,
+            "mimeType": mime,
+            "bitrate": bitrate,
+            "url": format!("https://r1.googlevideo.com/videoplayback?itag={}", itag),
+            "initRange": {"start": "0", "end": format!("{}", itag)},
+            "indexRange": {"start": format!("{}", itag + 1), "end": "2000"},
+        });
+        if let Some((id, default)) = track {
+            v["audioTrack"] = json!({"id": id, "displayName": id, "audioIsDefault": default});
+        }
+        v
+    }
+
+    pub(crate) fn info(items: &[serde_json::Value]) -> VideoInfo {
+        VideoInfo {
+            id: "x".to_owned(),
+            title: String::new(),
+            duration: String::new(),
+            author: String::new(),
+            description: String::new(),
+            date: String::new(),
+            live: false,
+            chapters: Vec::new(),
+            captions: Vec::new(),
+            translations: Vec::new(),
+            streams: items.iter().map(stream_item).collect(),
+        }
+    }
+
+    #[test]
+    fn dubbed_keys() {
+        let (key, item) = stream_item(&audio(140, "es-US.3", false));
+        assert_eq!(key, "140-es-US");
+        assert_eq!((item.itag.as_str(), item.lang()), ("140", "es-US"));
+        assert_eq!(item.proxy_path("x"), "/video/x/140.mp4?lang=es-US");
+        // 默认音轨以itag为key
+        let (key, item) = stream_item(&audio(140, "en.4", true));
+        assert_eq!((key.as_str(), item.lang()), ("140", ""));
+        let (key, _) = stream_item(&json!({"itag": 18, "mimeType": "video/mp4"}));
+        assert_eq!(key, "18");
+    }
+
+    #[test]
+    fn stream_by_lang() {
+        let info = info(&[
+            audio(140, "en.4", true),
+            audio(140, "es-US.3", false),
+            audio(140, "es-ES.3", false),
+            audio(140, "de-DE.3", false),
+            json!({"itag": 18, "mimeType": "video/mp4"}),
+        ]);
+        let lang =
+            |itag: &str, lang: &str| info.stream(itag, lang).map(|s| (&s.itag[..], s.lang()));
+        assert_eq!(lang("140", ""), Some(("140", "")));
+        assert_eq!(lang("140", "es-ES"), Some(("140", "es-ES")));
+        assert_eq!(lang("140", "ES-us"), Some(("140", "es-US")));
+        // 只指定主语言时按语言代码排序取第一个
+        assert_eq!(lang("140", "es"), Some(("140", "es-ES")));
+        assert_eq!(lang("140", "de"), Some(("140", "de-DE")));
+        // 默认音轨的语言及未找到的语言都使用默认音轨
+        assert_eq!(lang("140", "en"), Some(("140", "")));
+        assert_eq!(lang("140", "en-GB"), Some(("140", "")));
+        assert_eq!(lang("140", "fr"), Some(("140", "")));
+        assert_eq!(info.audio_lang("en"), Some(""));
+        assert_eq!(info.audio_lang("fr"), None);
+        // 该itag没有此音轨时使用默认的流
+        assert_eq!(lang("18", "es"), Some(("18", "")));
+        assert_eq!(lang("22", ""), None);
+    }
+
+    #[test]
+    fn timestamps() {
+        assert_eq!(timestamp("0:00"), Some(0.0));
+        assert_eq!(timestamp("12:34"), Some(754.0));
+        assert_eq!(timestamp("1:02:03"), Some(3723.0));
+        assert_eq!(timestamp("1:2"), None);
+        assert_eq!(timestamp("123:00"), None);
+        assert_eq!(timestamp("1:60"), None);
+        assert_eq!(timestamp("1:00:00:00"), None);
+        assert_eq!(timestamp("12"), None);
+    }
+
+    #[test]
+    fn marks_from_description() {
+        let description =
+            "简介\n0:00 Intro\n(1:30) - 第二段\n第三段: 12:05\n链接 https://example.com";
+        let marks = description_marks(description);
+        assert_eq!(
+            marks,
+            vec![
+                (0.0, "Intro".to_owned()),
+                (90.0, "第二段".to_owned()),
+                (725.0, "第三段".to_owned()),
+            ]
+        );
+        // 不从0:00开始, 少于3个或不递增时不作为章节
+        assert!(description_marks("0:10 a\n1:00 b\n2:00 c").is_empty());
+        assert!(description_marks("0:00 a\n1:00 b").is_empty());
+        assert!(description_marks("0:00 a\n2:00 b\n1:00 c").is_empty());
+    }
+
+    #[test]
+    fn chapters_from_markers() {
+        let item = |title: &str, start: f64| {
+            json!({"macroMarkersListItemRenderer": {
+                "title": {"simpleText": title},
+                "onTap": {"watchEndpoint": {"startTimeSeconds": start}},
+            }})
+        };
+        let next: HashMap<String, serde_json::Value> = HashMap::from([(
+            "engagementPanels".to_owned(),
+            json!([{"panel": {"macroMarkersListRenderer": {
+                "contents": [item("A", 0.0), item("B", 60.0), {
+                    "macroMarkersListItemRenderer": {
+                        "title": {"runs": [{"text": "C"}, {"text": "D"}]},
+                        "timeDescription": {"simpleText": "2:00"},
+                    }
+                }],
+            }}}]),
+        )]);
+        let c = chapters(&next, "0:00 x\n1:00 y\n2:00 z", "150");
+        let got: Vec<(&str, f64, f64)> = c.iter().map(|c| (&c.title[..], c.start, c.end)).collect();
+        assert_eq!(
+            got,
+            vec![("A", 0.0, 60.0), ("B", 60.0, 120.0), ("CD", 120.0, 150.0)]
+        );
+        // 没有markers时从简介解析
+        let c = chapters(&HashMap::new(), "0:00 x\n1:00 y\n2:00 z", "150");
+        assert_eq!(c.len(), 3);
+        assert_eq!((c[2].title.as_str(), c[2].end), ("z", 150.0));
+    }
+}
@@ -80,6 +80,57 @@ async fn getplayer(
     }
 }
 
+// /next的结果, 用于章节(macroMarkersListRenderer), 与播放器数据分开缓存
+// 失败时返回空结果且不缓存, 章节不影响播放
+pub async fn getnext_cache(
+    client: &web::Data<Client>,
+    vid: &String,
+    ttl: u64,
+) -> Arc<HashMap<String, Value>> {
+    let key = format!("{}:next", vid);
+    let real = || async { getnext(client, vid).await.ok().map(Arc::new) };
+    CACHEJSON
+        .load_or_store(&key, real, ttl)
+        .await
+        .unwrap_or_default()
+}
+
+async fn getnext(
+    client: &web::Data<Client>,
+    vid: &String,
+) -> Result<HashMap<String, Value>, Box<dyn Error>> {
+    let url = "https://www.youtube.com/youtubei/v1/next?prettyPrint=false";
+    let req = serde_json::json!({
+        "videoId": vid,
+        "context": {
+            "client": {
+                "clientName": "WEB",
+                "clientVersion": "2.20241126.01.00",
+            }
+        }
+    });
+    let mut response = client
+        .post(url)
+        .timeout(TIMEOUT)
+        .content_type("application/json")
+        .insert_header(UA)
+        .insert_header(AL)
+        .send_json(&req)
+        .await?;
+    if response.status() != StatusCode::OK {
+        println!("status: failed next {} {}", vid, response.status());
+        return Err(Box::new(io::Error::other(format!(
+            "{} {}",
+            vid,
+            response.status()
+        ))));
+    }
+    Ok(response
+        .json::<HashMap<String, Value>>()
+        .limit(5 << 20)
+        .await?)
+}
+
 pub async fn req_get_cache(
     client: &web::Data<Client>,
     url: &String,
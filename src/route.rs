@@ -1,24 +1,40 @@
 use crate::cache::map::{CACHEDATA, CACHEJSON};
+use crate::dash::mpd;
 use crate::handler;
-use crate::hls::{playlist, ts};
+use crate::hls::{playlist, ts, vod};
+use crate::media::{chapters, index, subtitle};
+use crate::parser;
+use crate::upstream::{hedge, shape};
 use actix_files as fs;
 use actix_web::http::header::CACHE_CONTROL;
-use actix_web::{Error, HttpRequest, HttpResponse, Responder, Result, get, post, web};
+use actix_web::{Error, HttpRequest, HttpResponse, Responder, Result, post, route, web};
 use awc::Client;
 use serde::Deserialize;
+use std::env;
 use std::path::PathBuf;
 
 #[derive(Deserialize)]
 struct Quality {
     prefer: Option<String>,
+    lang: Option<String>,
 }
 
-#[get("/")]
+#[derive(Deserialize)]
+struct Subtitle {
+    tlang: Option<String>,
+    auto: Option<u8>,
+}
+
+#[route("/", method = "GET", method = "HEAD")]
 async fn hello() -> impl Responder {
     HttpResponse::Ok().body("Hello world!")
 }
 
-#[get("/video/{vid:[\\w\\-]{6,15}}.{ext:(json)}")]
+#[route(
+    "/video/{vid:[\\w\\-]{6,15}}.{ext:(json)}",
+    method = "GET",
+    method = "HEAD"
+)]
 async fn vinfo(info: web::Path<(String, String)>, client: web::Data<Client>) -> impl Responder {
     let info = info.into_inner();
     match handler::get_info(&client, &info.0).await {
@@ -32,7 +48,51 @@ async fn vinfo(info: web::Path<(String, String)>, client: web::Data<Client>) ->
     }
 }
 
-#[get("/video/{vid:[\\w\\-]{6,15}}.{ext:(m3u8)}")]
+#[route(
+    "/video/{vid:[\\w\\-]{6,15}}/chapters.vtt",
+    method = "GET",
+    method = "HEAD"
+)]
+async fn chapters_vtt(info: web::Path<String>, client: web::Data<Client>) -> impl Responder {
+    match handler::get_info(&client, &info).await {
+        Ok(res) => HttpResponse::Ok()
+            .content_type("text/vtt; charset=utf-8")
+            .insert_header((CACHE_CONTROL, "public,max-age=3600"))
+            .body(chapters::vtt(&parser::load_chapters(&client, &res).await)),
+        Err(err) => HttpResponse::InternalServerError().body(format!("{:?}", err)),
+    }
+}
+
+#[route(
+    "/video/{vid:[\\w\\-]{6,15}}/subs/{lang:[\\w\\-]{2,16}}.{ext:(vtt|srt)}",
+    method = "GET",
+    method = "HEAD"
+)]
+async fn subs(
+    params: web::Query<Subtitle>,
+    info: web::Path<(String, String, String)>,
+    client: web::Data<Client>,
+) -> impl Responder {
+    let (vid, lang, ext) = info.into_inner();
+    let tlang = params.tlang.as_deref().unwrap_or_default();
+    let auto = params.auto.unwrap_or_default() == 1;
+    match subtitle::subtitle(&client, &vid, &lang, &ext, tlang, auto).await {
+        Ok(res) => HttpResponse::Ok()
+            .content_type(match ext.as_str() {
+                "srt" => "application/x-subrip; charset=utf-8",
+                _ => "text/vtt; charset=utf-8",
+            })
+            .insert_header((CACHE_CONTROL, "public,max-age=3600"))
+            .body(res),
+        Err(err) => HttpResponse::InternalServerError().body(format!("{:?}", err)),
+    }
+}
+
+#[route(
+    "/video/{vid:[\\w\\-]{6,15}}.{ext:(m3u8)}",
+    method = "GET",
+    method = "HEAD"
+)]
 async fn hls(info: web::Path<(String, String)>, client: web::Data<Client>) -> impl Responder {
     let info = info.into_inner();
     match playlist::playlist_master(&client, &info.0).await {
@@ -47,7 +107,11 @@ async fn hls(info: web::Path<(String, String)>, client: web::Data<Client>) -> im
     }
 }
 
-#[get("/video/{vid:[\\w\\-]{6,15}}/{list:[\\w]{1,8}}.{ext:(m3u8)}")]
+#[route(
+    "/video/{vid:[\\w\\-]{6,15}}/{list:[\\w]{1,8}}.{ext:(m3u8)}",
+    method = "GET",
+    method = "HEAD"
+)]
 async fn hls_list(info: web::Path<(String, String)>, client: web::Data<Client>) -> impl Responder {
     let info = info.into_inner();
     match playlist::playlist_index(&client, &info.0, &info.1).await {
@@ -59,7 +123,11 @@ async fn hls_list(info: web::Path<(String, String)>, client: web::Data<Client>)
     }
 }
 
-#[get("/video/{vid:[\\w\\-]{6,15}}/{uid:[\\w]{1,8}}.{ext:(ts)}")]
+#[route(
+    "/video/{vid:[\\w\\-]{6,15}}/{uid:[\\w]{1,8}}.{ext:(ts)}",
+    method = "GET",
+    method = "HEAD"
+)]
 async fn hls_ts(info: web::Path<(String, String)>) -> impl Responder {
     let info = info.into_inner();
     match playlist::playlist_ts(&info.0, &info.1).await {
@@ -70,27 +138,170 @@ async fn hls_ts(info: web::Path<(String, String)>) -> impl Responder {
     }
 }
 
-#[get("/video/{vid:[\\w\\-]{6,15}}.{ext:(jpg|webp)}")]
-async fn image(
+#[route(
+    "/video/{vid:[\\w\\-]{6,15}}.{ext:(mpd)}",
+    method = "GET",
+    method = "HEAD"
+)]
+async fn dash(info: web::Path<(String, String)>, client: web::Data<Client>) -> impl Responder {
+    let info = info.into_inner();
+    match mpd::mpd(&client, &info.0).await {
+        Ok(res) => HttpResponse::Ok()
+            .content_type("application/dash+xml")
+            .insert_header((CACHE_CONTROL, "public,max-age=3600"))
+            .body(res),
+        Err(err) => HttpResponse::InternalServerError().body(format!("{:?}", err)),
+    }
+}
+
+#[route(
+    "/video/{vid:[\\w\\-]{6,15}}.{ext:(jpg|webp)}",
+    method = "GET",
+    method = "HEAD"
+)]
+async fn image(req: HttpRequest, info: web::Path<(String, String)>) -> impl Responder {
+    let info = info.into_inner();
+    handler::proxy_image(req, info.0, info.1).await
+}
+
+#[route(
+    "/video/{vid:[\\w\\-]{6,15}}/{itag:\\d+}.{ext:(webm|mp4)}",
+    method = "GET",
+    method = "HEAD"
+)]
+async fn stream(
+    req: HttpRequest,
+    params: web::Query<Quality>,
+    info: web::Path<(String, String, String)>,
+    client: web::Data<Client>,
+) -> impl Responder {
+    let info = info.into_inner();
+    handler::proxy_file(
+        client,
+        req,
+        info.0,
+        info.1,
+        params.lang.as_deref().unwrap_or_default(),
+    )
+    .await
+}
+
+#[route(
+    "/video/{vid:[\\w\\-]{6,15}}/{vitag:\\d+}+{aitag:\\d+}.{ext:(webm|mp4)}",
+    method = "GET",
+    method = "HEAD"
+)]
+async fn streammux(
     req: HttpRequest,
+    params: web::Query<Quality>,
+    info: web::Path<(String, String, String, String)>,
+    client: web::Data<Client>,
+) -> impl Responder {
+    let info = info.into_inner();
+    handler::proxy_mux(
+        client,
+        req,
+        info.0,
+        info.1,
+        info.2,
+        info.3,
+        params.lang.as_deref().unwrap_or_default(),
+    )
+    .await
+}
+
+#[route(
+    "/video/{vid:[\\w\\-]{6,15}}/{itag:\\d+}/index.json",
+    method = "GET",
+    method = "HEAD"
+)]
+async fn segment_index(
+    params: web::Query<Quality>,
     info: web::Path<(String, String)>,
     client: web::Data<Client>,
 ) -> impl Responder {
     let info = info.into_inner();
-    handler::proxy_image(client, req, info.0, info.1).await
+    let lang = params.lang.as_deref().unwrap_or_default();
+    match index::index(&client, &info.0, &info.1, lang).await {
+        Ok(res) => HttpResponse::Ok()
+            .insert_header((CACHE_CONTROL, "public,max-age=3600"))
+            .json(res),
+        Err(err) => HttpResponse::InternalServerError().body(format!("{:?}", err)),
+    }
 }
 
-#[get("/video/{vid:[\\w\\-]{6,15}}/{itag:\\d+}.{ext:(webm|mp4)}")]
-async fn stream(
+#[route(
+    "/video/{vid:[\\w\\-]{6,15}}/{itag:\\d+}/clip.mp4",
+    method = "GET",
+    method = "HEAD"
+)]
+async fn clip(
     req: HttpRequest,
-    info: web::Path<(String, String, String)>,
+    params: web::Query<Quality>,
+    info: web::Path<(String, String)>,
     client: web::Data<Client>,
 ) -> impl Responder {
     let info = info.into_inner();
-    handler::proxy_file(client, req, info.0, info.1).await
+    handler::proxy_clip(
+        client,
+        req,
+        info.0,
+        info.1,
+        params.lang.as_deref().unwrap_or_default(),
+    )
+    .await
+}
+
+// progressive格式转为fMP4分片的HLS
+#[route(
+    "/video/{vid:[\\w\\-]{6,15}}/{itag:\\d+}/index.m3u8",
+    method = "GET",
+    method = "HEAD"
+)]
+async fn fmp4_list(info: web::Path<(String, String)>, client: web::Data<Client>) -> impl Responder {
+    let info = info.into_inner();
+    match vod::progressive(&client, &info.0, &info.1).await {
+        Ok(res) => HttpResponse::Ok()
+            .content_type("application/vnd.apple.mpegurl")
+            .insert_header((CACHE_CONTROL, "public,max-age=3600"))
+            .body(res),
+        Err(err) => HttpResponse::InternalServerError().body(format!("{:?}", err)),
+    }
 }
 
-#[get("/video/{vid:[\\w\\-]{6,15}}/{itag:\\d+}/{range:\\d+-\\d+}.ts")]
+#[route(
+    "/video/{vid:[\\w\\-]{6,15}}/{itag:\\d+}/init.mp4",
+    method = "GET",
+    method = "HEAD"
+)]
+async fn fmp4_init(
+    req: HttpRequest,
+    info: web::Path<(String, String)>,
+    client: web::Data<Client>,
+) -> impl Responder {
+    let info = info.into_inner();
+    handler::proxy_fragment(client, req, info.0, info.1, None).await
+}
+
+#[route(
+    "/video/{vid:[\\w\\-]{6,15}}/{itag:\\d+}/{n:\\d+}.m4s",
+    method = "GET",
+    method = "HEAD"
+)]
+async fn fmp4_segment(
+    req: HttpRequest,
+    info: web::Path<(String, String, usize)>,
+    client: web::Data<Client>,
+) -> impl Responder {
+    let info = info.into_inner();
+    handler::proxy_fragment(client, req, info.0, info.1, Some(info.2)).await
+}
+
+#[route(
+    "/video/{vid:[\\w\\-]{6,15}}/{itag:\\d+}/{range:\\d+-\\d+}.ts",
+    method = "GET",
+    method = "HEAD"
+)]
 async fn streamts(
     req: HttpRequest,
     info: web::Path<(String, String, String)>,
@@ -100,7 +311,11 @@ async fn streamts(
     handler::proxy_ts(client, req, info.0, info.1, info.2).await
 }
 
-#[get("/video/{vid:[\\w\\-]{6,15}}.{ext:(webm|mp4)}")]
+#[route(
+    "/video/{vid:[\\w\\-]{6,15}}.{ext:(webm|mp4)}",
+    method = "GET",
+    method = "HEAD"
+)]
 async fn streamauto(
     req: HttpRequest,
     params: web::Query<Quality>,
@@ -112,11 +327,58 @@ async fn streamauto(
         client,
         req,
         info.0,
+        info.1,
         params.prefer.as_ref().unwrap_or(&"".to_owned()),
+        params.lang.as_deref().unwrap_or_default(),
     )
     .await
 }
 
+#[route(
+    "/video/{vid:[\\w\\-]{6,15}}.{ext:(m4a|opus)}",
+    method = "GET",
+    method = "HEAD"
+)]
+async fn streamaudio(
+    req: HttpRequest,
+    params: web::Query<Quality>,
+    info: web::Path<(String, String)>,
+    client: web::Data<Client>,
+) -> impl Responder {
+    let info = info.into_inner();
+    handler::proxy_audio(
+        client,
+        req,
+        info.0,
+        info.1,
+        params.lang.as_deref().unwrap_or_default(),
+    )
+    .await
+}
+
+// 运行时查看及修改限速配置, 修改需要请求头x-token与环境变量RATE_TOKEN一致
+#[route("/rate", method = "GET", method = "HEAD")]
+async fn rate() -> impl Responder {
+    HttpResponse::Ok().json(shape::config())
+}
+
+#[post("/rate")]
+async fn rate_update(req: HttpRequest, config: web::Json<shape::Config>) -> impl Responder {
+    let token = env::var("RATE_TOKEN").unwrap_or_default();
+    let given = req.headers().get("x-token").and_then(|v| v.to_str().ok());
+    if token.is_empty() || given != Some(token.as_str()) {
+        return HttpResponse::Forbidden().body("invalid token");
+    }
+    shape::set_config(config.into_inner());
+    HttpResponse::Ok().json(shape::config())
+}
+
+// 各上游节点的首字节时间统计
+#[route("/hosts", method = "GET", method = "HEAD")]
+async fn hosts() -> impl Responder {
+    HttpResponse::Ok().json(hedge::stats())
+}
+
 #[post("/echo")]
 async fn echo(req_body: String) -> impl Responder {
     HttpResponse::Ok().body(req_body)
@@ -0,0 +1,247 @@
+use super::chunk::{self, Body, Source};
+use super::limit::Limits;
+use crate::cache::disk::{self, Entry};
+use crate::util;
+use actix_web::rt::{self, time};
+use actix_web::web::Bytes;
+use futures_util::stream::StreamExt;
+use std::collections::HashMap;
+use std::env;
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+// 每次预读的大小, 环境变量READ_AHEAD配置, 默认0为不开启
+static SIZE: LazyLock<u64> = LazyLock::new(|| {
+    env::var("READ_AHEAD")
+        .map(|v| util::parse_size(&v))
+        .unwrap_or_default()
+});
+
+// 所有预读数据占用内存的上限
+static MEMORY: LazyLock<u64> = LazyLock::new(|| {
+    env::var("READ_AHEAD_MEMORY")
+        .map(|v| util::parse_size(&v))
+        .ok()
+        .filter(|v| *v > 0)
+        .unwrap_or(256 << 20)
+});
+
+// 预读的数据没有被使用时保留的时间
+const TTL: Duration = Duration::from_secs(60);
+
+struct Ahead {
+    start: u64,
+    // 预读中为None
+    data: Option<Bytes>,
+    // 预读完成时通知等待的请求
+    done: watch::Receiver<bool>,
+    // 占用的内存, 预读中按请求的大小计算
+    size: u64,
+    t: Instant,
+}
+
+// key为视频/itag/音轨, 每个流只保留一段预读
+static BUFFERS: LazyLock<Mutex<HashMap<String, Ahead>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+pub fn enabled() -> bool {
+    *SIZE > 0
+}
+
+// 取出从start开始的预读数据, 不超过end, 剩余的部分继续保留
+// 正在预读这一段时等待预读完成, 最多等待wait
+pub async fn take(key: &str, start: u64, end: u64, wait: Duration) -> Option<Bytes> {
+    let mut done = {
+        let mut buffers = BUFFERS.lock().unwrap();
+        buffers.retain(|_, v| v.data.is_none() || v.t.elapsed() < TTL);
+        let a = buffers.get(key)?;
+        if a.start > start || start >= a.start + a.size {
+            return None;
+        }
+        if a.data.is_some() {
+            return split(&mut buffers, key, start, end);
+        }
+        a.done.clone()
+    };
+    let _ = time::timeout(wait, done.wait_for(|v| *v)).await;
+    split(&mut BUFFERS.lock().unwrap(), key, start, end)
+}
+
+fn split(buffers: &mut HashMap<String, Ahead>, key: &str, start: u64, end: u64) -> Option<Bytes> {
+    let a = buffers.get(key)?;
+    let data = a.data.as_ref()?;
+    let a_end = a.start + data.len() as u64;
+    if start < a.start || start >= a_end {
+        return None;
+    }
+    let a = buffers.remove(key)?;
+    let data = a.data?;
+    let from = (start - a.start) as usize;
+    let to = (end + 1).min(a_end);
+    if to < a_end {
+        let rest = data.slice((to - a.start) as usize..);
+        buffers.insert(
+            key.to_owned(),
+            Ahead {
+                start: to,
+                size: rest.len() as u64,
+                data: Some(rest),
+                done: a.done,
+                t: a.t,
+            },
+        );
+    }
+    Some(data.slice(from..(to - a.start) as usize))
+}
+
+// 在后台获取[start, start+SIZE), 开启磁盘缓存时写入磁盘, 否则保存在内存中
+pub fn prefetch(
+    key: String,
+    src: Source,
+    cache: Option<Arc<Entry>>,
+    start: u64,
+    total: u64,
+    limits: Limits,
+) {
+    if !enabled() || start >= total {
+        return;
+    }
+    let end = total.min(start + *SIZE) - 1;
+    if let Some(entry) = cache {
+        rt::spawn(async move {
+            if let Ok((_, body)) = disk::open(entry, src, start, end, limits).await {
+                drain(body).await;
+            }
+        });
+        return;
+    }
+    let size = end - start + 1;
+    let tx = {
+        let mut buffers = BUFFERS.lock().unwrap();
+        buffers.retain(|_, v| v.data.is_none() || v.t.elapsed() < TTL);
+        // 已经预读到或正在预读这一段时不再重复
+        if buffers
+            .get(&key)
+            .is_some_and(|a| a.start <= start && start < a.start + a.size)
+        {
+            return;
+        }
+        let used: u64 = buffers.values().map(|v| v.size).sum();
+        if used + size > *MEMORY {
+            return;
+        }
+        let (tx, done) = watch::channel(false);
+        buffers.insert(
+            key.clone(),
+            Ahead {
+                start,
+                data: None,
+                done,
+                size,
+                t: Instant::now(),
+            },
+        );
+        tx
+    };
+    rt::spawn(async move {
+        let data = match chunk::open(src, start, end, limits, 1).await {
+            Ok((_, body)) => collect(body, size).await,
+            Err(_) => None,
+        };
+        let mut buffers = BUFFERS.lock().unwrap();
+        let _ = tx.send(true);
+        // 期间被新的预读替换时放弃
+        if !buffers
+            .get(&key)
+            .is_some_and(|a| a.start == start && a.data.is_none())
+        {
+            return;
+        }
+        match data {
+            Some(data) => {
+                buffers.insert(
+                    key,
+                    Ahead {
+                        start,
+                        data: Some(data),
+                        done: tx.subscribe(),
+                        size,
+                        t: Instant::now(),
+                    },
+                );
+            }
+            None => {
+                buffers.remove(&key);
+            }
+        }
+    });
+}
+
+async fn collect(mut body: Body, size: u64) -> Option<Bytes> {
+    let mut buf = Vec::with_capacity(size as usize);
+    while let Some(b) = body.next().await {
+        buf.extend_from_slice(&b.ok()?);
+    }
+    Some(Bytes::from(buf))
+}
+
+async fn drain(mut body: Body) {
+    while let Some(Ok(_)) = body.next().await {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn insert(
+        key: &str,
+        start: u64,
+        data: Option<&'static [u8]>,
+        size: u64,
+    ) -> watch::Sender<bool> {
+        let (tx, done) = watch::channel(data.is_some());
+        BUFFERS.lock().unwrap().insert(
+            key.to_owned(),
+            Ahead {
+                start,
+                data: data.map(Bytes::from_static),
+                done,
+                size,
+                t: Instant::now(),
+            },
+        );
+        tx
+    }
+
+    #[actix_web::test]
+    async fn take_in_parts() {
+        let key = "take_in_parts";
+        let _tx = insert(key, 100, Some(b"0123456789"), 10);
+        let wait = Duration::ZERO;
+        // 不包含start时不取出
+        assert_eq!(take(key, 99, 200, wait).await, None);
+        assert_eq!(take(key, 110, 200, wait).await, None);
+        assert_eq!(take(key, 100, 103, wait).await.unwrap(), &b"0123"[..]);
+        // 剩余部分继续保留, 从中间开始时丢弃之前的部分
+        assert_eq!(take(key, 106, 200, wait).await.unwrap(), &b"6789"[..]);
+        assert_eq!(take(key, 106, 200, wait).await, None);
+        assert!(!BUFFERS.lock().unwrap().contains_key(key));
+    }
+
+    #[actix_web::test]
+    async fn wait_pending() {
+        let key = "wait_pending";
+        let tx = insert(key, 0, None, 4);
+        assert_eq!(take(key, 0, 3, Duration::from_millis(10)).await, None);
+        rt::spawn(async move {
+            time::sleep(Duration::from_millis(50)).await;
+            let mut buffers = BUFFERS.lock().unwrap();
+            let a = buffers.get_mut(key).unwrap();
+            a.data = Some(Bytes::from_static(b"abcd"));
+            let _ = tx.send(true);
+        });
+        let data = take(key, 1, 3, Duration::from_secs(5)).await;
+        assert_eq!(data.unwrap(), &b"bcd"[..]);
+    }
+}
@@ -0,0 +1,493 @@
+use super::hedge;
+use super::limit::{self, Limits};
+use crate::parser::{self, StreamItem};
+use actix_web::http::StatusCode;
+use actix_web::http::header::HeaderMap;
+use actix_web::rt::time;
+use actix_web::web::{self, Bytes};
+use awc::Client;
+use awc::error::PayloadError;
+use core::time::Duration;
+use futures_util::future::{self, LocalBoxFuture};
+use futures_util::stream::{self, LocalBoxStream, StreamExt};
+use std::env;
+use std::io;
+use std::iter;
+use std::rc::Rc;
+use std::sync::LazyLock;
+use std::time::Instant;
+use tokio::sync::{Semaphore, mpsc};
+
+// googlevideo对大的或者不限范围的请求会限速到接近实时码率, 所以分段请求再拼接
+pub static CHUNK_SIZE: LazyLock<u64> = LazyLock::new(|| {
+    env::var("CHUNK_SIZE")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|v| *v > 0)
+        .unwrap_or(10 << 20)
+});
+
+// 并行下载时所有请求共享的上游连接数, 避免一个下载占满
+static PARALLEL: LazyLock<Semaphore> = LazyLock::new(|| {
+    Semaphore::new(
+        env::var("PARALLEL_LIMIT")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(16),
+    )
+});
+
+pub const MAX_PARALLEL: usize = 8;
+
+// 上游中断或停滞后, 没有任何进展的情况下最多重试的次数
+const RETRY: u32 = 3;
+
+type Upstream = LocalBoxStream<'static, Result<Bytes, PayloadError>>;
+
+pub type Body = LocalBoxStream<'static, Result<Bytes, io::Error>>;
+
+// 重新解析获取新的上游地址
+pub type Refresh = Rc<dyn Fn() -> LocalBoxFuture<'static, Option<String>>>;
+
+#[derive(Clone)]
+pub struct Source {
+    pub url: String,
+    pub refresh: Option<Refresh>,
+}
+
+impl Source {
+    // 地址过期(403/410)时刷新地址, 其他错误稍等后重试
+    async fn recover(&mut self, err: &io::Error, retry: u32) {
+        println!("resume {}: {}", retry, err);
+        if err.kind() == io::ErrorKind::PermissionDenied
+            && let Some(refresh) = &self.refresh
+            && let Some(url) = refresh().await
+        {
+            self.url = url;
+            return;
+        }
+        time::sleep(Duration::from_millis(500 * retry as u64)).await;
+    }
+}
+
+// 地址过期时重新解析该视频得到同一itag及音轨的新地址
+pub fn source(client: &web::Data<Client>, vid: &str, item: &StreamItem) -> Source {
+    let client = client.clone();
+    let vid = vid.to_owned();
+    let itag = item.itag.clone();
+    let lang = item.lang().to_owned();
+    Source {
+        url: item.url.clone(),
+        refresh: Some(Rc::new(move || {
+            let (client, vid, itag, lang) =
+                (client.clone(), vid.clone(), itag.clone(), lang.clone());
+            Box::pin(async move { parser::refresh_url(&client, &vid, &itag, &lang).await })
+        })),
+    }
+}
+
+struct State {
+    src: Source,
+    limits: Limits,
+    deadline: Instant,
+    // 下一个要输出的字节
+    pos: u64,
+    end: u64,
+    // 当前子请求的结束位置
+    sub_end: u64,
+    cur: Option<Upstream>,
+}
+
+impl State {
+    fn stop(mut self) -> Self {
+        self.pos = self.end + 1;
+        self.cur = None;
+        self
+    }
+}
+
+async fn get(
+    url: &str,
+    start: u64,
+    end: u64,
+    limits: Limits,
+) -> Result<(HeaderMap, Upstream), io::Error> {
+    let client = limits.client();
+    let res = hedge::send(url, |url| {
+        client
+            .get(format!("{}&range={}-{}", url, start, end))
+            .no_decompress()
+            .timeout(limits.request())
+    })
+    .await
+    .map_err(|e| io::Error::other(e.to_string()))?;
+    let status = res.status();
+    if !status.is_success() {
+        let kind = match status {
+            StatusCode::FORBIDDEN | StatusCode::GONE => io::ErrorKind::PermissionDenied,
+            _ => io::ErrorKind::Other,
+        };
+        return Err(io::Error::new(
+            kind,
+            format!("upstream {} {}-{}", status, start, end),
+        ));
+    }
+    Ok((res.headers().clone(), res.boxed_local()))
+}
+
+// 请求[start, end]区间, 首个子请求在返回前完成, 以便获取上游的响应头和错误
+// parallel大于1时同时发起多个子请求, 按顺序输出
+pub async fn open(
+    src: Source,
+    start: u64,
+    end: u64,
+    limits: Limits,
+    parallel: usize,
+) -> Result<(HeaderMap, Body), io::Error> {
+    if parallel > 1 && end - start >= *CHUNK_SIZE {
+        let n = parallel.min(MAX_PARALLEL);
+        return open_parallel(src, start, end, limits, n).await;
+    }
+    open_sequential(src, start, end, limits).await
+}
+
+async fn open_sequential(
+    src: Source,
+    start: u64,
+    end: u64,
+    limits: Limits,
+) -> Result<(HeaderMap, Body), io::Error> {
+    let sub_end = end.min(start + *CHUNK_SIZE - 1);
+    let (headers, cur) = get(&src.url, start, sub_end, limits).await?;
+    let state = State {
+        src,
+        limits,
+        deadline: limits.deadline(),
+        pos: start,
+        end,
+        sub_end,
+        cur: Some(cur),
+    };
+    Ok((headers, stream::unfold(state, next).boxed_local()))
+}
+
+async fn next(mut s: State) -> Option<(Result<Bytes, io::Error>, State)> {
+    let mut retry = 0;
+    loop {
+        if s.pos > s.end {
+            return None;
+        }
+        let err = match s.cur.as_mut() {
+            None => {
+                s.sub_end = s.end.min(s.pos + *CHUNK_SIZE - 1);
+                match get(&s.src.url, s.pos, s.sub_end, s.limits).await {
+                    Ok((_, cur)) => {
+                        s.cur = Some(cur);
+                        continue;
+                    }
+                    Err(e) => e,
+                }
+            }
+            Some(cur) => match limit::read(cur, s.limits.idle, s.deadline).await {
+                Ok(Some(mut b)) => {
+                    // 上游返回的数据多于请求的范围时截断
+                    let left = s.sub_end + 1 - s.pos;
+                    if b.len() as u64 > left {
+                        b.truncate(left as usize);
+                    }
+                    s.pos += b.len() as u64;
+                    if s.pos > s.sub_end {
+                        s.cur = None;
+                    }
+                    return Some((Ok(b), s));
+                }
+                Ok(None) => io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    format!("upstream closed at {} of {}", s.pos, s.sub_end),
+                ),
+                Err(e) => io::Error::new(e.kind(), format!("{} at {} of {}", e, s.pos, s.sub_end)),
+            },
+        };
+        // 从已输出的位置重新请求剩余部分, 超过总时长则不再重试
+        s.cur = None;
+        if retry < RETRY && Instant::now() < s.deadline {
+            retry += 1;
+            s.src.recover(&err, retry).await;
+            continue;
+        }
+        println!("abort {}: {}", s.src.url, err);
+        return Some((Err(err), s.stop()));
+    }
+}
+
+// 完整读取[start, end], 用于需要整段解析的分片, 与open一样可中断续传
+pub async fn read(src: Source, start: u64, end: u64, limits: Limits) -> Result<Vec<u8>, io::Error> {
+    let (_, mut body) = open(src, start, end, limits, 1).await?;
+    let mut buf = Vec::with_capacity((end - start + 1) as usize);
+    while let Some(b) = body.next().await {
+        buf.extend_from_slice(&b?);
+    }
+    Ok(buf)
+}
+
+// 每个并行子请求最多缓冲的块数, 大于SLICE的块切开后再缓冲, 即每个子请求最多占用约1MB
+const BUFFER: usize = 16;
+const SLICE: usize = 64 << 10;
+
+// 在后台读取一个子区间, 持有全局许可直到完成, 中断时从已获取的位置继续
+// 缓冲满时暂停读取上游, 等待客户端读取前面的子区间
+fn spawn_part(src: Source, start: u64, end: u64, limits: Limits, deadline: Instant) -> Body {
+    let (tx, rx) = mpsc::channel(BUFFER);
+    actix_web::rt::spawn(async move {
+        let Ok(_permit) = PARALLEL.acquire().await else {
+            return;
+        };
+        if tx.is_closed() {
+            return;
+        }
+        let state = State {
+            src,
+            limits,
+            deadline,
+            pos: start,
+            end,
+            sub_end: end,
+            cur: None,
+        };
+        let mut body = stream::unfold(state, next).boxed_local();
+        while let Some(item) = body.next().await {
+            let mut b = match item {
+                Ok(b) => b,
+                Err(e) => {
+                    let _ = tx.send(Err(e)).await;
+                    return;
+                }
+            };
+            while !b.is_empty() {
+                let part = b.split_to(b.len().min(SLICE));
+                if tx.send(Ok(part)).await.is_err() {
+                    return;
+                }
+            }
+        }
+    });
+    stream::unfold(rx, |mut rx| async move { rx.recv().await.map(|b| (b, rx)) }).boxed_local()
+}
+
+// buffered保证按顺序输出, 包括正在输出的在内最多n个子请求, 客户端读取慢时各子请求缓冲满后暂停
+async fn open_parallel(
+    src: Source,
+    start: u64,
+    end: u64,
+    limits: Limits,
+    n: usize,
+) -> Result<(HeaderMap, Body), io::Error> {
+    let chunk = *CHUNK_SIZE;
+    let first_end = start + chunk - 1;
+    let deadline = limits.deadline();
+    // 首个子请求直接读取, 以便获取上游的响应头和错误, 同样持有许可直到读完
+    let permit = PARALLEL.acquire().await.map_err(io::Error::other)?;
+    let (headers, cur) = get(&src.url, start, first_end, limits).await?;
+    let state = State {
+        src: src.clone(),
+        limits,
+        deadline,
+        pos: start,
+        end: first_end,
+        sub_end: first_end,
+        cur: Some(cur),
+    };
+    let first: Body = stream::unfold(state, next)
+        .map(move |b| {
+            let _ = &permit;
+            b
+        })
+        .boxed_local();
+    let parts = (first_end + 1..=end)
+        .step_by(chunk as usize)
+        .map(move |pos| spawn_part(src.clone(), pos, end.min(pos + chunk - 1), limits, deadline));
+    let bodies = iter::once(first).chain(parts).map(future::ready);
+    Ok((
+        headers,
+        stream::iter(bodies).buffered(n).flatten().boxed_local(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{App, HttpResponse, HttpServer};
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    // 本地上游, 按query参数range返回区间, 记录收到的range
+    // expired=1时从第二个子请求起返回403, cut=1时第一个请求只返回1000字节后中断
+    struct Upstream {
+        addr: String,
+        ranges: Arc<Mutex<Vec<(u64, u64)>>>,
+    }
+
+    fn byte(i: u64) -> u8 {
+        (i % 251) as u8
+    }
+
+    fn content(start: u64, end: u64) -> Vec<u8> {
+        (start..=end).map(byte).collect()
+    }
+
+    fn serve() -> Upstream {
+        let ranges = Arc::new(Mutex::new(Vec::new()));
+        let log = ranges.clone();
+        let cut = Arc::new(AtomicBool::new(false));
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = format!("http://{}/f?id=1", listener.local_addr().unwrap());
+        let server = HttpServer::new(move || {
+            let (log, cut) = (log.clone(), cut.clone());
+            App::new().default_service(web::to(move |q: web::Query<HashMap<String, String>>| {
+                let (log, cut) = (log.clone(), cut.clone());
+                async move {
+                    let (a, b) = q["range"].split_once('-').unwrap();
+                    let (a, b): (u64, u64) = (a.parse().unwrap(), b.parse().unwrap());
+                    log.lock().unwrap().push((a, b));
+                    if q.contains_key("expired") && a > 0 {
+                        return HttpResponse::Forbidden().finish();
+                    }
+                    if q.contains_key("cut") && !cut.swap(true, Ordering::SeqCst) {
+                        let head = Bytes::from(content(a, a + 999));
+                        // 先发出响应头及数据, 再中断连接
+                        let reset = async {
+                            time::sleep(Duration::from_millis(100)).await;
+                            Err(io::Error::other("reset"))
+                        };
+                        let body = stream::once(async { Ok(head) }).chain(stream::once(reset));
+                        return HttpResponse::Ok().streaming(body);
+                    }
+                    HttpResponse::Ok().body(content(a, b))
+                }
+            }))
+        })
+        .workers(1)
+        .listen(listener)
+        .unwrap()
+        .run();
+        actix_web::rt::spawn(server);
+        Upstream { addr, ranges }
+    }
+
+    fn source(url: &str) -> Source {
+        Source {
+            url: url.to_owned(),
+            refresh: None,
+        }
+    }
+
+    async fn collect(mut body: Body) -> Vec<u8> {
+        let mut out = Vec::new();
+        while let Some(b) = body.next().await {
+            out.extend_from_slice(&b.unwrap());
+        }
+        out
+    }
+
+    #[actix_web::test]
+    async fn sequential_chunks() {
+        let up = serve();
+        let c = *CHUNK_SIZE;
+        let end = 2 * c + 12344;
+        let (_, body) = open(source(&up.addr), 0, end, limit::FILE, 1)
+            .await
+            .unwrap();
+        assert!(collect(body).await == content(0, end));
+        let ranges = up.ranges.lock().unwrap().clone();
+        assert_eq!(ranges, vec![(0, c - 1), (c, 2 * c - 1), (2 * c, end)]);
+    }
+
+    #[actix_web::test]
+    async fn small_range() {
+        let up = serve();
+        let data = read(source(&up.addr), 100, 199, limit::FILE).await.unwrap();
+        assert_eq!(data, content(100, 199));
+        assert_eq!(up.ranges.lock().unwrap().clone(), vec![(100, 199)]);
+    }
+
+    #[actix_web::test]
+    async fn parallel_in_order() {
+        let up = serve();
+        let c = *CHUNK_SIZE;
+        let end = 3 * c + 99;
+        let (_, body) = open(source(&up.addr), 0, end, limit::FILE, 3)
+            .await
+            .unwrap();
+        assert!(collect(body).await == content(0, end));
+        let mut ranges = up.ranges.lock().unwrap().clone();
+        ranges.sort();
+        let expected = vec![(0, c - 1), (c, 2 * c - 1), (2 * c, 3 * c - 1), (3 * c, end)];
+        assert_eq!(ranges, expected);
+    }
+
+    #[actix_web::test]
+    async fn resume_after_cut() {
+        let up = serve();
+        let c = *CHUNK_SIZE;
+        let end = c + 5000;
+        let url = format!("{}&cut=1", up.addr);
+        let (_, body) = open(source(&url), 0, end, limit::FILE, 1).await.unwrap();
+        assert!(collect(body).await == content(0, end));
+        let ranges = up.ranges.lock().unwrap().clone();
+        // 从中断的位置重新开始一个完整的子请求
+        assert_eq!(ranges, vec![(0, c - 1), (1000, c + 999), (c + 1000, end)]);
+    }
+
+    #[actix_web::test]
+    async fn parallel_prefetch() {
+        let up = serve();
+        let c = *CHUNK_SIZE;
+        let end = 2 * c + 99;
+        let (_, mut body) = open(source(&up.addr), 0, end, limit::FILE, 2)
+            .await
+            .unwrap();
+        // 读取首个子区间时已开始请求下一个, 但不会超过n个
+        body.next().await.unwrap().unwrap();
+        time::sleep(Duration::from_millis(200)).await;
+        let ranges = up.ranges.lock().unwrap().clone();
+        assert_eq!(ranges, vec![(0, c - 1), (c, 2 * c - 1)]);
+        let rest = collect(body).await;
+        assert!(rest.len() as u64 > c);
+        assert_eq!(up.ranges.lock().unwrap().len(), 3);
+    }
+
+    #[actix_web::test]
+    async fn parallel_resume_after_cut() {
+        let up = serve();
+        let c = *CHUNK_SIZE;
+        let end = c + 5000;
+        let url = format!("{}&cut=1", up.addr);
+        let (_, body) = open(source(&url), 0, end, limit::FILE, 2).await.unwrap();
+        assert!(collect(body).await == content(0, end));
+        assert!(up.ranges.lock().unwrap().contains(&(1000, c - 1)));
+    }
+
+    #[actix_web::test]
+    async fn refresh_expired() {
+        let up = serve();
+        let c = *CHUNK_SIZE;
+        let end = c + 5000;
+        let fresh = format!("{}&fresh=1", up.addr);
+        let src = Source {
+            url: format!("{}&expired=1", up.addr),
+            refresh: Some(Rc::new(move || {
+                let url = fresh.clone();
+                Box::pin(async move { Some(url) })
+            })),
+        };
+        let (_, body) = open(src, 0, end, limit::FILE, 1).await.unwrap();
+        assert!(collect(body).await == content(0, end));
+        // 没有refresh时重试后放弃
+        let src = source(&format!("{}&expired=1", up.addr));
+        let (_, body) = open(src, 0, end, limit::FILE, 1).await.unwrap();
+        let results: Vec<Result<Bytes, io::Error>> = body.collect().await;
+        let err = results.last().unwrap().as_ref().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+    }
+}
@@ -0,0 +1,423 @@
+use super::chunk::{self, Body, Source};
+use super::limit::Limits;
+use actix_web::http::header::HeaderMap;
+use actix_web::rt::{self, time};
+use actix_web::web::Bytes;
+use futures_util::stream::{self, StreamExt};
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::Duration;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::{Notify, watch};
+
+// 广播的每条消息最大字节数及缓冲的消息数, 落后超过缓冲的客户端改用独立连接
+const SLICE: usize = 64 << 10;
+const CAPACITY: usize = 64;
+
+// 缓冲已满时等待最慢的客户端读取的时间, 超时后继续读取上游, 最慢的客户端将落后并改用独立连接
+const LAG_WAIT: Duration = Duration::from_secs(2);
+
+// 错误需要Clone才能广播, 只保留错误信息
+type Msg = Result<Bytes, String>;
+
+// 上游的响应头, 或首个请求失败的错误信息
+type Opened = Option<Result<HeaderMap, String>>;
+
+struct Progress {
+    // 已广播的字节数
+    pos: u64,
+}
+
+struct Flight {
+    tx: broadcast::Sender<Msg>,
+    progress: Mutex<Progress>,
+    // 客户端每读取一条消息通知一次, 缓冲已满时pump等待此通知
+    drained: Notify,
+    // 发起者收到上游响应头后设置, 在此之前加入的客户端等待
+    opened: watch::Sender<Opened>,
+}
+
+// 发起者在收到响应头之前被取消时, 通知已加入的客户端并移除
+struct Opening<'a> {
+    key: &'a str,
+    flight: &'a Arc<Flight>,
+    done: bool,
+}
+
+impl Drop for Opening<'_> {
+    fn drop(&mut self) {
+        if !self.done {
+            fail(self.key, self.flight, "cancelled".to_owned());
+        }
+    }
+}
+
+// 正在进行的上游请求, 相同的(视频, itag, range)共享一个上游连接
+static FLIGHTS: LazyLock<Mutex<HashMap<String, Arc<Flight>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+struct Sub {
+    src: Source,
+    limits: Limits,
+    flight: Arc<Flight>,
+    // 改用独立连接后释放, 不再占用广播的缓冲, 所有客户端都释放后pump停止
+    rx: Option<broadcast::Receiver<Msg>>,
+    // 下一个要输出的字节
+    pos: u64,
+    end: u64,
+    // 落后太多或共享的上游提前结束后, 改用独立连接获取剩余部分
+    fallback: Option<Body>,
+}
+
+// 与chunk::open相同, 但相同key的请求共享同一个上游连接
+// 已经开始的请求, 如果广播的数据不超过一个CHUNK_SIZE, 新的客户端用独立连接补齐已错过的部分后加入
+pub async fn open(
+    key: String,
+    src: Source,
+    start: u64,
+    end: u64,
+    limits: Limits,
+    parallel: usize,
+) -> Result<(HeaderMap, Body), io::Error> {
+    let (flight, rx, pos, opened, owner) = {
+        let mut flights = FLIGHTS.lock().unwrap();
+        let joined = flights.get(&key).and_then(|f| {
+            let p = f.progress.lock().unwrap();
+            (p.pos < *chunk::CHUNK_SIZE)
+                .then(|| (f.clone(), f.tx.subscribe(), p.pos, f.opened.subscribe()))
+        });
+        match joined {
+            Some((f, rx, pos, opened)) => (f, rx, pos, opened, false),
+            None => {
+                let (tx, rx) = broadcast::channel(CAPACITY);
+                let (opened, wait) = watch::channel(None);
+                let f = Arc::new(Flight {
+                    tx,
+                    progress: Mutex::new(Progress { pos: 0 }),
+                    drained: Notify::new(),
+                    opened,
+                });
+                flights.insert(key.clone(), f.clone());
+                (f, rx, 0, wait, true)
+            }
+        }
+    };
+    let sub = Sub {
+        src: src.clone(),
+        limits,
+        flight: flight.clone(),
+        rx: Some(rx),
+        pos: start + pos,
+        end,
+        fallback: None,
+    };
+    let shared = stream::unfold(sub, next);
+    if !owner {
+        let headers = wait_opened(opened).await?;
+        // 已错过的部分用独立连接获取
+        let prefix = match pos {
+            0 => stream::empty().boxed_local(),
+            _ => chunk::open(src, start, start + pos - 1, limits, 1).await?.1,
+        };
+        let body = prefix.chain(shared).boxed_local();
+        return Ok((headers, body));
+    }
+    let res = {
+        let mut opening = Opening {
+            key: &key,
+            flight: &flight,
+            done: false,
+        };
+        let res = chunk::open(src, start, end, limits, parallel).await;
+        opening.done = true;
+        res
+    };
+    match res {
+        Ok((headers, body)) => {
+            flight.opened.send_replace(Some(Ok(headers.clone())));
+            rt::spawn(pump(key, flight, body));
+            Ok((headers, shared.boxed_local()))
+        }
+        Err(e) => {
+            fail(&key, &flight, e.to_string());
+            Err(e)
+        }
+    }
+}
+
+// 等待发起者收到上游响应头, 发起者失败时返回同样的错误
+async fn wait_opened(mut opened: watch::Receiver<Opened>) -> Result<HeaderMap, io::Error> {
+    let res = opened
+        .wait_for(Option::is_some)
+        .await
+        .map_err(io::Error::other)?
+        .clone();
+    res.unwrap_or_else(|| Err("cancelled".to_owned()))
+        .map_err(io::Error::other)
+}
+
+fn fail(key: &str, flight: &Arc<Flight>, err: String) {
+    let _ = flight.tx.send(Err(err.clone()));
+    flight.opened.send_replace(Some(Err(err)));
+    remove(key, flight);
+}
+
+fn remove(key: &str, flight: &Arc<Flight>) {
+    let mut flights = FLIGHTS.lock().unwrap();
+    if flights.get(key).is_some_and(|f| Arc::ptr_eq(f, flight)) {
+        flights.remove(key);
+    }
+}
+
+// 读取上游并广播, 缓冲已满时等待客户端读取, 上游的读取速度不超过客户端
+// 所有客户端都断开或改用独立连接后停止
+async fn pump(key: String, flight: Arc<Flight>, mut body: Body) {
+    'read: loop {
+        while flight.tx.len() >= CAPACITY {
+            if flight.tx.receiver_count() == 0 {
+                break 'read;
+            }
+            if time::timeout(LAG_WAIT, flight.drained.notified())
+                .await
+                .is_err()
+            {
+                break;
+            }
+        }
+        if flight.tx.receiver_count() == 0 {
+            break;
+        }
+        let Some(item) = body.next().await else {
+            break;
+        };
+        let b = match item {
+            Ok(b) => b,
+            Err(e) => {
+                let _ = flight.tx.send(Err(e.to_string()));
+                break;
+            }
+        };
+        for i in (0..b.len()).step_by(SLICE) {
+            let part = b.slice(i..b.len().min(i + SLICE));
+            let mut p = flight.progress.lock().unwrap();
+            let len = part.len() as u64;
+            if flight.tx.send(Ok(part)).is_err() {
+                break 'read;
+            }
+            p.pos += len;
+        }
+    }
+    remove(&key, &flight);
+}
+
+async fn next(mut s: Sub) -> Option<(Result<Bytes, io::Error>, Sub)> {
+    loop {
+        if s.pos > s.end {
+            return None;
+        }
+        if let Some(body) = s.fallback.as_mut() {
+            return match body.next().await {
+                Some(Ok(b)) => {
+                    s.pos += b.len() as u64;
+                    Some((Ok(b), s))
+                }
+                Some(Err(e)) => {
+                    s.pos = s.end + 1;
+                    Some((Err(e), s))
+                }
+                None => None,
+            };
+        }
+        let rx = s.rx.as_mut()?;
+        let reason = match rx.recv().await {
+            Ok(Ok(b)) => {
+                s.flight.drained.notify_one();
+                s.pos += b.len() as u64;
+                return Some((Ok(b), s));
+            }
+            Ok(Err(e)) => {
+                s.pos = s.end + 1;
+                return Some((Err(io::Error::other(e)), s));
+            }
+            Err(RecvError::Lagged(n)) => format!("lagged {} messages", n),
+            Err(RecvError::Closed) => "closed".to_owned(),
+        };
+        println!("fanout fallback at {} of {}: {}", s.pos, s.end, reason);
+        s.rx = None;
+        s.flight.drained.notify_one();
+        match chunk::open(s.src.clone(), s.pos, s.end, s.limits, 1).await {
+            Ok((_, body)) => s.fallback = Some(body),
+            Err(e) => {
+                s.pos = s.end + 1;
+                return Some((Err(e), s));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::upstream::limit;
+    use actix_web::{App, HttpResponse, HttpServer, web};
+
+    fn content(start: u64, end: u64) -> Vec<u8> {
+        (start..=end).map(|i| (i % 251) as u8).collect()
+    }
+
+    type Ranges = Arc<Mutex<Vec<(u64, u64)>>>;
+
+    // 本地上游, 记录收到的range, 响应头发出后延迟100ms再发送数据, 使第二个客户端在广播开始前加入
+    fn serve() -> (String, Ranges) {
+        let ranges = Arc::new(Mutex::new(Vec::new()));
+        let log = ranges.clone();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = format!("http://{}/f?id=1", listener.local_addr().unwrap());
+        let server = HttpServer::new(move || {
+            let log = log.clone();
+            App::new().default_service(web::to(move |q: web::Query<HashMap<String, String>>| {
+                let log = log.clone();
+                async move {
+                    let (a, b) = q["range"].split_once('-').unwrap();
+                    let (a, b): (u64, u64) = (a.parse().unwrap(), b.parse().unwrap());
+                    log.lock().unwrap().push((a, b));
+                    if q.contains_key("slow") {
+                        time::sleep(Duration::from_millis(200)).await;
+                    }
+                    let body = stream::once(async move {
+                        time::sleep(Duration::from_millis(100)).await;
+                        Ok::<_, io::Error>(Bytes::from(content(a, b)))
+                    });
+                    HttpResponse::Ok()
+                        .insert_header(("x-range", format!("{}-{}", a, b)))
+                        .streaming(body)
+                }
+            }))
+        })
+        .workers(1)
+        .listen(listener)
+        .unwrap()
+        .run();
+        rt::spawn(server);
+        (addr, ranges)
+    }
+
+    fn source(url: &str) -> Source {
+        Source {
+            url: url.to_owned(),
+            refresh: None,
+        }
+    }
+
+    async fn collect(mut body: Body) -> Vec<u8> {
+        let mut out = Vec::new();
+        while let Some(b) = body.next().await {
+            out.extend_from_slice(&b.unwrap());
+        }
+        out
+    }
+
+    #[actix_web::test]
+    async fn shared_upstream() {
+        let (addr, ranges) = serve();
+        let end = 200_000;
+        let key = "shared_upstream".to_owned();
+        let (_, a) = open(key.clone(), source(&addr), 0, end, limit::FILE, 1)
+            .await
+            .unwrap();
+        let (_, b) = open(key.clone(), source(&addr), 0, end, limit::FILE, 1)
+            .await
+            .unwrap();
+        let (a, b) = futures_util::join!(collect(a), collect(b));
+        assert!(a == content(0, end));
+        assert!(b == content(0, end));
+        assert_eq!(ranges.lock().unwrap().clone(), vec![(0, end)]);
+        // 广播结束后不再共享
+        assert!(!FLIGHTS.lock().unwrap().contains_key(&key));
+    }
+
+    #[actix_web::test]
+    async fn join_before_headers() {
+        let (addr, ranges) = serve();
+        let url = format!("{}&slow=1", addr);
+        let end = 200_000;
+        let key = "join_before_headers".to_owned();
+        let a = open(key.clone(), source(&url), 0, end, limit::FILE, 1);
+        // 发起者收到响应头之前加入, 等待并得到同样的响应头
+        let b = async {
+            time::sleep(Duration::from_millis(50)).await;
+            open(key.clone(), source(&url), 0, end, limit::FILE, 1).await
+        };
+        let (a, b) = futures_util::join!(a, b);
+        let ((ha, a), (hb, b)) = (a.unwrap(), b.unwrap());
+        assert_eq!(hb.get("x-range"), ha.get("x-range"));
+        assert_eq!(hb.get("x-range").unwrap(), "0-200000");
+        let (a, b) = futures_util::join!(collect(a), collect(b));
+        assert!(a == content(0, end) && b == content(0, end));
+        assert_eq!(ranges.lock().unwrap().clone(), vec![(0, end)]);
+    }
+
+    #[actix_web::test]
+    async fn cancelled_owner() {
+        let (addr, _) = serve();
+        let url = format!("{}&slow=1", addr);
+        let key = "cancelled_owner".to_owned();
+        let a = open(key.clone(), source(&url), 0, 999, limit::FILE, 1);
+        let b = async {
+            time::sleep(Duration::from_millis(50)).await;
+            open(key.clone(), source(&url), 0, 999, limit::FILE, 1).await
+        };
+        // 发起者在收到响应头前被取消, 已加入的客户端得到错误而不是一直等待
+        let a = time::timeout(Duration::from_millis(100), a);
+        let (a, b) = futures_util::join!(a, b);
+        assert!(a.is_err());
+        assert!(b.is_err());
+        assert!(!FLIGHTS.lock().unwrap().contains_key(&key));
+    }
+
+    #[actix_web::test]
+    async fn separate_keys() {
+        let (addr, ranges) = serve();
+        let (_, a) = open(
+            "separate_a".to_owned(),
+            source(&addr),
+            0,
+            999,
+            limit::FILE,
+            1,
+        )
+        .await
+        .unwrap();
+        let (_, b) = open(
+            "separate_b".to_owned(),
+            source(&addr),
+            0,
+            999,
+            limit::FILE,
+            1,
+        )
+        .await
+        .unwrap();
+        let (a, b) = futures_util::join!(collect(a), collect(b));
+        assert!(a == content(0, 999) && b == content(0, 999));
+        assert_eq!(ranges.lock().unwrap().clone(), vec![(0, 999), (0, 999)]);
+    }
+
+    #[actix_web::test]
+    async fn dropped_client() {
+        let (addr, ranges) = serve();
+        let end = 200_000;
+        let key = "dropped_client".to_owned();
+        let (_, a) = open(key.clone(), source(&addr), 0, end, limit::FILE, 1)
+            .await
+            .unwrap();
+        let (_, b) = open(key.clone(), source(&addr), 0, end, limit::FILE, 1)
+            .await
+            .unwrap();
+        // 一个客户端断开不影响其他客户端
+        drop(a);
+        assert!(collect(b).await == content(0, end));
+        assert_eq!(ranges.lock().unwrap().clone(), vec![(0, end)]);
+    }
+}
@@ -0,0 +1,213 @@
+use crate::util;
+use actix_web::dev::{Decompress, Payload};
+use actix_web::rt::time;
+use awc::error::SendRequestError;
+use awc::{ClientRequest, ClientResponse};
+use core::time::Duration;
+use futures_util::future::{self, Either};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::env;
+use std::future::Future;
+use std::sync::{LazyLock, Mutex};
+use std::time::Instant;
+
+// 首字节超过此时间(毫秒)仍未返回时, 向镜像节点发起第二个请求, 0为不开启
+static HEDGE_AFTER: LazyLock<Duration> = LazyLock::new(|| {
+    Duration::from_millis(
+        env::var("HEDGE_AFTER")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(0),
+    )
+});
+
+type Response = Result<ClientResponse<Decompress<Payload>>, SendRequestError>;
+
+// 至少有这么多次记录后才根据统计调整节点顺序
+const MIN_SAMPLES: u64 = 3;
+
+// 平均首字节时间超过最快节点的倍数时, 优先使用最快的节点
+const SLOW_FACTOR: f64 = 2.0;
+
+#[derive(Clone, Default, Serialize)]
+pub struct Stats {
+    pub count: u64,
+    pub errors: u64,
+    // 首字节时间的指数移动平均, 毫秒
+    pub ttfb: f64,
+}
+
+static HOSTS: LazyLock<Mutex<HashMap<String, Stats>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+pub fn stats() -> HashMap<String, Stats> {
+    HOSTS.lock().unwrap().clone()
+}
+
+fn record(host: &str, ttfb: Duration, ok: bool) {
+    let mut hosts = HOSTS.lock().unwrap();
+    let s = hosts.entry(host.to_owned()).or_default();
+    let ms = ttfb.as_secs_f64() * 1000.0;
+    s.ttfb = match s.count {
+        0 => ms,
+        _ => s.ttfb * 0.8 + ms * 0.2,
+    };
+    s.count += 1;
+    if !ok {
+        s.errors += 1;
+    }
+}
+
+// 出错的请求按一次很慢的请求计分
+fn score(host: &str) -> Option<f64> {
+    let hosts = HOSTS.lock().unwrap();
+    let s = hosts.get(host).filter(|s| s.count >= MIN_SAMPLES)?;
+    Some(s.ttfb * (1.0 + 4.0 * s.errors as f64 / s.count as f64))
+}
+
+fn host(url: &str) -> &str {
+    let rest = url.split_once("://").map(|(_, r)| r).unwrap_or(url);
+    rest.split(['/', '?']).next().unwrap_or_default()
+}
+
+// rr{fvip}---{mn}.googlevideo.com 均为同一资源的镜像节点, 签名地址在各节点通用
+fn mirrors(url: &str) -> Vec<String> {
+    let cur = host(url);
+    if !cur.starts_with("rr") || !cur.contains("---") || !cur.ends_with(".googlevideo.com") {
+        return vec![];
+    }
+    let (Some(mn), Some(fvip)) = (util::query_param(url, "mn"), util::query_param(url, "fvip"))
+    else {
+        return vec![];
+    };
+    mn.split(',')
+        .flat_map(|v| v.split("%2C"))
+        .map(|sn| format!("rr{}---{}.googlevideo.com", fvip, sn))
+        .filter(|h| h != cur)
+        .map(|h| url.replacen(cur, &h, 1))
+        .collect()
+}
+
+// 原地址在前, 除非统计显示它明显慢于某个镜像
+fn candidates(url: &str) -> Vec<String> {
+    let mut urls = vec![url.to_owned()];
+    urls.extend(mirrors(url));
+    let scores: Vec<Option<f64>> = urls.iter().map(|u| score(host(u))).collect();
+    let best = scores
+        .iter()
+        .enumerate()
+        .filter_map(|(i, s)| s.map(|s| (i, s)))
+        .min_by(|a, b| a.1.total_cmp(&b.1));
+    if let (Some(cur), Some((i, best))) = (scores[0], best)
+        && cur > best * SLOW_FACTOR
+    {
+        urls.swap(0, i);
+    }
+    urls
+}
+
+async fn timed<F>(host: String, fut: F) -> Response
+where
+    F: Future<Output = Response>,
+{
+    let start = Instant::now();
+    let res = fut.await;
+    let ok = res.as_ref().is_ok_and(|r| !r.status().is_server_error());
+    record(&host, start.elapsed(), ok);
+    res
+}
+
+fn answered(res: &Response) -> bool {
+    res.as_ref().is_ok_and(|r| r.status().is_success())
+}
+
+// 发送请求, 首字节超过HEDGE_AFTER时同时请求一个镜像节点, 使用先返回的一个, 另一个被丢弃即取消
+// make根据地址构造请求, 以便在不同节点上重复
+pub async fn send<M>(url: &str, make: M) -> Response
+where
+    M: Fn(&str) -> ClientRequest,
+{
+    let mut urls = candidates(url);
+    let primary = urls.remove(0);
+    let first = timed(host(&primary).to_owned(), make(&primary).send());
+    let after = *HEDGE_AFTER;
+    let Some(alt) = urls.first().filter(|_| !after.is_zero()) else {
+        return first.await;
+    };
+    let mut first = Box::pin(first);
+    let start = Instant::now();
+    if let Ok(res) = time::timeout(after, &mut first).await {
+        return res;
+    }
+    println!("hedge {} -> {}", host(&primary), host(alt));
+    let second = Box::pin(timed(host(alt).to_owned(), make(alt).send()));
+    match future::select(first, second).await {
+        Either::Left((res, second)) => {
+            if answered(&res) {
+                return res;
+            }
+            second.await
+        }
+        Either::Right((res, first)) => {
+            if answered(&res) {
+                // 被取消的请求至少用了这么久, 也计入统计
+                record(host(&primary), start.elapsed(), true);
+                return res;
+            }
+            first.await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const URL: &str =
+        "https://rr3---sn-a1.googlevideo.com/videoplayback?mn=sn-a1%2Csn-b2&fvip=3&itag=18";
+
+    #[test]
+    fn hosts() {
+        assert_eq!(host(URL), "rr3---sn-a1.googlevideo.com");
+        assert_eq!(host("http://127.0.0.1:8080?x=1"), "127.0.0.1:8080");
+    }
+
+    #[test]
+    fn mirror_hosts() {
+        let urls = mirrors(URL);
+        assert_eq!(urls.len(), 1);
+        assert!(urls[0].starts_with("https://rr3---sn-b2.googlevideo.com/videoplayback?"));
+        assert!(mirrors("https://example.com/x?mn=a,b&fvip=1").is_empty());
+        assert!(mirrors("https://rr1---sn-a1.googlevideo.com/x?itag=18").is_empty());
+    }
+
+    #[test]
+    fn slow_host_last() {
+        let url = URL.replace("sn-a1", "sn-slow").replace("sn-b2", "sn-fast");
+        let (slow, fast) = (
+            "rr3---sn-slow.googlevideo.com",
+            "rr3---sn-fast.googlevideo.com",
+        );
+        // 样本不足时保持原顺序
+        record(slow, Duration::from_millis(900), true);
+        record(fast, Duration::from_millis(100), true);
+        assert_eq!(host(&candidates(&url)[0]), slow);
+        for _ in 1..MIN_SAMPLES {
+            record(slow, Duration::from_millis(900), true);
+            record(fast, Duration::from_millis(100), true);
+        }
+        assert_eq!(host(&candidates(&url)[0]), fast);
+        let s = &stats()[fast];
+        assert_eq!((s.count, s.errors), (MIN_SAMPLES, 0));
+    }
+
+    #[test]
+    fn errors_count_as_slow() {
+        let host = "rr1---sn-err.googlevideo.com";
+        for _ in 0..MIN_SAMPLES {
+            record(host, Duration::from_millis(100), false);
+        }
+        assert_eq!(score(host), Some(500.0));
+    }
+}
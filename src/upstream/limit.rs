@@ -0,0 +1,203 @@
+use actix_web::rt::time;
+use actix_web::web::Bytes;
+use awc::Client;
+use core::time::Duration;
+use futures_util::stream::{self, LocalBoxStream, Stream, StreamExt};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::io;
+use std::time::Instant;
+
+// 建立连接, 收到响应头, 两次收到数据之间, 以及整个传输的时间限制
+#[derive(Clone, Copy)]
+pub struct Limits {
+    pub connect: Duration,
+    pub ttfb: Duration,
+    pub idle: Duration,
+    pub total: Duration,
+}
+
+const fn secs(n: u64) -> Duration {
+    Duration::from_secs(n)
+}
+
+pub const FILE: Limits = Limits {
+    connect: secs(5),
+    ttfb: secs(15),
+    idle: secs(30),
+    total: secs(6 * 3600),
+};
+
+pub const TS: Limits = Limits {
+    connect: secs(5),
+    ttfb: secs(10),
+    idle: secs(10),
+    total: secs(30),
+};
+
+pub const IMAGE: Limits = Limits {
+    connect: secs(3),
+    ttfb: secs(5),
+    idle: secs(5),
+    total: secs(10),
+};
+
+// 不属于以上各类的请求(如player接口)使用的连接超时
+pub const CONNECT: Duration = secs(5);
+
+thread_local! {
+    // awc的连接超时设置在Connector上, 每个worker线程按连接超时各保留一个Client
+    static CLIENTS: RefCell<HashMap<Duration, Client>> = RefCell::new(HashMap::new());
+}
+
+impl Limits {
+    // 连接超时为本类connect的Client
+    pub fn client(&self) -> Client {
+        CLIENTS.with(|clients| {
+            clients
+                .borrow_mut()
+                .entry(self.connect)
+                .or_insert_with(|| {
+                    Client::builder()
+                        .connector(awc::Connector::new().timeout(self.connect))
+                        .finish()
+                })
+                .clone()
+        })
+    }
+
+    // awc的请求超时从建立连接开始计算, 到收到响应头为止
+    pub fn request(&self) -> Duration {
+        self.connect + self.ttfb
+    }
+
+    pub fn deadline(&self) -> Instant {
+        Instant::now() + self.total
+    }
+}
+
+// 读取下一块数据, 超过idle时间没有数据或超过deadline则返回TimedOut
+pub async fn read<S, E>(
+    body: &mut S,
+    idle: Duration,
+    deadline: Instant,
+) -> Result<Option<Bytes>, io::Error>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+    E: Display,
+{
+    let left = deadline.saturating_duration_since(Instant::now());
+    if left.is_zero() {
+        return Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "total duration exceeded",
+        ));
+    }
+    match time::timeout(idle.min(left), body.next()).await {
+        Ok(Some(Ok(b))) => Ok(Some(b)),
+        Ok(Some(Err(e))) => Err(io::Error::other(e.to_string())),
+        Ok(None) => Ok(None),
+        Err(_) if idle < left => Err(io::Error::new(io::ErrorKind::TimedOut, "stalled")),
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "total duration exceeded",
+        )),
+    }
+}
+
+// 直接转发的响应体, 停滞或超时后中止并记录
+pub fn guard<S, E>(
+    body: S,
+    limits: Limits,
+    label: String,
+) -> LocalBoxStream<'static, Result<Bytes, io::Error>>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin + 'static,
+    E: Display,
+{
+    let deadline = limits.deadline();
+    stream::unfold(Some(body), move |body| {
+        let label = label.clone();
+        async move {
+            let mut body = body?;
+            match read(&mut body, limits.idle, deadline).await {
+                Ok(Some(b)) => Some((Ok(b), Some(body))),
+                Ok(None) => None,
+                Err(e) => {
+                    println!("abort {}: {}", label, e);
+                    Some((Err(e), None))
+                }
+            }
+        }
+    })
+    .boxed_local()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::stream::LocalBoxStream;
+
+    type Body = LocalBoxStream<'static, Result<Bytes, io::Error>>;
+
+    // 每块之间间隔gap
+    fn slow(chunks: usize, gap: Duration) -> Body {
+        stream::iter(0..chunks)
+            .then(move |_| async move {
+                time::sleep(gap).await;
+                Ok(Bytes::from_static(b"data"))
+            })
+            .boxed_local()
+    }
+
+    fn limits(idle: u64, total: u64) -> Limits {
+        Limits {
+            connect: Duration::from_millis(100),
+            ttfb: Duration::from_millis(100),
+            idle: Duration::from_millis(idle),
+            total: Duration::from_millis(total),
+        }
+    }
+
+    #[actix_web::test]
+    async fn read_until_end() {
+        let mut body = slow(3, Duration::from_millis(5));
+        let deadline = limits(100, 1000).deadline();
+        for _ in 0..3 {
+            let b = read(&mut body, Duration::from_millis(100), deadline).await;
+            assert_eq!(b.unwrap().unwrap(), "data");
+        }
+        let end = read(&mut body, Duration::from_millis(100), deadline).await;
+        assert!(end.unwrap().is_none());
+    }
+
+    #[actix_web::test]
+    async fn stalled() {
+        let mut body = slow(1, Duration::from_millis(200));
+        let deadline = limits(50, 1000).deadline();
+        let err = read(&mut body, Duration::from_millis(50), deadline).await;
+        let err = err.unwrap_err();
+        assert_eq!(
+            (err.kind(), err.to_string()),
+            (io::ErrorKind::TimedOut, "stalled".to_owned())
+        );
+    }
+
+    #[actix_web::test]
+    async fn total_exceeded() {
+        // 每块都在idle之内, 但总时长超出
+        let body = slow(10, Duration::from_millis(30));
+        let chunks: Vec<_> = guard(body, limits(100, 100), "test".to_owned())
+            .collect()
+            .await;
+        let err = chunks.last().unwrap().as_ref().unwrap_err();
+        assert_eq!(err.to_string(), "total duration exceeded");
+        assert!(chunks.len() < 10);
+    }
+
+    #[test]
+    fn request_timeout() {
+        assert_eq!(IMAGE.request(), Duration::from_secs(8));
+    }
+}
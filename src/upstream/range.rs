@@ -0,0 +1,140 @@
+use actix_web::http::header::HeaderValue;
+
+// 超过此数量的range将被忽略, 返回整个文件
+const MAX_RANGES: usize = 16;
+
+pub enum Ranges {
+    // 没有Range或无法识别, 返回整个文件
+    Full,
+    // 闭区间
+    Partial(Vec<(u64, u64)>),
+    Unsatisfiable,
+}
+
+pub fn parse(header: Option<&HeaderValue>, total: u64) -> Ranges {
+    let Some(spec) = header
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.trim().strip_prefix("bytes="))
+    else {
+        return Ranges::Full;
+    };
+    let mut res = Vec::new();
+    for item in spec.split(',') {
+        let Some((a, b)) = item.trim().split_once('-') else {
+            return Ranges::Full;
+        };
+        let (a, b) = (a.trim(), b.trim());
+        if a.is_empty() {
+            // 后缀形式 -n
+            let Ok(n) = b.parse::<u64>() else {
+                return Ranges::Full;
+            };
+            if n > 0 && total > 0 {
+                res.push((total - n.min(total), total - 1));
+            }
+            continue;
+        }
+        let Ok(start) = a.parse::<u64>() else {
+            return Ranges::Full;
+        };
+        let end = match b {
+            "" => u64::MAX,
+            _ => match b.parse::<u64>() {
+                Ok(end) if end >= start => end,
+                _ => return Ranges::Full,
+            },
+        };
+        if start < total {
+            res.push((start, end.min(total - 1)));
+        }
+    }
+    if res.is_empty() {
+        return Ranges::Unsatisfiable;
+    }
+    // 合并重叠或相邻的range
+    res.sort();
+    let mut merged: Vec<(u64, u64)> = Vec::with_capacity(res.len());
+    for (start, end) in res {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 + 1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    if merged.len() > MAX_RANGES {
+        return Ranges::Full;
+    }
+    Ranges::Partial(merged)
+}
+
+// multipart/byteranges中每个部分的头
+pub fn part_header(boundary: &str, content_type: &str, start: u64, end: u64, total: u64) -> String {
+    format!(
+        "\r\n--{}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+        boundary, content_type, start, end, total
+    )
+}
+
+pub fn tail(boundary: &str) -> String {
+    format!("\r\n--{}--\r\n", boundary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ranges(header: &str, total: u64) -> Option<Vec<(u64, u64)>> {
+        match parse(Some(&HeaderValue::from_str(header).unwrap()), total) {
+            Ranges::Partial(v) => Some(v),
+            Ranges::Full => None,
+            Ranges::Unsatisfiable => Some(Vec::new()),
+        }
+    }
+
+    #[test]
+    fn single() {
+        assert_eq!(ranges("bytes=0-99", 1000), Some(vec![(0, 99)]));
+        assert_eq!(ranges("bytes=500-", 1000), Some(vec![(500, 999)]));
+        assert_eq!(ranges("bytes=900-5000", 1000), Some(vec![(900, 999)]));
+        assert_eq!(ranges("bytes=-100", 1000), Some(vec![(900, 999)]));
+        assert_eq!(ranges("bytes=-5000", 1000), Some(vec![(0, 999)]));
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(matches!(parse(None, 1000), Ranges::Full));
+        assert_eq!(ranges("items=0-1", 1000), None);
+        assert_eq!(ranges("bytes=abc", 1000), None);
+        assert_eq!(ranges("bytes=10-5", 1000), None);
+        assert_eq!(ranges("bytes=1000-", 1000), Some(vec![]));
+        assert_eq!(ranges("bytes=-0", 1000), Some(vec![]));
+    }
+
+    #[test]
+    fn multiple() {
+        let v = ranges("bytes=0-9, 20-29,-5", 100);
+        assert_eq!(v, Some(vec![(0, 9), (20, 29), (95, 99)]));
+        // 重叠及相邻的range合并
+        let v = ranges("bytes=50-60,0-9,10-19,55-70", 100);
+        assert_eq!(v, Some(vec![(0, 19), (50, 70)]));
+        // 超出文件的部分忽略
+        assert_eq!(ranges("bytes=0-9,200-300", 100), Some(vec![(0, 9)]));
+    }
+
+    #[test]
+    fn too_many() {
+        let spec: Vec<String> = (0..=MAX_RANGES as u64)
+            .map(|i| format!("{}-{}", i * 10, i * 10 + 1))
+            .collect();
+        assert_eq!(ranges(&format!("bytes={}", spec.join(",")), 1000), None);
+    }
+
+    #[test]
+    fn multipart() {
+        let head = part_header("b", "video/mp4", 0, 9, 100);
+        assert_eq!(
+            head,
+            "\r\n--b\r\nContent-Type: video/mp4\r\nContent-Range: bytes 0-9/100\r\n\r\n"
+        );
+        assert_eq!(tail("b"), "\r\n--b--\r\n");
+    }
+}
@@ -0,0 +1,258 @@
+use crate::util;
+use actix_web::rt::time;
+use actix_web::web::Bytes;
+use core::time::Duration;
+use futures_util::stream::{self, LocalBoxStream, Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::env;
+use std::net::IpAddr;
+use std::rc::Rc;
+use std::sync::{Arc, LazyLock, Mutex, RwLock};
+use std::time::Instant;
+
+// 每次扣除令牌的最大字节数, 避免大块数据造成突发
+const SLICE: usize = 64 << 10;
+
+// 客户端数量超过此值时清理已结束的连接
+const CLIENTS_GC: usize = 1024;
+
+// 速率单位均为字节/秒, 0表示不限制
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Config {
+    #[serde(default)]
+    pub global: u64,
+    #[serde(default)]
+    pub client: u64,
+    #[serde(default)]
+    pub routes: HashMap<String, u64>,
+    // 默认开启先突发后限速的路由, 限速为码率的1.5倍
+    #[serde(default)]
+    pub pace: Vec<String>,
+    // 突发阶段的数据量, 以秒为单位的播放时长
+    #[serde(default)]
+    pub burst: u64,
+}
+
+static CONFIG: LazyLock<RwLock<Config>> = LazyLock::new(|| {
+    RwLock::new(Config {
+        global: env_size("RATE_GLOBAL"),
+        client: env_size("RATE_CLIENT"),
+        routes: env_list("RATE_ROUTES")
+            .iter()
+            .filter_map(|v| v.split_once('='))
+            .map(|(k, v)| (k.to_owned(), util::parse_size(v)))
+            .collect(),
+        pace: env_list("PACE"),
+        burst: env::var("PACE_BURST")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(30),
+    })
+});
+
+static GLOBAL: LazyLock<Bucket> = LazyLock::new(Bucket::default);
+
+static ROUTES: LazyLock<Mutex<HashMap<String, Arc<Bucket>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+static CLIENTS: LazyLock<Mutex<HashMap<IpAddr, Arc<Bucket>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+fn env_size(key: &str) -> u64 {
+    env::var(key)
+        .map(|v| util::parse_size(&v))
+        .unwrap_or_default()
+}
+
+fn env_list(key: &str) -> Vec<String> {
+    env::var(key)
+        .unwrap_or_default()
+        .split(',')
+        .map(|v| v.trim().to_owned())
+        .filter(|v| !v.is_empty())
+        .collect()
+}
+
+pub fn config() -> Config {
+    CONFIG.read().unwrap().clone()
+}
+
+pub fn set_config(config: Config) {
+    *CONFIG.write().unwrap() = config;
+}
+
+pub fn pace_default(route: &str) -> bool {
+    CONFIG.read().unwrap().pace.iter().any(|r| r == route)
+}
+
+struct State {
+    tokens: f64,
+    last: Instant,
+}
+
+// 令牌桶, 令牌不足时记为欠账, 由调用方等待偿还
+struct Bucket(Mutex<State>);
+
+impl Default for Bucket {
+    fn default() -> Self {
+        Bucket::new(0.0)
+    }
+}
+
+impl Bucket {
+    fn new(tokens: f64) -> Self {
+        Bucket(Mutex::new(State {
+            tokens,
+            last: Instant::now(),
+        }))
+    }
+
+    // 扣除n字节, 返回需要等待的时间, 最多积累1秒的令牌(初始的突发额度除外)
+    fn take(&self, n: usize, rate: u64) -> Duration {
+        if rate == 0 {
+            return Duration::ZERO;
+        }
+        let rate = rate as f64;
+        let mut s = self.0.lock().unwrap();
+        let now = Instant::now();
+        let add = now.duration_since(s.last).as_secs_f64() * rate;
+        s.last = now;
+        if s.tokens < rate {
+            s.tokens = (s.tokens + add).min(rate);
+        }
+        s.tokens -= n as f64;
+        match s.tokens < 0.0 {
+            true => Duration::from_secs_f64(-s.tokens / rate),
+            false => Duration::ZERO,
+        }
+    }
+}
+
+// 一个响应体使用的所有限速, 全局, 路由, 客户端ip, 以及可选的按码率限速
+pub struct Shaper {
+    route: String,
+    route_bucket: Arc<Bucket>,
+    client: Option<Arc<Bucket>>,
+    pace: Option<(Bucket, u64)>,
+}
+
+impl Shaper {
+    // bitrate为码率(bit/s), 不为None时先突发burst秒的数据, 之后限速为码率的1.5倍
+    pub fn new(route: &str, ip: Option<IpAddr>, bitrate: Option<u64>) -> Self {
+        let route_bucket = ROUTES
+            .lock()
+            .unwrap()
+            .entry(route.to_owned())
+            .or_default()
+            .clone();
+        let client = ip.map(|ip| {
+            let mut clients = CLIENTS.lock().unwrap();
+            if clients.len() > CLIENTS_GC {
+                clients.retain(|_, b| Arc::strong_count(b) > 1);
+            }
+            clients.entry(ip).or_default().clone()
+        });
+        let burst = CONFIG.read().unwrap().burst;
+        let pace = bitrate.filter(|b| *b > 0).map(|b| {
+            let rate = b * 3 / 16;
+            (Bucket::new((b / 8 * burst) as f64), rate)
+        });
+        Shaper {
+            route: route.to_owned(),
+            route_bucket,
+            client,
+            pace,
+        }
+    }
+
+    fn wait(&self, n: usize) -> Duration {
+        let (global, client, route) = {
+            let c = CONFIG.read().unwrap();
+            (
+                c.global,
+                c.client,
+                c.routes.get(&self.route).copied().unwrap_or_default(),
+            )
+        };
+        let mut wait = GLOBAL.take(n, global).max(self.route_bucket.take(n, route));
+        if let Some(bucket) = &self.client {
+            wait = wait.max(bucket.take(n, client));
+        }
+        if let Some((bucket, rate)) = &self.pace {
+            wait = wait.max(bucket.take(n, *rate));
+        }
+        wait
+    }
+
+    // 按SLICE切分后输出, 每块输出前等待令牌
+    pub fn wrap<S, E>(self, body: S) -> LocalBoxStream<'static, Result<Bytes, E>>
+    where
+        S: Stream<Item = Result<Bytes, E>> + 'static,
+        E: 'static,
+    {
+        let shaper = Rc::new(self);
+        body.flat_map(|item| {
+            let parts: Vec<Result<Bytes, E>> = match item {
+                Ok(b) => (0..b.len())
+                    .step_by(SLICE)
+                    .map(|i| Ok(b.slice(i..b.len().min(i + SLICE))))
+                    .collect(),
+                Err(e) => vec![Err(e)],
+            };
+            stream::iter(parts)
+        })
+        .then(move |item| {
+            let shaper = shaper.clone();
+            async move {
+                if let Ok(b) = &item {
+                    let wait = shaper.wait(b.len());
+                    if !wait.is_zero() {
+                        time::sleep(wait).await;
+                    }
+                }
+                item
+            }
+        })
+        .boxed_local()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bucket_debt() {
+        let bucket = Bucket::default();
+        assert_eq!(bucket.take(1000, 0), Duration::ZERO);
+        let wait = bucket.take(1000, 1000);
+        assert!(wait > Duration::from_millis(900) && wait <= Duration::from_secs(1));
+        // 欠账累计
+        let wait = bucket.take(1000, 1000);
+        assert!(wait > Duration::from_millis(1900));
+    }
+
+    #[test]
+    fn bucket_burst() {
+        // 初始额度可超过1秒的令牌
+        let bucket = Bucket::new(5000.0);
+        assert_eq!(bucket.take(4000, 1000), Duration::ZERO);
+        assert_eq!(bucket.take(1000, 1000), Duration::ZERO);
+        assert!(bucket.take(1000, 1000) > Duration::from_millis(900));
+    }
+
+    #[actix_web::test]
+    async fn wrap_slices() {
+        let data = Bytes::from(vec![1u8; 3 * SLICE + 10]);
+        let body = stream::iter([Ok::<_, ()>(data.clone()), Err(())]);
+        let parts: Vec<_> = Shaper::new("test", None, None).wrap(body).collect().await;
+        let sizes: Vec<usize> = parts
+            .iter()
+            .filter_map(|p| p.as_ref().ok())
+            .map(|b| b.len())
+            .collect();
+        assert_eq!(sizes, vec![SLICE, SLICE, SLICE, 10]);
+        assert!(parts.last().unwrap().is_err());
+    }
+}
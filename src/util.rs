@@ -1,3 +1,7 @@
+use std::env;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::LazyLock;
+
 const BASE62_MAP: &[u8] =
     "0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz".as_bytes();
 
@@ -29,3 +33,279 @@ pub fn hash(data: &str) -> String {
     let s = base62(n);
     String::from_utf8_lossy(&s).to_string()
 }
+
+// 标准base64编码, 带填充
+pub fn base64(data: &[u8]) -> String {
+    const MAP: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for c in data.chunks(3) {
+        let n = (c[0] as u32) << 16
+            | (c.get(1).copied().unwrap_or_default() as u32) << 8
+            | c.get(2).copied().unwrap_or_default() as u32;
+        for i in 0..4 {
+            if i <= c.len() {
+                out.push(MAP[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+// 视频封面的上游地址
+pub fn thumbnail(vid: &str, ext: &str) -> String {
+    match ext {
+        "jpg" => format!("https://i.ytimg.com/vi/{}/mqdefault.{}", vid, ext),
+        _ => format!("https://i.ytimg.com/vi_webp/{}/mqdefault.{}", vid, ext),
+    }
+}
+
+// 获取url中的query参数, 不做解码
+pub fn query_param<'a>(url: &'a str, key: &str) -> Option<&'a str> {
+    let (_, query) = url.split_once('?')?;
+    query
+        .split('&')
+        .find_map(|kv| kv.strip_prefix(key)?.strip_prefix('='))
+}
+
+// 百分号解码, 编码不完整或结果不是UTF-8时返回None
+pub fn percent_decode(s: &str) -> Option<String> {
+    let mut out = Vec::with_capacity(s.len());
+    let mut bytes = s.bytes();
+    while let Some(b) = bytes.next() {
+        out.push(match b {
+            b'%' => {
+                let hex = [bytes.next()?, bytes.next()?];
+                u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?
+            }
+            b'+' => b' ',
+            b => b,
+        });
+    }
+    String::from_utf8(out).ok()
+}
+
+// RFC 5987 attr-char以外的字节均需百分号编码
+fn attr_char(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$&+-.^_`|~".contains(&b)
+}
+
+// 附件下载, filename为ASCII回退, filename*保留UTF-8原文
+pub fn content_disposition(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    let name = name.trim();
+    let fallback: String = name
+        .chars()
+        .map(|c| if c.is_ascii() { c } else { '_' })
+        .collect();
+    let mut encoded = String::with_capacity(name.len() * 3);
+    for b in name.bytes() {
+        if attr_char(b) {
+            encoded.push(b as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", b));
+        }
+    }
+    format!(
+        "attachment; filename=\"{}\"; filename*=UTF-8''{}",
+        fallback, encoded
+    )
+}
+
+// 环境变量TRUSTED_PROXIES, 逗号分隔的ip或网段, 例如127.0.0.1,10.0.0.0/8
+static TRUSTED_PROXIES: LazyLock<Vec<(IpAddr, u8)>> = LazyLock::new(|| {
+    env::var("TRUSTED_PROXIES")
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(|v| {
+            let (ip, bits) = v.trim().split_once('/').unwrap_or((v.trim(), ""));
+            let ip: IpAddr = ip.parse().ok()?;
+            let max = if ip.is_ipv4() { 32 } else { 128 };
+            let bits = if bits.is_empty() {
+                max
+            } else {
+                bits.parse().ok()?
+            };
+            (bits <= max).then_some((ip, bits))
+        })
+        .collect()
+});
+
+fn trusted(ip: IpAddr) -> bool {
+    TRUSTED_PROXIES
+        .iter()
+        .any(|&(net, bits)| in_net(ip, net, bits))
+}
+
+fn in_net(ip: IpAddr, net: IpAddr, bits: u8) -> bool {
+    let mask = |n: u32, len: u32| if n == 0 { 0 } else { u128::MAX << (len - n) };
+    match (ip.to_canonical(), net) {
+        (IpAddr::V4(a), IpAddr::V4(b)) => {
+            let m = mask(bits as u32, 32) as u32;
+            u32::from(a) & m == u32::from(b) & m
+        }
+        (IpAddr::V6(a), IpAddr::V6(b)) => {
+            let m = mask(bits as u32, 128);
+            u128::from(a) & m == u128::from(b) & m
+        }
+        _ => false,
+    }
+}
+
+// 转发头中的一跳, 可能带端口或方括号, 无法解析(如unknown)时为None
+fn hop(v: &str) -> Option<IpAddr> {
+    let v = v.trim().trim_matches('"');
+    v.parse::<SocketAddr>()
+        .map(|a| a.ip())
+        .or_else(|_| v.trim_matches(['[', ']']).parse())
+        .ok()
+}
+
+// 客户端ip, 默认为连接的对端地址
+// 对端在TRUSTED_PROXIES中时才使用Forwarded/X-Forwarded-For, 从右往左跳过受信任的代理, 取第一个不受信任的地址
+pub fn client_ip(req: &actix_web::HttpRequest) -> Option<IpAddr> {
+    let peer = req.peer_addr()?.ip();
+    if !trusted(peer) {
+        return Some(peer);
+    }
+    let headers = req.headers();
+    let hops: Vec<&str> = match headers.get("forwarded") {
+        Some(v) => v
+            .to_str()
+            .unwrap_or_default()
+            .split(',')
+            .filter_map(|e| {
+                e.split(';')
+                    .filter_map(|p| p.trim().split_once('='))
+                    .find(|(k, _)| k.eq_ignore_ascii_case("for"))
+                    .map(|(_, v)| v)
+            })
+            .collect(),
+        None => headers
+            .get_all("x-forwarded-for")
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .collect(),
+    };
+    let mut ip = peer;
+    for v in hops.iter().rev() {
+        match hop(v) {
+            Some(h) => {
+                ip = h;
+                if !trusted(h) {
+                    break;
+                }
+            }
+            None => break,
+        }
+    }
+    Some(ip)
+}
+
+// 支持K,M,G后缀, 例如10M
+pub fn parse_size(v: &str) -> u64 {
+    let v = v.trim();
+    let (num, unit) = match v.char_indices().last() {
+        Some((i, c)) if c.is_ascii_alphabetic() => (&v[..i], c.to_ascii_uppercase()),
+        _ => (v, ' '),
+    };
+    let n: u64 = num.trim().parse().unwrap_or_default();
+    match unit {
+        'K' => n << 10,
+        'M' => n << 20,
+        'G' => n << 30,
+        _ => n,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn disposition() {
+        assert_eq!(
+            content_disposition("a b.mp4"),
+            "attachment; filename=\"a b.mp4\"; filename*=UTF-8''a%20b.mp4"
+        );
+        // 路径及引号等字符替换为_, 非ASCII在filename中替换, 在filename*中编码
+        assert_eq!(
+            content_disposition(" 视频/\"x\".mp4 "),
+            "attachment; filename=\"____x_.mp4\"; filename*=UTF-8''%E8%A7%86%E9%A2%91__x_.mp4"
+        );
+    }
+
+    #[test]
+    fn query() {
+        let url = "https://x/videoplayback?itag=18&lmt=123&ip=1.2.3.4";
+        assert_eq!(query_param(url, "lmt"), Some("123"));
+        assert_eq!(query_param(url, "it"), None);
+        assert_eq!(query_param("https://x/", "lmt"), None);
+        assert_eq!(percent_decode("2001%3Adb8%3A%3A1").unwrap(), "2001:db8::1");
+        assert_eq!(percent_decode("a+b%2fc").unwrap(), "a b/c");
+        assert_eq!(percent_decode("bad%3"), None);
+        assert_eq!(percent_decode("%zz"), None);
+    }
+
+    #[test]
+    fn networks() {
+        let ip = |v: &str| v.parse::<IpAddr>().unwrap();
+        assert!(in_net(ip("10.1.2.3"), ip("10.0.0.0"), 8));
+        assert!(!in_net(ip("11.1.2.3"), ip("10.0.0.0"), 8));
+        assert!(in_net(ip("::ffff:10.1.2.3"), ip("10.0.0.0"), 8));
+        assert!(in_net(ip("1.2.3.4"), ip("0.0.0.0"), 0));
+        assert!(in_net(ip("2001:db8::1"), ip("2001:db8::"), 32));
+        assert!(!in_net(ip("2001:db9::1"), ip("2001:db8::"), 32));
+        assert!(!in_net(ip("10.0.0.1"), ip("::1"), 128));
+    }
+
+    #[test]
+    fn hops() {
+        assert_eq!(hop(" 1.2.3.4 "), Some("1.2.3.4".parse().unwrap()));
+        assert_eq!(hop("1.2.3.4:80"), Some("1.2.3.4".parse().unwrap()));
+        assert_eq!(
+            hop("\"[2001:db8::1]:4711\""),
+            Some("2001:db8::1".parse().unwrap())
+        );
+        assert_eq!(hop("[2001:db8::1]"), Some("2001:db8::1".parse().unwrap()));
+        assert_eq!(hop("unknown"), None);
+    }
+
+    #[test]
+    fn untrusted_peer() {
+        // 未配置TRUSTED_PROXIES时忽略转发头
+        let req = actix_web::test::TestRequest::default()
+            .peer_addr("1.2.3.4:5000".parse().unwrap())
+            .insert_header(("x-forwarded-for", "9.9.9.9"))
+            .insert_header(("forwarded", "for=8.8.8.8"))
+            .to_http_request();
+        assert_eq!(client_ip(&req), Some("1.2.3.4".parse().unwrap()));
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("512"), 512);
+        assert_eq!(parse_size("10k"), 10 << 10);
+        assert_eq!(parse_size(" 2M "), 2 << 20);
+        assert_eq!(parse_size("1G"), 1 << 30);
+        assert_eq!(parse_size("abc"), 0);
+    }
+
+    #[test]
+    fn base64_padding() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+        assert_eq!(base64(b"foobar"), "Zm9vYmFy");
+        assert_eq!(base64(&[0xFB, 0xFF]), "+/8=");
+    }
+}